@@ -0,0 +1,514 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+#[cfg(target_family = "unix")]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use log_crate::{debug, trace};
+
+use super::auth::AuthExchange;
+use super::events::subscribe_command;
+use super::reply::ReplyParser;
+use super::{
+    add_onion_command, check_argument, join_arguments, onion_client_auth_add_command,
+    onion_client_auth_view_command, setconf_command, AddedOnion, Authenticator,
+    ClientAuthCredential, EventKind, ListenerAddr, ProtocolInfo, Reply, Signal,
+};
+use crate::hs::{ClientAuthSecretKey, OnionAddressV3};
+use crate::{Error, Tor, TorAddress, TorFlag};
+
+type Writer = Pin<Box<dyn AsyncWrite + Send>>;
+type ReplySender = oneshot::Sender<Result<Reply, Error>>;
+
+/// Commands waiting for their reply, in the order they were sent
+#[derive(Default)]
+struct Pending {
+    senders: VecDeque<ReplySender>,
+    /// Set once the connection is unusable, returned to every following command
+    closed: Option<Error>,
+}
+
+impl Pending {
+    fn close(&mut self, error: Error) {
+        for sender in self.senders.drain(..) {
+            let _ = sender.send(Err(error.clone()));
+        }
+        self.closed.get_or_insert(error);
+    }
+}
+
+type SharedPending = Arc<std::sync::Mutex<Pending>>;
+
+fn lock(pending: &SharedPending) -> std::sync::MutexGuard<'_, Pending> {
+    pending.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Closes the connection if a command is dropped while being written
+struct WriteGuard<'a> {
+    pending: &'a SharedPending,
+    done: bool,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            let error = Error::Protocol("A command was cancelled while being sent".into());
+            lock(self.pending).close(error);
+        }
+    }
+}
+
+/// Asynchronous client for Tor's control port, built on `tokio`
+///
+/// A background task reads from the socket, so that asynchronous events can be consumed
+/// through [`AsyncControlConnection::events`] while commands are being sent.
+pub struct AsyncControlConnection {
+    writer: Mutex<Writer>,
+    pending: SharedPending,
+    events: std::sync::Mutex<Option<mpsc::UnboundedReceiver<Reply>>>,
+}
+
+impl std::fmt::Debug for AsyncControlConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AsyncControlConnection").finish()
+    }
+}
+
+async fn read_replies<R: AsyncRead + Unpin>(
+    reader: R,
+    pending: SharedPending,
+    events: mpsc::UnboundedSender<Reply>,
+) {
+    let mut reader = BufReader::new(reader);
+    let mut parser = ReplyParser::default();
+    let mut buf = Vec::new();
+
+    loop {
+        buf.clear();
+        let result = match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\r', '\n']);
+                trace!("<- {}", line);
+                parser.feed(line)
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        match result {
+            Ok(None) => {}
+            Ok(Some(reply)) if reply.is_async() => {
+                // The event stream may have been dropped, in which case events are discarded
+                let _ = events.send(reply);
+            }
+            Ok(Some(reply)) => {
+                let sender = lock(&pending).senders.pop_front();
+                match sender {
+                    // The command may have been cancelled, in which case its reply is discarded
+                    Some(sender) => {
+                        let _ = sender.send(Ok(reply));
+                    }
+                    None => {
+                        let error = format!("Unexpected synchronous reply: {:?}", reply);
+                        lock(&pending).close(Error::Protocol(error));
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                lock(&pending).close(e);
+                return;
+            }
+        }
+    }
+}
+
+impl AsyncControlConnection {
+    /// Connect to a control port, without authenticating
+    ///
+    /// Must be called from within a `tokio` runtime, since a task is spawned to read from the
+    /// socket.
+    pub async fn connect(addr: &ListenerAddr) -> Result<AsyncControlConnection, Error> {
+        debug!("Connecting to the control port at {}", addr);
+
+        let pending = SharedPending::default();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+
+        let writer: Writer = match addr {
+            ListenerAddr::Tcp(addr) => {
+                let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+                tokio::spawn(read_replies(reader, Arc::clone(&pending), events_tx));
+                Box::pin(writer)
+            }
+            #[cfg(target_family = "unix")]
+            ListenerAddr::Unix(path) => {
+                let (reader, writer) = UnixStream::connect(path).await?.into_split();
+                tokio::spawn(read_replies(reader, Arc::clone(&pending), events_tx));
+                Box::pin(writer)
+            }
+        };
+
+        Ok(AsyncControlConnection {
+            writer: Mutex::new(writer),
+            pending,
+            events: std::sync::Mutex::new(Some(events_rx)),
+        })
+    }
+
+    /// Connect to the control port configured in `tor` and authenticate
+    pub async fn connect_config(
+        tor: &Tor,
+        authenticator: &Authenticator,
+    ) -> Result<AsyncControlConnection, Error> {
+        let addr = tor.control_addr().ok_or(Error::NoControlPort)?;
+        let conn = AsyncControlConnection::connect(&addr).await?;
+        conn.authenticate(authenticator).await?;
+
+        Ok(conn)
+    }
+
+    /// Send a raw command and return its reply, whatever the status code is
+    ///
+    /// The future can be cancelled while waiting for the reply, which is then discarded.
+    /// Cancelling it while the command is being written closes the connection, since Tor may
+    /// have received part of it: every following command fails.
+    pub async fn send_command(&self, command: &str) -> Result<Reply, Error> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut writer = self.writer.lock().await;
+            {
+                let mut pending = lock(&self.pending);
+                if let Some(error) = &pending.closed {
+                    return Err(error.clone());
+                }
+                // Replies come in the order of the commands, so queue the sender while writing
+                pending.senders.push_back(sender);
+            }
+
+            let mut guard = WriteGuard {
+                pending: &self.pending,
+                done: false,
+            };
+            trace!("-> {}", command);
+            let written = async {
+                writer.write_all(command.as_bytes()).await?;
+                writer.write_all(b"\r\n").await?;
+                writer.flush().await
+            }
+            .await;
+            guard.done = true;
+
+            if let Err(e) = written {
+                let error = Error::from(e);
+                lock(&self.pending).close(error.clone());
+                return Err(error);
+            }
+        }
+
+        receiver
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+    }
+
+    async fn command(&self, command: &str) -> Result<Reply, Error> {
+        self.send_command(command).await?.into_result()
+    }
+
+    /// Ask Tor which authentication methods it supports
+    pub async fn protocol_info(&self) -> Result<ProtocolInfo, Error> {
+        ProtocolInfo::from_reply(&self.command("PROTOCOLINFO 1").await?)
+    }
+
+    /// Authenticate with the control port, picking the strongest method supported by both
+    /// Tor and `authenticator`
+    pub async fn authenticate(&self, authenticator: &Authenticator) -> Result<(), Error> {
+        let info = self.protocol_info().await?;
+        debug!("Control port PROTOCOLINFO: {:?}", info);
+
+        let exchange = authenticator.start(&info)?;
+        let command = match exchange {
+            AuthExchange::Authenticate(command) => command,
+            AuthExchange::Challenge { .. } => {
+                exchange.respond(&self.command(&exchange.command()).await?)?
+            }
+        };
+
+        self.command(&command).await.map(|_| ())
+    }
+
+    /// Query a single `GETINFO` key
+    pub async fn getinfo(&self, key: &str) -> Result<String, Error> {
+        self.getinfo_many(&[key])
+            .await?
+            .remove(key)
+            .ok_or_else(|| Error::Protocol(format!("Missing GETINFO key: {}", key)))
+    }
+
+    /// Query multiple `GETINFO` keys at once
+    pub async fn getinfo_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        self.send_command(&format!("GETINFO {}", join_arguments("GETINFO key", keys)?))
+            .await?
+            .into_getinfo()
+    }
+
+    /// Change the value of configuration options
+    pub async fn setconf(&self, flags: &[TorFlag]) -> Result<(), Error> {
+        self.command(&setconf_command(flags)).await.map(|_| ())
+    }
+
+    /// Send a signal to Tor
+    pub async fn signal(&self, signal: Signal) -> Result<(), Error> {
+        self.command(&format!("SIGNAL {}", signal))
+            .await
+            .map(|_| ())
+    }
+
+    /// Create an ephemeral onion service
+    ///
+    /// `key` is either `NEW:<KeyType>` or `<KeyType>:<KeyBlob>`, `flags` are passed as-is in
+    /// the `Flags=` argument.
+    pub async fn add_onion(
+        &self,
+        key: &str,
+        flags: &[&str],
+        ports: &[(u16, Option<TorAddress>)],
+    ) -> Result<AddedOnion, Error> {
+        let reply = self
+            .command(&add_onion_command(key, flags, None, ports)?)
+            .await?;
+        AddedOnion::from_reply(&reply)
+    }
+
+    /// Remove an ephemeral onion service
+    pub async fn del_onion(&self, service_id: &str) -> Result<(), Error> {
+        self.command(&format!(
+            "DEL_ONION {}",
+            check_argument("service ID", service_id)?
+        ))
+        .await
+        .map(|_| ())
+    }
+
+    /// Register the credentials to access a v3 onion service with client authorization
+    ///
+    /// With `permanent`, Tor also stores them in its `ClientOnionAuthDir`, which must be set.
+    pub async fn onion_client_auth_add(
+        &self,
+        address: &OnionAddressV3,
+        key: &ClientAuthSecretKey,
+        client_name: Option<&str>,
+        permanent: bool,
+    ) -> Result<(), Error> {
+        self.command(&onion_client_auth_add_command(
+            address,
+            key,
+            client_name,
+            permanent,
+        )?)
+        .await
+        .map(|_| ())
+    }
+
+    /// Remove the credentials registered for an onion service
+    pub async fn onion_client_auth_remove(&self, address: &OnionAddressV3) -> Result<(), Error> {
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_REMOVE {}",
+            address.service_id()
+        ))
+        .await
+        .map(|_| ())
+    }
+
+    /// List the registered credentials, for all the services or only for `address`
+    pub async fn onion_client_auth_view(
+        &self,
+        address: Option<&OnionAddressV3>,
+    ) -> Result<Vec<ClientAuthCredential>, Error> {
+        ClientAuthCredential::from_reply(
+            &self
+                .command(&onion_client_auth_view_command(address))
+                .await?,
+        )
+    }
+
+    /// Subscribe to the given asynchronous events, replacing the previous subscriptions
+    pub async fn set_events(&self, events: &[&str]) -> Result<(), Error> {
+        self.command(&format!("SETEVENTS {}", join_arguments("event", events)?))
+            .await
+            .map(|_| ())
+    }
+
+    /// Subscribe to the given typed events, replacing the previous subscriptions
+    ///
+    /// The replies received through [`AsyncControlConnection::events`] can be parsed with
+    /// [`Event::from_reply`](super::Event::from_reply).
+    pub async fn subscribe(&self, kinds: &[EventKind]) -> Result<(), Error> {
+        self.command(&subscribe_command(kinds)).await.map(|_| ())
+    }
+
+    /// Take the stream of asynchronous events received on this connection
+    ///
+    /// Returns `None` if the stream has already been taken.
+    pub fn events(&self) -> Option<EventStream> {
+        self.events
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .take()
+            .map(|rx| EventStream { rx })
+    }
+}
+
+/// Stream of asynchronous events (`650` replies) received on the control port
+#[derive(Debug)]
+pub struct EventStream {
+    rx: mpsc::UnboundedReceiver<Reply>,
+}
+
+impl Stream for EventStream {
+    type Item = Reply;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use super::super::reply::fixtures::*;
+    use super::super::testing::FakeControlServer;
+    use super::*;
+
+    async fn next_event(events: &mut EventStream) -> Option<Reply> {
+        poll_fn(|cx| Pin::new(&mut *events).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let server = FakeControlServer::new(vec![
+            ("PROTOCOLINFO 1", PROTOCOLINFO_PASSWORD),
+            ("AUTHENTICATE \"wrong\"", BAD_AUTH),
+            ("PROTOCOLINFO 1", PROTOCOLINFO_PASSWORD),
+            ("AUTHENTICATE \"secret\"", OK),
+        ]);
+        let conn = AsyncControlConnection::connect(&ListenerAddr::Tcp(server.addr()))
+            .await
+            .unwrap();
+        match conn
+            .authenticate(Authenticator::new().password("wrong"))
+            .await
+        {
+            Err(Error::Control { code: 515, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        conn.authenticate(Authenticator::new().password("secret"))
+            .await
+            .unwrap();
+        drop(conn);
+        server.join();
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let server = FakeControlServer::new(vec![
+            ("PROTOCOLINFO 1", PROTOCOLINFO_NULL),
+            ("AUTHENTICATE", OK),
+            ("GETINFO version config-text", GETINFO_MULTI),
+            ("SETCONF SocksPort=\"9050\"", OK),
+            ("SIGNAL NEWNYM", OK),
+            (
+                "ADD_ONION NEW:ED25519-V3 Flags=Detach Port=80,127.0.0.1:8080",
+                "250-ServiceID=abcdef\r\n250-PrivateKey=ED25519-V3:a2V5\r\n250 OK\r\n",
+            ),
+            ("DEL_ONION abcdef", OK),
+            ("FOO", UNRECOGNIZED),
+        ]);
+        let tor = Tor::new()
+            .flag(TorFlag::ControlPort(server.addr().port()))
+            .clone();
+        let conn = AsyncControlConnection::connect_config(&tor, &Authenticator::new())
+            .await
+            .unwrap();
+
+        let info = conn
+            .getinfo_many(&["version", "config-text"])
+            .await
+            .unwrap();
+        assert_eq!(info["version"], "0.4.7.13");
+        assert_eq!(info["config-text"], "SocksPort 9050\n.dotted\n");
+        conn.setconf(&[TorFlag::SocksPort(9050)]).await.unwrap();
+        conn.signal(Signal::NewNym).await.unwrap();
+
+        let onion = conn
+            .add_onion(
+                "NEW:ED25519-V3",
+                &["Detach"],
+                &[(80, Some(TorAddress::AddressPort("127.0.0.1".into(), 8080)))],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            onion,
+            AddedOnion {
+                service_id: "abcdef".into(),
+                private_key: Some("ED25519-V3:a2V5".into()),
+            }
+        );
+        conn.del_onion("abcdef").await.unwrap();
+        assert_eq!(conn.send_command("FOO").await.unwrap().code(), 510);
+        drop(conn);
+        server.join();
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_command() {
+        let server = FakeControlServer::new(vec![
+            ("SIGNAL NEWNYM", OK),
+            ("GETINFO version", "250-version=0.4.7.13\r\n250 OK\r\n"),
+        ]);
+        let conn = AsyncControlConnection::connect(&ListenerAddr::Tcp(server.addr()))
+            .await
+            .unwrap();
+
+        // Drop the command once it's sent, while its reply is still pending
+        tokio::select! {
+            biased;
+            _ = conn.send_command("SIGNAL NEWNYM") => {}
+            _ = std::future::ready(()) => {}
+        }
+        assert_eq!(conn.getinfo("version").await.unwrap(), "0.4.7.13");
+        drop(conn);
+        server.join();
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let server = FakeControlServer::new(vec![(
+            "SETEVENTS NOTICE",
+            "250 OK\r\n650 NOTICE Bootstrapped 5%\r\n650+NOTICE\r\nmulti\r\n.\r\n650 OK\r\n",
+        )]);
+        let conn = AsyncControlConnection::connect(&ListenerAddr::Tcp(server.addr()))
+            .await
+            .unwrap();
+        let mut events = conn.events().unwrap();
+        assert!(conn.events().is_none());
+
+        conn.set_events(&["NOTICE"]).await.unwrap();
+
+        let event = next_event(&mut events).await.unwrap();
+        assert_eq!(event.message(), "NOTICE Bootstrapped 5%");
+        let event = next_event(&mut events).await.unwrap();
+        assert_eq!(event.lines[0].data.as_deref(), Some("multi\n"));
+        drop(conn);
+        server.join();
+    }
+}
@@ -0,0 +1,316 @@
+use std::path::PathBuf;
+
+use data_encoding::HEXUPPER;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use log_crate::debug;
+
+use super::reply::{parse_arguments, quote};
+use super::{ProtocolInfo, Reply};
+use crate::Error;
+
+const SERVER_TO_CONTROLLER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const CONTROLLER_TO_SERVER_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+const COOKIE_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+
+/// Authentication methods advertised by Tor in its `PROTOCOLINFO` reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    Null,
+    HashedPassword,
+    Cookie,
+    SafeCookie,
+    /// Method unknown to this library
+    Other(String),
+}
+
+impl std::str::FromStr for AuthMethod {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "NULL" => AuthMethod::Null,
+            "HASHEDPASSWORD" => AuthMethod::HashedPassword,
+            "COOKIE" => AuthMethod::Cookie,
+            "SAFECOOKIE" => AuthMethod::SafeCookie,
+            other => AuthMethod::Other(other.to_string()),
+        })
+    }
+}
+
+/// Credentials used to authenticate with the control port
+///
+/// The method is picked automatically among the ones advertised by Tor, preferring
+/// `SAFECOOKIE`, then `COOKIE`, `HASHEDPASSWORD` and finally `NULL`. Cookie-based methods are
+/// skipped if the cookie can't be read, and `HASHEDPASSWORD` is skipped if no password has
+/// been set.
+#[derive(Debug, Clone, Default)]
+pub struct Authenticator {
+    password: Option<String>,
+    cookie_file: Option<PathBuf>,
+}
+
+/// Steps required to authenticate, computed from the `PROTOCOLINFO` reply
+///
+/// Shared between the blocking and the async clients, which only have to send the commands.
+#[derive(Debug)]
+pub(crate) enum AuthExchange {
+    /// Send the `AUTHENTICATE` command directly
+    Authenticate(String),
+    /// Send an `AUTHCHALLENGE` first, then call [`AuthExchange::respond`] with its reply
+    Challenge {
+        cookie: Vec<u8>,
+        client_nonce: [u8; NONCE_LEN],
+    },
+}
+
+impl AuthExchange {
+    /// Command to send for the first step
+    pub fn command(&self) -> String {
+        match self {
+            AuthExchange::Authenticate(command) => command.clone(),
+            AuthExchange::Challenge { client_nonce, .. } => {
+                format!("AUTHCHALLENGE SAFECOOKIE {}", HEXUPPER.encode(client_nonce))
+            }
+        }
+    }
+
+    /// Verify the `AUTHCHALLENGE` reply and build the `AUTHENTICATE` command
+    pub fn respond(&self, reply: &Reply) -> Result<String, Error> {
+        let (cookie, client_nonce) = match self {
+            AuthExchange::Challenge {
+                cookie,
+                client_nonce,
+            } => (cookie, client_nonce),
+            AuthExchange::Authenticate(_) => {
+                return Err(Error::Authentication("Unexpected AUTHCHALLENGE".into()))
+            }
+        };
+
+        let args = parse_arguments(reply.message())?;
+        let decode = |key| {
+            args.get(key)
+                .and_then(|v| HEXUPPER.decode(v.to_uppercase().as_bytes()).ok())
+                .ok_or_else(|| Error::Protocol(format!("Invalid {} in AUTHCHALLENGE reply", key)))
+        };
+        let server_hash = decode("SERVERHASH")?;
+        let server_nonce = decode("SERVERNONCE")?;
+
+        let hmac = |key| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+            mac.update(cookie);
+            mac.update(client_nonce);
+            mac.update(&server_nonce);
+            mac
+        };
+        hmac(SERVER_TO_CONTROLLER_KEY)
+            .verify_slice(&server_hash)
+            .map_err(|_| {
+                Error::Authentication("Tor sent an invalid SAFECOOKIE server hash".into())
+            })?;
+        let client_hash = hmac(CONTROLLER_TO_SERVER_KEY).finalize().into_bytes();
+
+        Ok(format!("AUTHENTICATE {}", HEXUPPER.encode(&client_hash)))
+    }
+}
+
+impl Authenticator {
+    /// Create a new instance that will use the cookie file advertised by Tor, and no password
+    pub fn new() -> Authenticator {
+        Default::default()
+    }
+
+    /// Set the password used for `HASHEDPASSWORD` authentication
+    pub fn password<S: Into<String>>(&mut self, password: S) -> &mut Authenticator {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Read the cookie from `path` instead of the `COOKIEFILE` advertised by Tor
+    pub fn cookie_file<P: Into<PathBuf>>(&mut self, path: P) -> &mut Authenticator {
+        self.cookie_file = Some(path.into());
+        self
+    }
+
+    fn read_cookie(&self, info: &ProtocolInfo) -> Result<Vec<u8>, Error> {
+        let path = self
+            .cookie_file
+            .as_ref()
+            .or(info.cookie_file.as_ref())
+            .ok_or_else(|| Error::Authentication("No cookie file available".into()))?;
+        let cookie = std::fs::read(path)?;
+        if cookie.len() != COOKIE_LEN {
+            return Err(Error::Authentication(format!(
+                "Invalid cookie length in {}: {}",
+                path.display(),
+                cookie.len()
+            )));
+        }
+
+        Ok(cookie)
+    }
+
+    pub(crate) fn start(&self, info: &ProtocolInfo) -> Result<AuthExchange, Error> {
+        let mut client_nonce = [0; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut client_nonce);
+
+        self.start_with_nonce(info, client_nonce)
+    }
+
+    fn start_with_nonce(
+        &self,
+        info: &ProtocolInfo,
+        client_nonce: [u8; NONCE_LEN],
+    ) -> Result<AuthExchange, Error> {
+        let has = |method| info.auth_methods.contains(&method);
+
+        if has(AuthMethod::SafeCookie) || has(AuthMethod::Cookie) {
+            match self.read_cookie(info) {
+                Ok(cookie) if has(AuthMethod::SafeCookie) => {
+                    debug!("Authenticating with SAFECOOKIE");
+                    return Ok(AuthExchange::Challenge {
+                        cookie,
+                        client_nonce,
+                    });
+                }
+                Ok(cookie) => {
+                    debug!("Authenticating with COOKIE");
+                    return Ok(AuthExchange::Authenticate(format!(
+                        "AUTHENTICATE {}",
+                        HEXUPPER.encode(&cookie)
+                    )));
+                }
+                Err(e) => debug!("Skipping cookie authentication: {}", e),
+            }
+        }
+
+        match &self.password {
+            Some(password) if has(AuthMethod::HashedPassword) => {
+                debug!("Authenticating with HASHEDPASSWORD");
+                return Ok(AuthExchange::Authenticate(format!(
+                    "AUTHENTICATE {}",
+                    quote(password)
+                )));
+            }
+            _ => {}
+        }
+
+        if has(AuthMethod::Null) {
+            debug!("Authenticating with NULL");
+            return Ok(AuthExchange::Authenticate("AUTHENTICATE".into()));
+        }
+
+        Err(Error::Authentication(format!(
+            "No usable authentication method among {:?}",
+            info.auth_methods
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::reply::fixtures::parse_all;
+    use super::*;
+
+    const COOKIE: [u8; COOKIE_LEN] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
+        0x1e, 0x1f,
+    ];
+    const CLIENT_NONCE: [u8; NONCE_LEN] = [0xaa; NONCE_LEN];
+
+    const AUTHCHALLENGE: &str = "AUTHCHALLENGE SAFECOOKIE \
+                                 AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+    const AUTHCHALLENGE_REPLY: &str = "250 AUTHCHALLENGE \
+        SERVERHASH=5F7CB5569957C99373E329258545940F029AF175DA225F4A3125EEBF30C24BBA \
+        SERVERNONCE=5555555555555555555555555555555555555555555555555555555555555555\r\n";
+    const AUTHENTICATE: &str =
+        "AUTHENTICATE 9F99F2C4B75C91123CA5C6C6E3F76A9EF7FBD9471053CBE34454A4269CC39F7C";
+
+    /// Write a cookie to a temporary file, returning its path
+    fn cookie_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "libtor-test-{}-{}.authcookie",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, COOKIE).unwrap();
+        path
+    }
+
+    fn protocol_info(methods: &str, cookie_file: Option<&PathBuf>) -> ProtocolInfo {
+        let cookie = cookie_file
+            .map(|p| format!(" COOKIEFILE={}", quote(&p.display().to_string())))
+            .unwrap_or_default();
+        let transcript = format!(
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={}{}\r\n250-VERSION Tor=\"0.4.7.13\"\r\n250 OK\r\n",
+            methods, cookie
+        );
+        ProtocolInfo::from_reply(&parse_all(&transcript)[0]).unwrap()
+    }
+
+    #[test]
+    fn test_safecookie() {
+        let path = cookie_file("safecookie");
+        let info = protocol_info("COOKIE,SAFECOOKIE,HASHEDPASSWORD", Some(&path));
+
+        let exchange = Authenticator::new()
+            .password("secret")
+            .start_with_nonce(&info, CLIENT_NONCE)
+            .unwrap();
+        assert_eq!(exchange.command(), AUTHCHALLENGE);
+        let reply = &parse_all(AUTHCHALLENGE_REPLY)[0];
+        assert_eq!(exchange.respond(reply).unwrap(), AUTHENTICATE);
+
+        // Flip one bit of the server nonce
+        let tampered = AUTHCHALLENGE_REPLY.replace("SERVERNONCE=5", "SERVERNONCE=4");
+        match exchange.respond(&parse_all(&tampered)[0]) {
+            Err(Error::Authentication(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cookie() {
+        let path = cookie_file("cookie");
+        let info = protocol_info("COOKIE,HASHEDPASSWORD", None);
+
+        let exchange = Authenticator::new()
+            .cookie_file(&path)
+            .start_with_nonce(&info, CLIENT_NONCE)
+            .unwrap();
+        assert_eq!(
+            exchange.command(),
+            "AUTHENTICATE 000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fallback() {
+        let missing = std::env::temp_dir().join("libtor-test-missing.authcookie");
+        let info = protocol_info("SAFECOOKIE,HASHEDPASSWORD", Some(&missing));
+
+        let exchange = Authenticator::new()
+            .password("secret")
+            .start_with_nonce(&info, CLIENT_NONCE)
+            .unwrap();
+        assert_eq!(exchange.command(), "AUTHENTICATE \"secret\"");
+
+        match Authenticator::new().start_with_nonce(&info, CLIENT_NONCE) {
+            Err(Error::Authentication(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let info = protocol_info("NULL", None);
+        let exchange = Authenticator::new()
+            .password("secret")
+            .start_with_nonce(&info, CLIENT_NONCE)
+            .unwrap();
+        assert_eq!(exchange.command(), "AUTHENTICATE");
+    }
+}
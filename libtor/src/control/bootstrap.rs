@@ -0,0 +1,320 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::time::{Duration, Instant};
+
+use log_crate::{debug, warn};
+
+use super::reply::parse_arguments;
+use super::ControlConnection;
+use crate::Error;
+
+/// Problem reported by Tor while bootstrapping, with a `WARN` severity
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BootstrapProblem {
+    /// Human-readable description of the problem
+    pub warning: String,
+    /// Machine-readable reason, e.g. `CONNECTREFUSED` or `TIMEOUT`
+    pub reason: String,
+    /// How many times the problem occurred
+    pub count: u32,
+    /// Whether Tor recommends to `ignore` or `warn` about the problem
+    pub recommendation: String,
+    /// Address of the relay involved, if any
+    pub host: Option<String>,
+}
+
+impl BootstrapProblem {
+    /// Whether Tor recommends to report the problem to the user
+    pub fn is_fatal(&self) -> bool {
+        self.recommendation == "warn"
+    }
+}
+
+/// Bootstrap phase, as reported by `BOOTSTRAP` status events and
+/// `GETINFO status/bootstrap-phase`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BootstrapPhase {
+    /// Progress, in percent
+    pub progress: u8,
+    /// Machine-readable name of the phase, e.g. `conn_done` or `done`
+    pub tag: String,
+    /// Human-readable description of the phase
+    pub summary: String,
+    /// Problem encountered during this phase, if any
+    pub problem: Option<BootstrapProblem>,
+}
+
+impl BootstrapPhase {
+    /// Whether Tor has finished bootstrapping
+    pub fn is_done(&self) -> bool {
+        self.progress >= 100
+    }
+
+    /// Parse a status line such as `NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY="Done"`
+    ///
+    /// The leading `STATUS_CLIENT` keyword of status events is accepted too. Returns `None` if
+    /// the line is not a `BOOTSTRAP` status.
+    pub fn from_status(line: &str) -> Result<Option<BootstrapPhase>, Error> {
+        let args = parse_arguments(line)?;
+        let mut positional = args.positional.iter().map(String::as_str);
+        let severity = match positional.next() {
+            Some("STATUS_CLIENT") => positional.next(),
+            other => other,
+        };
+        if positional.next() != Some("BOOTSTRAP") {
+            return Ok(None);
+        }
+
+        let invalid = || Error::Protocol(format!("Invalid bootstrap status: {}", line));
+        let problem = if severity == Some("WARN") {
+            Some(BootstrapProblem {
+                warning: args.get("WARNING").unwrap_or_default().to_string(),
+                reason: args.get("REASON").unwrap_or_default().to_string(),
+                count: args
+                    .get("COUNT")
+                    .and_then(|c| c.parse().ok())
+                    .unwrap_or_default(),
+                recommendation: args.get("RECOMMENDATION").unwrap_or_default().to_string(),
+                host: args.get("HOSTADDR").map(String::from),
+            })
+        } else {
+            None
+        };
+
+        Ok(Some(BootstrapPhase {
+            progress: args
+                .get("PROGRESS")
+                .and_then(|p| p.parse().ok())
+                .ok_or_else(invalid)?,
+            tag: args.get("TAG").ok_or_else(invalid)?.to_string(),
+            summary: args.get("SUMMARY").unwrap_or_default().to_string(),
+            problem,
+        }))
+    }
+}
+
+impl std::fmt::Display for BootstrapPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}% ({}): {}", self.progress, self.tag, self.summary)?;
+        if let Some(problem) = &self.problem {
+            write!(f, " [{}: {}]", problem.reason, problem.warning)?;
+        }
+        Ok(())
+    }
+}
+
+impl BootstrapPhase {
+    /// Emit the phase as a `tracing` event
+    fn trace(&self) {
+        tracing_event!(
+            info,
+            progress = self.progress,
+            tag = %self.tag,
+            summary = %self.summary,
+            warning = self.problem.as_ref().map(|p| p.warning.as_str()),
+            reason = self.problem.as_ref().map(|p| p.reason.as_str()),
+            "bootstrap phase"
+        );
+    }
+}
+
+impl ControlConnection {
+    /// Block until Tor has finished bootstrapping, calling `callback` for every new phase
+    ///
+    /// This replaces the event subscriptions of the connection with `STATUS_CLIENT`. Fails
+    /// with [`Error::Bootstrap`] if Tor reports a problem it recommends to warn about, or with
+    /// [`Error::Timeout`] if Tor hasn't bootstrapped within `timeout`.
+    pub fn wait_for_bootstrap<F>(
+        &self,
+        timeout: Duration,
+        mut callback: F,
+    ) -> Result<BootstrapPhase, Error>
+    where
+        F: FnMut(&BootstrapPhase),
+    {
+        tracing_span!(info_span, "tor_bootstrap", timeout = ?timeout);
+        let deadline = Instant::now() + timeout;
+
+        self.set_events(&["STATUS_CLIENT"])?;
+        let mut phase = self.bootstrap_phase()?;
+        phase.trace();
+        callback(&phase);
+
+        loop {
+            if phase.is_done() {
+                return Ok(phase);
+            }
+            if let Some(problem) = phase.problem.as_ref().filter(|p| p.is_fatal()) {
+                warn!("Bootstrap problem: {}", problem.warning);
+                return Err(Error::Bootstrap(Box::new(phase)));
+            }
+
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if remaining > Duration::from_millis(0) => remaining,
+                _ => return Err(Error::Timeout),
+            };
+            // The read timeout set by the caller is left untouched
+            let event = self.next_event_timeout(remaining)?;
+            if let Some(new_phase) = BootstrapPhase::from_status(event.message())? {
+                debug!("Bootstrap phase: {}", new_phase);
+                new_phase.trace();
+                callback(&new_phase);
+                phase = new_phase;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::FakeControlServer;
+    use super::super::ListenerAddr;
+    use super::*;
+
+    const GETINFO_STARTING: &str = "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=0 \
+                                    TAG=starting SUMMARY=\"Starting\"\r\n250 OK\r\n";
+
+    #[test]
+    fn test_parse_phase() {
+        let phase = BootstrapPhase::from_status(
+            "STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=14 TAG=handshake SUMMARY=\"Handshaking with a relay\"",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            phase,
+            BootstrapPhase {
+                progress: 14,
+                tag: "handshake".into(),
+                summary: "Handshaking with a relay".into(),
+                problem: None,
+            }
+        );
+        assert!(!phase.is_done());
+
+        let phase = BootstrapPhase::from_status(
+            "WARN BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY=\"Connecting to a relay\" \
+             WARNING=\"Connection refused\" REASON=CONNECTREFUSED COUNT=3 \
+             RECOMMENDATION=ignore HOSTID=\"$AAAA\" HOSTADDR=\"1.2.3.4:443\"",
+        )
+        .unwrap()
+        .unwrap();
+        let problem = phase.problem.unwrap();
+        assert_eq!(problem.reason, "CONNECTREFUSED");
+        assert_eq!(problem.count, 3);
+        assert_eq!(problem.host.as_deref(), Some("1.2.3.4:443"));
+        assert!(!problem.is_fatal());
+
+        assert_eq!(
+            BootstrapPhase::from_status("NOTICE CIRCUIT_ESTABLISHED").unwrap(),
+            None
+        );
+        assert!(BootstrapPhase::from_status("NOTICE BOOTSTRAP TAG=done").is_err());
+    }
+
+    #[test]
+    fn test_wait_for_bootstrap() {
+        let server = FakeControlServer::new(vec![
+            ("SETEVENTS STATUS_CLIENT", "250 OK\r\n"),
+            (
+                "GETINFO status/bootstrap-phase",
+                concat!(
+                    "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=0 TAG=starting SUMMARY=\"Starting\"\r\n",
+                    "250 OK\r\n",
+                    "650 STATUS_GENERAL NOTICE CLOCK_JUMPED TIME=120\r\n",
+                    "650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\"\r\n",
+                    "650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n",
+                ),
+            ),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        let mut progress = vec![];
+        let phase = conn
+            .wait_for_bootstrap(Duration::from_secs(10), |p| progress.push(p.progress))
+            .unwrap();
+        assert!(phase.is_done());
+        assert_eq!(progress, vec![0, 50, 100]);
+
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_wait_for_bootstrap_problem() {
+        let server = FakeControlServer::new(vec![
+            ("SETEVENTS STATUS_CLIENT", "250 OK\r\n"),
+            (
+                "GETINFO status/bootstrap-phase",
+                concat!(
+                    "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=0 TAG=starting SUMMARY=\"Starting\"\r\n",
+                    "250 OK\r\n",
+                    "650 STATUS_CLIENT WARN BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY=\"Connecting to a relay\" WARNING=\"No route to host\" REASON=NOROUTE COUNT=1 RECOMMENDATION=ignore\r\n",
+                    "650 STATUS_CLIENT WARN BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY=\"Connecting to a relay\" WARNING=\"No route to host\" REASON=NOROUTE COUNT=10 RECOMMENDATION=warn\r\n",
+                ),
+            ),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        match conn.wait_for_bootstrap(Duration::from_secs(10), |_| {}) {
+            Err(Error::Bootstrap(phase)) => {
+                assert_eq!(phase.problem.unwrap().count, 10);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_wait_for_bootstrap_invalid_event() {
+        let server = FakeControlServer::new(vec![
+            ("SETEVENTS STATUS_CLIENT", "250 OK\r\n"),
+            (
+                "GETINFO status/bootstrap-phase",
+                concat!(
+                    "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=0 TAG=starting SUMMARY=\"Starting\"\r\n",
+                    "250 OK\r\n",
+                    "650 STATUS_CLIENT NOTICE BOOTSTRAP TAG=done\r\n",
+                ),
+            ),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5)));
+
+        assert!(matches!(
+            conn.wait_for_bootstrap(Duration::from_secs(10), |_| {}),
+            Err(Error::Protocol(_))
+        ));
+        assert_eq!(conn.read_timeout(), Some(Duration::from_secs(5)));
+
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_wait_for_bootstrap_timeout() {
+        let server = FakeControlServer::new(vec![
+            ("SETEVENTS STATUS_CLIENT", "250 OK\r\n"),
+            ("GETINFO status/bootstrap-phase", GETINFO_STARTING),
+            ("GETINFO version", "250-version=0.4.7.13\r\n250 OK\r\n"),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(5)));
+
+        match conn.wait_for_bootstrap(Duration::from_millis(100), |_| {}) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(conn.read_timeout(), Some(Duration::from_secs(5)));
+        // The connection is still usable after a timeout
+        assert_eq!(conn.getinfo("version").unwrap(), "0.4.7.13");
+
+        drop(conn);
+        server.join();
+    }
+}
@@ -0,0 +1,781 @@
+use super::reply::{parse_arguments, Arguments};
+use super::{ControlConnection, Reply, Signal};
+use crate::log::LogLevel;
+use crate::Error;
+
+/// Asynchronous event that can be subscribed to with [`ControlConnection::subscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// Circuit status changed
+    Circ,
+    /// Stream status changed
+    Stream,
+    /// Status of a connection to a relay changed
+    OrConn,
+    /// Bandwidth used in the last second
+    Bw,
+    /// General status events
+    StatusGeneral,
+    /// Status events of the client, e.g. bootstrap progress
+    StatusClient,
+    /// Status events of the relay
+    StatusServer,
+    /// Progress of hidden service descriptor fetches and uploads
+    HsDesc,
+    /// Content of the hidden service descriptors fetched
+    HsDescContent,
+    /// Whether the network is reachable
+    NetworkLiveness,
+    /// Configuration changed
+    ConfChanged,
+    /// Signal received by Tor
+    Signal,
+}
+
+impl EventKind {
+    /// Name of the event in the control protocol, e.g. `STATUS_CLIENT`
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Circ => "CIRC",
+            EventKind::Stream => "STREAM",
+            EventKind::OrConn => "ORCONN",
+            EventKind::Bw => "BW",
+            EventKind::StatusGeneral => "STATUS_GENERAL",
+            EventKind::StatusClient => "STATUS_CLIENT",
+            EventKind::StatusServer => "STATUS_SERVER",
+            EventKind::HsDesc => "HS_DESC",
+            EventKind::HsDescContent => "HS_DESC_CONTENT",
+            EventKind::NetworkLiveness => "NETWORK_LIVENESS",
+            EventKind::ConfChanged => "CONF_CHANGED",
+            EventKind::Signal => "SIGNAL",
+        }
+    }
+}
+
+impl std::fmt::Display for EventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Status reported in an event, falling back to `Other` for values added by newer versions of
+/// Tor
+macro_rules! event_status {
+    ($(#[$attr:meta])* $name:ident { $($variant:ident => $value:literal),* $(,)? }) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum $name {
+            $($variant,)*
+            Other(String),
+        }
+
+        impl $name {
+            fn parse(s: &str) -> $name {
+                match s {
+                    $($value => $name::$variant,)*
+                    other => $name::Other(other.to_string()),
+                }
+            }
+        }
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    $($name::$variant => write!(f, $value),)*
+                    $name::Other(other) => write!(f, "{}", other),
+                }
+            }
+        }
+    };
+}
+
+event_status!(
+    /// Status of a circuit
+    CircuitStatus {
+        Launched => "LAUNCHED",
+        Built => "BUILT",
+        GuardWait => "GUARD_WAIT",
+        Extended => "EXTENDED",
+        Failed => "FAILED",
+        Closed => "CLOSED",
+    }
+);
+
+event_status!(
+    /// Status of a stream
+    StreamStatus {
+        New => "NEW",
+        NewResolve => "NEWRESOLVE",
+        Remap => "REMAP",
+        SentConnect => "SENTCONNECT",
+        SentResolve => "SENTRESOLVE",
+        Succeeded => "SUCCEEDED",
+        Failed => "FAILED",
+        Closed => "CLOSED",
+        Detached => "DETACHED",
+        ControllerWait => "CONTROLLER_WAIT",
+        XoffSent => "XOFF_SENT",
+        XoffReceived => "XOFF_RECV",
+        XonSent => "XON_SENT",
+        XonReceived => "XON_RECV",
+    }
+);
+
+event_status!(
+    /// Status of a connection to a relay
+    OrConnStatus {
+        New => "NEW",
+        Launched => "LAUNCHED",
+        Connected => "CONNECTED",
+        Failed => "FAILED",
+        Closed => "CLOSED",
+    }
+);
+
+event_status!(
+    /// Step of a hidden service descriptor fetch or upload
+    HsDescAction {
+        Requested => "REQUESTED",
+        Upload => "UPLOAD",
+        Received => "RECEIVED",
+        Uploaded => "UPLOADED",
+        Ignore => "IGNORE",
+        Failed => "FAILED",
+        Created => "CREATED",
+    }
+);
+
+/// `CIRC` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitEvent {
+    pub id: String,
+    pub status: CircuitStatus,
+    /// Relays of the circuit, as `$<fingerprint>~<nickname>`
+    pub path: Vec<String>,
+    pub build_flags: Vec<String>,
+    pub purpose: Option<String>,
+    pub hs_state: Option<String>,
+    pub rend_query: Option<String>,
+    /// Reason of a `FAILED` or `CLOSED` circuit
+    pub reason: Option<String>,
+    pub remote_reason: Option<String>,
+    /// SOCKS credentials of the streams attached, used for stream isolation
+    pub socks_username: Option<String>,
+    pub socks_password: Option<String>,
+}
+
+/// `STREAM` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamEvent {
+    pub id: String,
+    pub status: StreamStatus,
+    /// Circuit the stream is attached to, `0` if none
+    pub circuit_id: String,
+    /// Destination of the stream, as `<host>:<port>`
+    pub target: String,
+    pub reason: Option<String>,
+    pub remote_reason: Option<String>,
+    pub source: Option<String>,
+    pub source_addr: Option<String>,
+    pub purpose: Option<String>,
+    pub socks_username: Option<String>,
+    pub socks_password: Option<String>,
+}
+
+/// `ORCONN` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrConnEvent {
+    /// Relay, as `$<fingerprint>~<nickname>` or `<address>:<port>`
+    pub target: String,
+    pub status: OrConnStatus,
+    pub reason: Option<String>,
+    pub num_circuits: Option<u32>,
+    pub id: Option<String>,
+}
+
+/// Source of a `STATUS_*` event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusKind {
+    General,
+    Client,
+    Server,
+}
+
+/// `STATUS_GENERAL`, `STATUS_CLIENT` or `STATUS_SERVER` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusEvent {
+    pub kind: StatusKind,
+    /// Either `Notice`, `Warn` or `Err`
+    pub severity: LogLevel,
+    /// What happened, e.g. `BOOTSTRAP` or `CIRCUIT_ESTABLISHED`
+    pub action: String,
+    /// Arguments of the action, e.g. `PROGRESS=100`
+    pub arguments: Arguments,
+}
+
+/// `HS_DESC` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsDescEvent {
+    pub action: HsDescAction,
+    /// Onion address of the service without the `.onion` suffix, or `UNKNOWN`
+    pub address: String,
+    pub auth_type: String,
+    /// Directory the descriptor is fetched from or uploaded to, or `UNKNOWN`
+    pub hs_dir: String,
+    pub descriptor_id: Option<String>,
+    pub reason: Option<String>,
+    pub replica: Option<String>,
+    pub hs_dir_index: Option<String>,
+}
+
+/// `HS_DESC_CONTENT` event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsDescContentEvent {
+    pub address: String,
+    pub descriptor_id: String,
+    pub hs_dir: String,
+    /// Descriptor fetched, empty if the fetch failed
+    pub descriptor: String,
+}
+
+/// Asynchronous event received from the control port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Circ(CircuitEvent),
+    Stream(StreamEvent),
+    OrConn(OrConnEvent),
+    /// Bytes read and written in the last second
+    Bw {
+        read: u64,
+        written: u64,
+    },
+    Status(StatusEvent),
+    HsDesc(HsDescEvent),
+    HsDescContent(HsDescContentEvent),
+    /// Whether Tor thinks that the network is reachable
+    NetworkLiveness {
+        up: bool,
+    },
+    /// Options that were changed, with their new value or `None` if reset to their default
+    ConfChanged(Vec<(String, Option<String>)>),
+    Signal(Signal),
+    /// Any other event, e.g. log messages
+    Other(Reply),
+}
+
+/// Arguments of an event, failing with [`Error::Protocol`] when a required one is missing
+struct EventArguments<'a> {
+    line: &'a str,
+    args: Arguments,
+}
+
+impl EventArguments<'_> {
+    fn parse(line: &str) -> Result<EventArguments<'_>, Error> {
+        Ok(EventArguments {
+            line,
+            args: parse_arguments(line)?,
+        })
+    }
+
+    fn positional(&self, index: usize) -> Result<&str, Error> {
+        self.optional_positional(index)
+            .ok_or_else(|| Error::Protocol(format!("Missing argument in event: {}", self.line)))
+    }
+
+    fn optional_positional(&self, index: usize) -> Option<&str> {
+        self.args.positional.get(index).map(String::as_str)
+    }
+
+    fn keyword(&self, key: &str) -> Option<String> {
+        self.args.get(key).map(str::to_string)
+    }
+
+    fn list(&self, key: &str) -> Vec<String> {
+        self.args
+            .get(key)
+            .map(|v| v.split(',').map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    fn number<T: std::str::FromStr>(&self, index: usize) -> Result<T, Error> {
+        self.positional(index)?
+            .parse()
+            .map_err(|_| Error::Protocol(format!("Invalid number in event: {}", self.line)))
+    }
+}
+
+impl CircuitEvent {
+    /// Parse a `CIRC` event line, also used for the `circuit-status` GETINFO key
+    pub(crate) fn from_line(line: &str) -> Result<CircuitEvent, Error> {
+        let args = EventArguments::parse(line)?;
+        // The path is omitted for circuits that have no hop yet
+        let path = match args.optional_positional(3) {
+            Some(path) => path.split(',').map(str::to_string).collect(),
+            None => vec![],
+        };
+        Ok(CircuitEvent {
+            id: args.positional(1)?.to_string(),
+            status: CircuitStatus::parse(args.positional(2)?),
+            path,
+            build_flags: args.list("BUILD_FLAGS"),
+            purpose: args.keyword("PURPOSE"),
+            hs_state: args.keyword("HS_STATE"),
+            rend_query: args.keyword("REND_QUERY"),
+            reason: args.keyword("REASON"),
+            remote_reason: args.keyword("REMOTE_REASON"),
+            socks_username: args.keyword("SOCKS_USERNAME"),
+            socks_password: args.keyword("SOCKS_PASSWORD"),
+        })
+    }
+}
+
+impl StreamEvent {
+    /// Parse a `STREAM` event line, also used for the `stream-status` GETINFO key
+    pub(crate) fn from_line(line: &str) -> Result<StreamEvent, Error> {
+        let args = EventArguments::parse(line)?;
+        Ok(StreamEvent {
+            id: args.positional(1)?.to_string(),
+            status: StreamStatus::parse(args.positional(2)?),
+            circuit_id: args.positional(3)?.to_string(),
+            target: args.positional(4)?.to_string(),
+            reason: args.keyword("REASON"),
+            remote_reason: args.keyword("REMOTE_REASON"),
+            source: args.keyword("SOURCE"),
+            source_addr: args.keyword("SOURCE_ADDR"),
+            purpose: args.keyword("PURPOSE"),
+            socks_username: args.keyword("SOCKS_USERNAME"),
+            socks_password: args.keyword("SOCKS_PASSWORD"),
+        })
+    }
+}
+
+impl Event {
+    /// Parse an asynchronous reply
+    ///
+    /// Events of unknown types are returned as [`Event::Other`], and malformed ones fail with
+    /// [`Error::Protocol`].
+    pub fn from_reply(reply: &Reply) -> Result<Event, Error> {
+        let line = match reply.lines.first() {
+            Some(line) if reply.is_async() => line,
+            _ => return Err(Error::Protocol(format!("Not an event: {:?}", reply))),
+        };
+        let kind = line.text.split(' ').next().unwrap_or_default();
+
+        match kind {
+            "CIRC" => CircuitEvent::from_line(&line.text).map(Event::Circ),
+            "STREAM" => StreamEvent::from_line(&line.text).map(Event::Stream),
+            "ORCONN" => {
+                let args = EventArguments::parse(&line.text)?;
+                Ok(Event::OrConn(OrConnEvent {
+                    target: args.positional(1)?.to_string(),
+                    status: OrConnStatus::parse(args.positional(2)?),
+                    reason: args.keyword("REASON"),
+                    num_circuits: args.args.get("NCIRCS").and_then(|n| n.parse().ok()),
+                    id: args.keyword("ID"),
+                }))
+            }
+            "BW" => {
+                let args = EventArguments::parse(&line.text)?;
+                Ok(Event::Bw {
+                    read: args.number(1)?,
+                    written: args.number(2)?,
+                })
+            }
+            "STATUS_GENERAL" | "STATUS_CLIENT" | "STATUS_SERVER" => {
+                let mut args = EventArguments::parse(&line.text)?;
+                let kind = match kind {
+                    "STATUS_GENERAL" => StatusKind::General,
+                    "STATUS_CLIENT" => StatusKind::Client,
+                    _ => StatusKind::Server,
+                };
+                let severity = args.positional(1)?.parse()?;
+                let action = args.positional(2)?.to_string();
+                args.args.positional.drain(..3);
+
+                Ok(Event::Status(StatusEvent {
+                    kind,
+                    severity,
+                    action,
+                    arguments: args.args,
+                }))
+            }
+            "HS_DESC" => {
+                let args = EventArguments::parse(&line.text)?;
+                Ok(Event::HsDesc(HsDescEvent {
+                    action: HsDescAction::parse(args.positional(1)?),
+                    address: args.positional(2)?.to_string(),
+                    auth_type: args.positional(3)?.to_string(),
+                    hs_dir: args.positional(4)?.to_string(),
+                    descriptor_id: args.optional_positional(5).map(str::to_string),
+                    reason: args.keyword("REASON"),
+                    replica: args.keyword("REPLICA"),
+                    hs_dir_index: args.keyword("HSDIR_INDEX"),
+                }))
+            }
+            "HS_DESC_CONTENT" => {
+                let args = EventArguments::parse(&line.text)?;
+                Ok(Event::HsDescContent(HsDescContentEvent {
+                    address: args.positional(1)?.to_string(),
+                    descriptor_id: args.positional(2)?.to_string(),
+                    hs_dir: args.positional(3)?.to_string(),
+                    descriptor: line.data.clone().unwrap_or_default(),
+                }))
+            }
+            "NETWORK_LIVENESS" => {
+                let args = EventArguments::parse(&line.text)?;
+                match args.positional(1)? {
+                    "UP" => Ok(Event::NetworkLiveness { up: true }),
+                    "DOWN" => Ok(Event::NetworkLiveness { up: false }),
+                    _ => Err(Error::Protocol(format!("Invalid event: {}", line.text))),
+                }
+            }
+            "CONF_CHANGED" => Ok(Event::ConfChanged(
+                reply.lines[1..]
+                    .iter()
+                    .filter(|l| l.text != "OK")
+                    .map(|l| match l.text.split_once('=') {
+                        Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                        None => (l.text.clone(), None),
+                    })
+                    .collect(),
+            )),
+            "SIGNAL" => {
+                let args = EventArguments::parse(&line.text)?;
+                Ok(Event::Signal(args.positional(1)?.parse()?))
+            }
+            _ => Ok(Event::Other(reply.clone())),
+        }
+    }
+}
+
+/// Blocking iterator over the events received by a [`ControlConnection`]
+///
+/// Returned by [`ControlConnection::events`], ends when the connection is closed by Tor.
+#[derive(Debug)]
+pub struct Events<'a> {
+    conn: &'a ControlConnection,
+    done: bool,
+}
+
+impl Iterator for Events<'_> {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.conn.next_event() {
+            Ok(reply) => Some(Event::from_reply(&reply)),
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.done = true;
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// `SETEVENTS` command for the given kinds
+pub(crate) fn subscribe_command(kinds: &[EventKind]) -> String {
+    let names = kinds.iter().map(EventKind::name).collect::<Vec<_>>();
+    format!("SETEVENTS {}", names.join(" "))
+        .trim_end()
+        .to_string()
+}
+
+impl ControlConnection {
+    /// Subscribe to the given events, replacing the previous subscriptions
+    ///
+    /// The events can then be read with [`ControlConnection::events`].
+    pub fn subscribe(&self, kinds: &[EventKind]) -> Result<(), Error> {
+        self.command(&subscribe_command(kinds)).map(|_| ())
+    }
+
+    /// Iterate over the events received, blocking until the next one
+    pub fn events(&self) -> Events<'_> {
+        Events {
+            conn: self,
+            done: false,
+        }
+    }
+
+    /// Subscribe to the given events and call `callback` for every one of them
+    ///
+    /// This blocks until the connection is closed by Tor, or `callback` returns an error.
+    pub fn watch_events<F>(&self, kinds: &[EventKind], mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(Event) -> Result<(), Error>,
+    {
+        self.subscribe(kinds)?;
+        for event in self.events() {
+            callback(event?)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::reply::fixtures::{parse_all, OK};
+    use super::super::testing::FakeControlServer;
+    use super::super::ListenerAddr;
+    use super::*;
+
+    fn parse(raw: &str) -> Event {
+        Event::from_reply(&parse_all(raw).remove(0)).unwrap()
+    }
+
+    #[test]
+    fn test_circ() {
+        match parse(
+            "650 CIRC 12 BUILT $AAAA~relay1,$BBBB~relay2 BUILD_FLAGS=NEED_CAPACITY,NEED_UPTIME \
+             PURPOSE=GENERAL TIME_CREATED=2022-01-01T00:00:00.000000 SOCKS_USERNAME=\"user\"\r\n",
+        ) {
+            Event::Circ(circ) => {
+                assert_eq!(circ.id, "12");
+                assert_eq!(circ.status, CircuitStatus::Built);
+                assert_eq!(circ.path, vec!["$AAAA~relay1", "$BBBB~relay2"]);
+                assert_eq!(circ.build_flags, vec!["NEED_CAPACITY", "NEED_UPTIME"]);
+                assert_eq!(circ.purpose.as_deref(), Some("GENERAL"));
+                assert_eq!(circ.socks_username.as_deref(), Some("user"));
+                assert_eq!(circ.reason, None);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match parse("650 CIRC 13 FAILED REASON=TIMEOUT\r\n") {
+            Event::Circ(circ) => {
+                assert_eq!(circ.status, CircuitStatus::Failed);
+                assert!(circ.path.is_empty());
+                assert_eq!(circ.reason.as_deref(), Some("TIMEOUT"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match parse("650 CIRC 14 SOMETHING_NEW\r\n") {
+            Event::Circ(circ) => {
+                assert_eq!(circ.status, CircuitStatus::Other("SOMETHING_NEW".into()))
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+        assert!(Event::from_reply(&parse_all("650 CIRC\r\n")[0]).is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        match parse(
+            "650 STREAM 34 FAILED 12 example.com:443 REASON=END REMOTE_REASON=CONNECTREFUSED \
+             SOURCE_ADDR=127.0.0.1:54321 PURPOSE=USER\r\n",
+        ) {
+            Event::Stream(stream) => {
+                assert_eq!(stream.id, "34");
+                assert_eq!(stream.status, StreamStatus::Failed);
+                assert_eq!(stream.circuit_id, "12");
+                assert_eq!(stream.target, "example.com:443");
+                assert_eq!(stream.remote_reason.as_deref(), Some("CONNECTREFUSED"));
+                assert_eq!(stream.source_addr.as_deref(), Some("127.0.0.1:54321"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_orconn() {
+        assert_eq!(
+            parse("650 ORCONN $AAAA~relay1 CLOSED REASON=DONE NCIRCS=3 ID=7\r\n"),
+            Event::OrConn(OrConnEvent {
+                target: "$AAAA~relay1".into(),
+                status: OrConnStatus::Closed,
+                reason: Some("DONE".into()),
+                num_circuits: Some(3),
+                id: Some("7".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_bw() {
+        assert_eq!(
+            parse("650 BW 1024 2048\r\n"),
+            Event::Bw {
+                read: 1024,
+                written: 2048
+            }
+        );
+        assert!(Event::from_reply(&parse_all("650 BW lots 0\r\n")[0]).is_err());
+    }
+
+    #[test]
+    fn test_status() {
+        match parse("650 STATUS_CLIENT NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n")
+        {
+            Event::Status(status) => {
+                assert_eq!(status.kind, StatusKind::Client);
+                assert_eq!(status.severity, LogLevel::Notice);
+                assert_eq!(status.action, "BOOTSTRAP");
+                assert_eq!(status.arguments.get("SUMMARY"), Some("Done"));
+                assert!(status.arguments.positional.is_empty());
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match parse("650 STATUS_GENERAL WARN CLOCK_SKEW SKEW=-3600 SOURCE=OR:1.2.3.4:443\r\n") {
+            Event::Status(status) => {
+                assert_eq!(status.kind, StatusKind::General);
+                assert_eq!(status.severity, LogLevel::Warn);
+                assert_eq!(status.arguments.get("SKEW"), Some("-3600"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        match parse("650 STATUS_SERVER ERR BAD_SERVER_DESCRIPTOR DIRAUTH=1.2.3.4:80\r\n") {
+            Event::Status(status) => {
+                assert_eq!(status.kind, StatusKind::Server);
+                assert_eq!(status.severity, LogLevel::Err);
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hs_desc() {
+        assert_eq!(
+            parse(
+                "650 HS_DESC RECEIVED abcdef NO_AUTH $CCCC~hsdir descid \
+                 HSDIR_INDEX=0123\r\n"
+            ),
+            Event::HsDesc(HsDescEvent {
+                action: HsDescAction::Received,
+                address: "abcdef".into(),
+                auth_type: "NO_AUTH".into(),
+                hs_dir: "$CCCC~hsdir".into(),
+                descriptor_id: Some("descid".into()),
+                reason: None,
+                replica: None,
+                hs_dir_index: Some("0123".into()),
+            })
+        );
+
+        match parse("650 HS_DESC FAILED abcdef NO_AUTH UNKNOWN REASON=NOT_FOUND\r\n") {
+            Event::HsDesc(desc) => {
+                assert_eq!(desc.action, HsDescAction::Failed);
+                assert_eq!(desc.descriptor_id, None);
+                assert_eq!(desc.reason.as_deref(), Some("NOT_FOUND"));
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_hs_desc_content() {
+        assert_eq!(
+            parse(
+                "650+HS_DESC_CONTENT abcdef descid $CCCC~hsdir\r\n\
+                 hs-descriptor 3\r\n\
+                 descriptor-lifetime 180\r\n\
+                 .\r\n\
+                 650 OK\r\n"
+            ),
+            Event::HsDescContent(HsDescContentEvent {
+                address: "abcdef".into(),
+                descriptor_id: "descid".into(),
+                hs_dir: "$CCCC~hsdir".into(),
+                descriptor: "hs-descriptor 3\ndescriptor-lifetime 180\n".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_network_liveness() {
+        assert_eq!(
+            parse("650 NETWORK_LIVENESS DOWN\r\n"),
+            Event::NetworkLiveness { up: false }
+        );
+        assert_eq!(
+            parse("650 NETWORK_LIVENESS UP\r\n"),
+            Event::NetworkLiveness { up: true }
+        );
+    }
+
+    #[test]
+    fn test_conf_changed() {
+        assert_eq!(
+            parse("650-CONF_CHANGED\r\n650-SocksPort=9050\r\n650-ExitNodes\r\n650 OK\r\n"),
+            Event::ConfChanged(vec![
+                ("SocksPort".into(), Some("9050".into())),
+                ("ExitNodes".into(), None),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_signal() {
+        assert_eq!(
+            parse("650 SIGNAL NEWNYM\r\n"),
+            Event::Signal(Signal::NewNym)
+        );
+        assert_eq!(
+            parse("650 SIGNAL CLEARDNSCACHE\r\n"),
+            Event::Signal(Signal::ClearDnsCache)
+        );
+    }
+
+    #[test]
+    fn test_other() {
+        assert!(matches!(
+            parse("650 NOTICE Something happened\r\n"),
+            Event::Other(_)
+        ));
+        assert!(Event::from_reply(&parse_all(OK)[0]).is_err());
+    }
+
+    #[test]
+    fn test_events() {
+        let server = FakeControlServer::new(vec![
+            ("SETEVENTS CIRC NETWORK_LIVENESS", OK),
+            (
+                "GETINFO version",
+                "650 NETWORK_LIVENESS UP\r\n250-version=0.4.7.13\r\n250 OK\r\n",
+            ),
+            (
+                "SETEVENTS",
+                "250 OK\r\n650 CIRC 1 FAILED REASON=TIMEOUT\r\n",
+            ),
+            ("SETEVENTS BW", "250 OK\r\n650 BW 1 2\r\n"),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        conn.subscribe(&[EventKind::Circ, EventKind::NetworkLiveness])
+            .unwrap();
+        conn.getinfo("version").unwrap();
+        conn.subscribe(&[]).unwrap();
+
+        // The event received while waiting for GETINFO is queued
+        let mut events = conn.events();
+        assert_eq!(
+            events.next().unwrap().unwrap(),
+            Event::NetworkLiveness { up: true }
+        );
+        assert!(matches!(events.next().unwrap().unwrap(), Event::Circ(_)));
+
+        // Stop watching by returning an error from the callback
+        let mut received = vec![];
+        let result = conn.watch_events(&[EventKind::Bw], |event| {
+            received.push(event);
+            Err(Error::NotRunning)
+        });
+        assert!(matches!(result, Err(Error::NotRunning)));
+        assert_eq!(
+            received,
+            vec![Event::Bw {
+                read: 1,
+                written: 2
+            }]
+        );
+
+        drop(conn);
+        server.join();
+    }
+}
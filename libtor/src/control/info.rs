@@ -0,0 +1,522 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use super::reply::parse_arguments;
+#[cfg(feature = "tokio")]
+use super::AsyncControlConnection;
+use super::{BootstrapPhase, CircuitEvent, ControlConnection, ListenerAddr, StreamEvent};
+use crate::hs::OnionAddressV3;
+use crate::Error;
+
+fn invalid(key: &str, value: &str) -> Error {
+    Error::Protocol(format!("Invalid value for GETINFO {}: {}", key, value))
+}
+
+/// Version of Tor, as returned by `GETINFO version`
+///
+/// Versions are ordered like Tor does, so a release comes after its alpha, beta and rc
+/// versions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TorVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub micro: u32,
+    pub patch: u32,
+    /// Status tag like `alpha` or `rc`, `None` for releases
+    pub status: Option<String>,
+}
+
+impl std::str::FromStr for TorVersion {
+    type Err = Error;
+
+    /// Parse a version like `0.4.8.9` or `0.4.9.1-alpha-dev (git-0123456789abcdef)`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidValue {
+            kind: "TorVersion".into(),
+            value: s.to_string(),
+        };
+
+        let version = s.split(' ').next().unwrap_or_default();
+        let (numbers, status) = match version.split_once('-') {
+            Some((numbers, status)) => (numbers, Some(status.to_string())),
+            None => (version, None),
+        };
+        let numbers = numbers
+            .split('.')
+            .map(|n| n.parse().map_err(|_| invalid()))
+            .collect::<Result<Vec<u32>, _>>()?;
+
+        match numbers.as_slice() {
+            [major, minor, micro] | [major, minor, micro, _] => Ok(TorVersion {
+                major: *major,
+                minor: *minor,
+                micro: *micro,
+                patch: numbers.get(3).copied().unwrap_or(0),
+                status,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for TorVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.micro, self.patch
+        )?;
+        if let Some(status) = &self.status {
+            write!(f, "-{}", status)?;
+        }
+        Ok(())
+    }
+}
+
+impl PartialOrd for TorVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TorVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let numbers = |v: &TorVersion| (v.major, v.minor, v.micro, v.patch);
+        // Releases have no status tag, and come after the pre-releases
+        let status = |v: &TorVersion| (v.status.is_none(), v.status.clone());
+        numbers(self)
+            .cmp(&numbers(other))
+            .then_with(|| status(self).cmp(&status(other)))
+    }
+}
+
+/// Entry of a relay in the consensus, as returned by `GETINFO ns/id/<fingerprint>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterStatus {
+    pub nickname: String,
+    /// Base64 encoded identity of the relay
+    pub identity: String,
+    /// Base64 encoded descriptor digest, `None` with a microdescriptor consensus
+    pub digest: Option<String>,
+    /// Publication time, in the `YYYY-MM-DD HH:MM:SS` format
+    pub published: String,
+    pub address: IpAddr,
+    pub or_port: u16,
+    pub dir_port: u16,
+    pub ipv6_address: Option<SocketAddr>,
+    /// Flags like `Guard` or `Exit`
+    pub flags: Vec<String>,
+    /// Version of Tor running on the relay, like `Tor 0.4.8.9`
+    pub version: Option<String>,
+    /// Consensus weight
+    pub bandwidth: Option<u64>,
+}
+
+impl RouterStatus {
+    /// Parse a router status entry from a network status document
+    pub fn from_entry(entry: &str) -> Result<RouterStatus, Error> {
+        let invalid = || Error::InvalidValue {
+            kind: "RouterStatus".into(),
+            value: entry.to_string(),
+        };
+
+        let mut status = None;
+        for line in entry.lines() {
+            let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let status = match (keyword, status.as_mut()) {
+                ("r", None) => {
+                    let fields = rest.split(' ').collect::<Vec<_>>();
+                    // The descriptor digest is missing from microdescriptor consensuses
+                    let (digest, offset) = match fields.len() {
+                        8 => (Some(fields[2].to_string()), 3),
+                        7 => (None, 2),
+                        _ => return Err(invalid()),
+                    };
+                    let port = |i: usize| fields[offset + i].parse().map_err(|_| invalid());
+                    status = Some(RouterStatus {
+                        nickname: fields[0].to_string(),
+                        identity: fields[1].to_string(),
+                        digest,
+                        published: format!("{} {}", fields[offset], fields[offset + 1]),
+                        address: fields[offset + 2].parse().map_err(|_| invalid())?,
+                        or_port: port(3)?,
+                        dir_port: port(4)?,
+                        ipv6_address: None,
+                        flags: vec![],
+                        version: None,
+                        bandwidth: None,
+                    });
+                    continue;
+                }
+                (_, Some(status)) => status,
+                _ => return Err(invalid()),
+            };
+
+            match keyword {
+                "a" if status.ipv6_address.is_none() => {
+                    status.ipv6_address = rest.parse().ok();
+                }
+                "s" => status.flags = rest.split(' ').map(str::to_string).collect(),
+                "v" => status.version = Some(rest.to_string()),
+                "w" => {
+                    let args = parse_arguments(rest).map_err(|_| invalid())?;
+                    status.bandwidth = args.get("Bandwidth").and_then(|b| b.parse().ok());
+                }
+                _ => {}
+            }
+        }
+
+        status.ok_or_else(invalid)
+    }
+}
+
+fn parse_tor_version(key: &str, value: &str) -> Result<TorVersion, Error> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+fn parse_listeners(key: &str, value: &str) -> Result<Vec<ListenerAddr>, Error> {
+    parse_arguments(value)?
+        .positional
+        .iter()
+        .map(|addr| addr.parse().map_err(|_| invalid(key, value)))
+        .collect()
+}
+
+fn parse_bootstrap_phase(key: &str, value: &str) -> Result<BootstrapPhase, Error> {
+    BootstrapPhase::from_status(value)?.ok_or_else(|| invalid(key, value))
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, Error> {
+    match value {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        _ => Err(invalid(key, value)),
+    }
+}
+
+fn parse_circuit_status(key: &str, value: &str) -> Result<Vec<CircuitEvent>, Error> {
+    value
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| CircuitEvent::from_line(&format!("CIRC {}", line)))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid(key, value))
+}
+
+fn parse_stream_status(key: &str, value: &str) -> Result<Vec<StreamEvent>, Error> {
+    value
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| StreamEvent::from_line(&format!("STREAM {}", line)))
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid(key, value))
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+fn parse_onions(key: &str, value: &str) -> Result<Vec<OnionAddressV3>, Error> {
+    value
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid(key, value))
+}
+
+fn parse_path(_key: &str, value: &str) -> Result<PathBuf, Error> {
+    Ok(PathBuf::from(value))
+}
+
+fn parse_address(key: &str, value: &str) -> Result<IpAddr, Error> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+fn parse_router_status(key: &str, value: &str) -> Result<RouterStatus, Error> {
+    RouterStatus::from_entry(value).map_err(|_| invalid(key, value))
+}
+
+fn router_status_key(fingerprint: &str) -> String {
+    format!("ns/id/{}", fingerprint.trim_start_matches('$'))
+}
+
+/// Implement the typed queries on both the blocking and the async connections
+///
+/// Each parser gets the queried key along with the value, to name it in its errors
+macro_rules! typed_getinfo {
+    ($($(#[$doc:meta])* $name:ident: $key:literal => $parse:ident -> $ty:ty;)*) => {
+        impl ControlConnection {
+            $(
+                $(#[$doc])*
+                pub fn $name(&self) -> Result<$ty, Error> {
+                    $parse($key, &self.getinfo($key)?)
+                }
+            )*
+
+            /// Query the consensus entry of a relay, from its hex encoded fingerprint
+            pub fn router_status(&self, fingerprint: &str) -> Result<RouterStatus, Error> {
+                let key = router_status_key(fingerprint);
+                parse_router_status(&key, &self.getinfo(&key)?)
+            }
+        }
+
+        #[cfg(feature = "tokio")]
+        impl AsyncControlConnection {
+            $(
+                $(#[$doc])*
+                pub async fn $name(&self) -> Result<$ty, Error> {
+                    $parse($key, &self.getinfo($key).await?)
+                }
+            )*
+
+            /// Query the consensus entry of a relay, from its hex encoded fingerprint
+            pub async fn router_status(&self, fingerprint: &str) -> Result<RouterStatus, Error> {
+                let key = router_status_key(fingerprint);
+                parse_router_status(&key, &self.getinfo(&key).await?)
+            }
+        }
+    };
+}
+
+typed_getinfo! {
+    /// Query the version of Tor
+    tor_version: "version" => parse_tor_version -> TorVersion;
+    /// Query the addresses of the SOCKS listeners
+    socks_listeners: "net/listeners/socks" => parse_listeners -> Vec<ListenerAddr>;
+    /// Query the addresses of the control listeners
+    control_listeners: "net/listeners/control" => parse_listeners -> Vec<ListenerAddr>;
+    /// Query the current bootstrap phase
+    bootstrap_phase: "status/bootstrap-phase" => parse_bootstrap_phase -> BootstrapPhase;
+    /// Whether Tor has managed to build a circuit
+    circuit_established: "status/circuit-established" => parse_bool -> bool;
+    /// Query the status of all the open circuits
+    circuit_status: "circuit-status" => parse_circuit_status -> Vec<CircuitEvent>;
+    /// Query the status of all the open streams
+    stream_status: "stream-status" => parse_stream_status -> Vec<StreamEvent>;
+    /// Query the number of bytes read since Tor started
+    traffic_read: "traffic/read" => parse_number -> u64;
+    /// Query the number of bytes written since Tor started
+    traffic_written: "traffic/written" => parse_number -> u64;
+    /// Query the onion services created by this connection with `ADD_ONION`
+    current_onions: "onions/current" => parse_onions -> Vec<OnionAddressV3>;
+    /// Query the path of the torrc file
+    config_file: "config-file" => parse_path -> PathBuf;
+    /// Query the process id of Tor
+    process_id: "process/pid" => parse_number -> u32;
+    /// Query the best guess of Tor for our external IP address
+    external_address: "address" => parse_address -> IpAddr;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::FakeControlServer;
+    use super::super::{CircuitStatus, StreamStatus};
+    use super::*;
+
+    const NS_ENTRY: &str =
+        "r relay1 AAAAAAAAAAAAAAAAAAAAAAAAAAA 2024-01-01 12:00:00 192.0.2.1 9001 0\n\
+                            a [2001:db8::1]:9001\n\
+                            s Fast Guard Running Stable Valid\n\
+                            v Tor 0.4.8.9\n\
+                            pr Cons=1-2\n\
+                            w Bandwidth=1500";
+
+    #[test]
+    fn test_tor_version() {
+        let version: TorVersion = "0.4.9.1-alpha-dev (git-0123456789abcdef)".parse().unwrap();
+        assert_eq!(
+            version,
+            TorVersion {
+                major: 0,
+                minor: 4,
+                micro: 9,
+                patch: 1,
+                status: Some("alpha-dev".into()),
+            }
+        );
+        assert_eq!(version.to_string(), "0.4.9.1-alpha-dev");
+
+        let release: TorVersion = "0.4.9.1".parse().unwrap();
+        assert!(release > version);
+        assert!(release < "0.4.10.0-alpha".parse().unwrap());
+        assert!("0.4".parse::<TorVersion>().is_err());
+    }
+
+    #[test]
+    fn test_router_status() {
+        let status = RouterStatus::from_entry(NS_ENTRY).unwrap();
+        assert_eq!(status.nickname, "relay1");
+        assert_eq!(status.digest, None);
+        assert_eq!(status.published, "2024-01-01 12:00:00");
+        assert_eq!(status.address, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!((status.or_port, status.dir_port), (9001, 0));
+        assert_eq!(
+            status.ipv6_address,
+            Some("[2001:db8::1]:9001".parse().unwrap())
+        );
+        assert_eq!(status.flags.len(), 5);
+        assert_eq!(status.version.as_deref(), Some("Tor 0.4.8.9"));
+        assert_eq!(status.bandwidth, Some(1500));
+
+        let status = RouterStatus::from_entry(
+            "r relay2 BBBB CCCC 2024-01-01 12:00:00 192.0.2.2 443 80\ns Exit",
+        )
+        .unwrap();
+        assert_eq!(status.digest.as_deref(), Some("CCCC"));
+        assert_eq!((status.or_port, status.dir_port), (443, 80));
+        assert_eq!(status.flags, vec!["Exit".to_string()]);
+
+        assert!(RouterStatus::from_entry("s Exit").is_err());
+    }
+
+    #[test]
+    fn test_typed_getinfo() {
+        let onion = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid";
+        let ns_reply = format!(
+            "250+ns/id/0123456789ABCDEF=\r\n{}\r\n.\r\n250 OK\r\n",
+            NS_ENTRY.replace('\n', "\r\n")
+        );
+        let script = vec![
+            (
+                "GETINFO version",
+                "250-version=0.4.8.9\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO net/listeners/socks",
+                "250-net/listeners/socks=\"127.0.0.1:9050\" \"[::1]:9050\"\r\n250 OK\r\n"
+                    .to_string(),
+            ),
+            (
+                "GETINFO status/circuit-established",
+                "250-status/circuit-established=1\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO circuit-status",
+                "250+circuit-status=\r\n\
+                 1 BUILT $AAAA~relay1,$BBBB~relay2 PURPOSE=GENERAL\r\n\
+                 2 LAUNCHED PURPOSE=HS_CLIENT_REND\r\n\
+                 .\r\n250 OK\r\n"
+                    .to_string(),
+            ),
+            (
+                "GETINFO stream-status",
+                "250-stream-status=\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO stream-status",
+                "250-stream-status=4 SUCCEEDED 1 example.com:443\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO traffic/read",
+                "250-traffic/read=123456\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO onions/current",
+                format!("250-onions/current={}\r\n250 OK\r\n", onion),
+            ),
+            (
+                "GETINFO config-file",
+                "250-config-file=/etc/tor/torrc\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO process/pid",
+                "250-process/pid=4242\r\n250 OK\r\n".to_string(),
+            ),
+            (
+                "GETINFO address",
+                "250-address=198.51.100.7\r\n250 OK\r\n".to_string(),
+            ),
+            ("GETINFO ns/id/0123456789ABCDEF", ns_reply),
+        ];
+        let server = FakeControlServer::with_script(
+            script
+                .into_iter()
+                .map(|(command, reply)| (command.to_string(), reply))
+                .collect(),
+        );
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        assert_eq!(conn.tor_version().unwrap().to_string(), "0.4.8.9");
+        assert_eq!(
+            conn.socks_listeners().unwrap(),
+            vec![
+                ListenerAddr::Tcp("127.0.0.1:9050".parse().unwrap()),
+                ListenerAddr::Tcp("[::1]:9050".parse().unwrap()),
+            ]
+        );
+        assert!(conn.circuit_established().unwrap());
+
+        let circuits = conn.circuit_status().unwrap();
+        assert_eq!(circuits.len(), 2);
+        assert_eq!(circuits[0].status, CircuitStatus::Built);
+        assert_eq!(circuits[0].path.len(), 2);
+        assert!(circuits[1].path.is_empty());
+
+        assert!(conn.stream_status().unwrap().is_empty());
+        let streams = conn.stream_status().unwrap();
+        assert_eq!(streams[0].status, StreamStatus::Succeeded);
+        assert_eq!(streams[0].target, "example.com:443");
+
+        assert_eq!(conn.traffic_read().unwrap(), 123456);
+        assert_eq!(
+            conn.current_onions().unwrap(),
+            vec![onion.parse::<OnionAddressV3>().unwrap()]
+        );
+        assert_eq!(conn.config_file().unwrap(), PathBuf::from("/etc/tor/torrc"));
+        assert_eq!(conn.process_id().unwrap(), 4242);
+        assert_eq!(
+            conn.external_address().unwrap(),
+            "198.51.100.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            conn.router_status("$0123456789ABCDEF").unwrap().nickname,
+            "relay1"
+        );
+
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let server = FakeControlServer::new(vec![
+            (
+                "GETINFO status/circuit-established",
+                "250-status/circuit-established=yes\r\n250 OK\r\n",
+            ),
+            (
+                "GETINFO traffic/written",
+                "250-traffic/written=lots\r\n250 OK\r\n",
+            ),
+            (
+                "GETINFO onions/current",
+                "250-onions/current=notanonion\r\n250 OK\r\n",
+            ),
+            (
+                "GETINFO ns/id/0123456789ABCDEF",
+                "250-ns/id/0123456789ABCDEF=s Exit\r\n250 OK\r\n",
+            ),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        match conn.circuit_established() {
+            Err(Error::Protocol(msg)) => assert!(msg.contains("status/circuit-established")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match conn.traffic_written() {
+            Err(Error::Protocol(msg)) => assert!(msg.contains("traffic/written")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match conn.current_onions() {
+            Err(Error::Protocol(msg)) => assert!(msg.contains("onions/current")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match conn.router_status("$0123456789ABCDEF") {
+            Err(Error::Protocol(msg)) => assert!(msg.contains("ns/id/0123456789ABCDEF")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        drop(conn);
+        server.join();
+    }
+}
@@ -0,0 +1,73 @@
+use super::ControlConnection;
+use crate::log::{LogLevel, LogMessage};
+use crate::Error;
+
+impl ControlConnection {
+    /// Subscribe to the log events of `level` and above, calling `callback` for every message
+    ///
+    /// This replaces the event subscriptions of the connection, and blocks until the connection
+    /// is closed by Tor. Log events don't include the domain of the messages.
+    pub fn watch_logs<F>(&self, level: LogLevel, mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(LogMessage),
+    {
+        let events = LogLevel::ALL
+            .iter()
+            .filter(|l| **l >= level)
+            .map(LogLevel::event_name)
+            .collect::<Vec<_>>();
+        let events = events.iter().map(String::as_str).collect::<Vec<_>>();
+
+        self.for_each_event(&events, |event| {
+            if let Some(message) = LogMessage::from_event(event) {
+                callback(message);
+            }
+        })
+    }
+
+    /// Forward the log messages of `level` and above to the `log` crate, with the `tor` target
+    ///
+    /// See [`ControlConnection::watch_logs`]. Usually run on a dedicated connection from its
+    /// own thread.
+    pub fn forward_logs(&self, level: LogLevel) -> Result<(), Error> {
+        self.watch_logs(level, |message| message.forward())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::testing::FakeControlServer;
+    use super::super::ListenerAddr;
+    use super::*;
+
+    #[test]
+    fn test_watch_logs() {
+        let server = FakeControlServer::new(vec![(
+            "SETEVENTS NOTICE WARN ERR",
+            "250 OK\r\n650 NOTICE Opening Socks listener\r\n650 STATUS_CLIENT NOTICE CIRCUIT_ESTABLISHED\r\n650 ERR Something failed\r\n",
+        )]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(200)));
+
+        let mut messages = vec![];
+        match conn.watch_logs(LogLevel::Notice, |m| messages.push(m)) {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(
+            messages
+                .iter()
+                .map(|m| (m.level, m.message.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                (LogLevel::Notice, "Opening Socks listener"),
+                (LogLevel::Err, "Something failed"),
+            ]
+        );
+
+        drop(conn);
+        server.join();
+    }
+}
@@ -0,0 +1,1146 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(target_family = "unix")]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use log_crate::{debug, trace};
+
+use crate::hs::{ClientAuthSecretKey, OnionAddressV3};
+use crate::{Error, Expand, Tor, TorAddress, TorFlag};
+
+/// Asynchronous control port client
+#[cfg(feature = "tokio")]
+mod async_client;
+/// Control port authentication
+mod auth;
+/// Bootstrap progress tracking
+mod bootstrap;
+/// Typed asynchronous events
+mod events;
+/// Typed `GETINFO` queries
+mod info;
+/// Forwarding of Tor's log events
+mod logs;
+/// Ephemeral onion services
+mod onion;
+/// Reply and argument parsers for the control protocol
+pub mod reply;
+#[cfg(test)]
+pub(crate) mod testing;
+/// Structured `tracing` events
+#[cfg(feature = "tracing")]
+mod trace;
+
+#[cfg(feature = "tokio")]
+pub use self::async_client::{AsyncControlConnection, EventStream};
+use self::auth::AuthExchange;
+pub use self::auth::{AuthMethod, Authenticator};
+pub use self::bootstrap::{BootstrapPhase, BootstrapProblem};
+pub use self::events::{
+    CircuitEvent, CircuitStatus, Event, EventKind, Events, HsDescAction, HsDescContentEvent,
+    HsDescEvent, OrConnEvent, OrConnStatus, StatusEvent, StatusKind, StreamEvent, StreamStatus,
+};
+pub use self::info::{RouterStatus, TorVersion};
+#[cfg(feature = "tokio")]
+pub use self::onion::AsyncOnionService;
+pub use self::onion::{OnionFlag, OnionService, OnionServiceBuilder};
+use self::reply::{parse_arguments, quote, ReplyParser};
+pub use self::reply::{Arguments, Reply, ReplyLine};
+#[cfg(feature = "tracing")]
+pub use self::trace::{trace_event, TRACED_EVENTS};
+
+/// Address of a listener opened by Tor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddr {
+    /// TCP socket
+    Tcp(SocketAddr),
+    /// Path to a Unix socket
+    #[cfg(target_family = "unix")]
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenerAddr::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(target_family = "unix")]
+            ListenerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl std::str::FromStr for ListenerAddr {
+    type Err = Error;
+
+    /// Parse an address in the format used by Tor, like `127.0.0.1:9050` or `unix:/path`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(target_family = "unix")]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenerAddr::Unix(path.into()));
+        }
+
+        s.parse()
+            .map(ListenerAddr::Tcp)
+            .map_err(|_| Error::InvalidValue {
+                kind: "ListenerAddr".into(),
+                value: s.to_string(),
+            })
+    }
+}
+
+impl ListenerAddr {
+    /// Parse the contents of a `ControlPortWriteToFile` file, with one `PORT=<address>` or
+    /// `UNIX_PORT=<path>` line per control listener
+    pub fn from_port_file(contents: &str) -> Result<Vec<ListenerAddr>, Error> {
+        let invalid = || Error::InvalidValue {
+            kind: "ControlPortWriteToFile".into(),
+            value: contents.to_string(),
+        };
+
+        let addrs = contents
+            .lines()
+            .filter_map(|line| match line.split_once('=') {
+                Some(("PORT", addr)) => Some(addr.parse().map_err(|_| invalid())),
+                #[cfg(target_family = "unix")]
+                Some(("UNIX_PORT", path)) => Some(Ok(ListenerAddr::Unix(path.into()))),
+                _ => None,
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if addrs.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(addrs)
+    }
+
+    /// Read a `ControlPortWriteToFile` file, see [`ListenerAddr::from_port_file`]
+    ///
+    /// Tor writes the file atomically once its listeners are open, so this fails with an
+    /// [`Error::Io`] of kind `NotFound` until then.
+    pub fn read_port_file<P: AsRef<Path>>(path: P) -> Result<Vec<ListenerAddr>, Error> {
+        ListenerAddr::from_port_file(&std::fs::read_to_string(path)?)
+    }
+
+    pub(crate) fn from_tor_address(address: &TorAddress) -> Option<ListenerAddr> {
+        match address {
+            TorAddress::Port(0) => None,
+            TorAddress::Port(port) => Some(ListenerAddr::Tcp(([127, 0, 0, 1], *port).into())),
+            TorAddress::Address(addr) => addr.parse().ok().map(ListenerAddr::Tcp),
+            TorAddress::AddressPort(addr, port) => {
+                (addr.trim_start_matches('[').trim_end_matches(']'), *port)
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .map(ListenerAddr::Tcp)
+            }
+            #[cfg(target_family = "unix")]
+            TorAddress::Unix(path) => Some(ListenerAddr::Unix(path.into())),
+        }
+    }
+}
+
+impl Tor {
+    /// Return the address of the first control port configured with `ControlPort`,
+    /// `ControlPortAddress` or `ControlSocket`
+    ///
+    /// Control ports configured with `ControlPortAuto` can't be known in advance, so they are
+    /// ignored.
+    pub fn control_addr(&self) -> Option<ListenerAddr> {
+        self.flags.iter().find_map(|flag| match flag {
+            TorFlag::ControlPort(port) => ListenerAddr::from_tor_address(&TorAddress::Port(*port)),
+            TorFlag::ControlPortAddress(address, _) => ListenerAddr::from_tor_address(address),
+            #[cfg(target_family = "unix")]
+            TorFlag::ControlSocket(path) => Some(ListenerAddr::Unix(path.into())),
+            _ => None,
+        })
+    }
+
+    /// Return the path configured with `ControlPortWriteToFile`
+    pub fn control_port_file(&self) -> Option<PathBuf> {
+        self.flags.iter().find_map(|flag| match flag {
+            TorFlag::ControlPortWriteToFile(path) => Some(path.into()),
+            _ => None,
+        })
+    }
+}
+
+/// Parsed `PROTOCOLINFO` reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    pub protocol_version: u32,
+    pub auth_methods: Vec<AuthMethod>,
+    pub cookie_file: Option<PathBuf>,
+    pub tor_version: Option<String>,
+}
+
+impl ProtocolInfo {
+    /// Parse a `PROTOCOLINFO` reply
+    pub fn from_reply(reply: &Reply) -> Result<ProtocolInfo, Error> {
+        let mut info = ProtocolInfo {
+            protocol_version: 0,
+            auth_methods: vec![],
+            cookie_file: None,
+            tor_version: None,
+        };
+
+        for line in &reply.lines {
+            let args = parse_arguments(&line.text)?;
+            match args.positional.first().map(String::as_str) {
+                Some("PROTOCOLINFO") => {
+                    info.protocol_version = args
+                        .positional
+                        .get(1)
+                        .and_then(|v| v.parse().ok())
+                        .ok_or_else(|| {
+                            Error::Protocol(format!("Invalid PROTOCOLINFO line: {}", line.text))
+                        })?;
+                }
+                Some("AUTH") => {
+                    info.auth_methods = args
+                        .get("METHODS")
+                        .unwrap_or_default()
+                        .split(',')
+                        .filter(|m| !m.is_empty())
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()?;
+                    info.cookie_file = args.get("COOKIEFILE").map(PathBuf::from);
+                }
+                Some("VERSION") => info.tor_version = args.get("Tor").map(String::from),
+                _ => {}
+            }
+        }
+
+        Ok(info)
+    }
+}
+
+/// Signals that can be sent with the `SIGNAL` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Reload,
+    Shutdown,
+    Dump,
+    Debug,
+    Halt,
+    ClearDnsCache,
+    NewNym,
+    Heartbeat,
+    Dormant,
+    Active,
+}
+
+from_str_like_debug!(
+    Signal,
+    [
+        Reload,
+        Shutdown,
+        Dump,
+        Debug,
+        Halt,
+        ClearDnsCache,
+        NewNym,
+        Heartbeat,
+        Dormant,
+        Active
+    ]
+);
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Signal::ClearDnsCache => "CLEARDNSCACHE".to_string(),
+            other => format!("{:?}", other).to_uppercase(),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Reply to an `ADD_ONION` command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddedOnion {
+    /// Onion address of the service, without the `.onion` suffix
+    pub service_id: String,
+    /// Private key of the service, in the `KeyType:KeyBlob` format. Only returned for
+    /// services created with a new key and without the `DiscardPK` flag
+    pub private_key: Option<String>,
+}
+
+impl AddedOnion {
+    /// Parse an `ADD_ONION` reply
+    pub fn from_reply(reply: &Reply) -> Result<AddedOnion, Error> {
+        let mut service_id = None;
+        let mut private_key = None;
+        for line in &reply.lines {
+            if let Some(id) = line.text.strip_prefix("ServiceID=") {
+                service_id = Some(id.to_string());
+            } else if let Some(key) = line.text.strip_prefix("PrivateKey=") {
+                private_key = Some(key.to_string());
+            }
+        }
+
+        Ok(AddedOnion {
+            service_id: service_id
+                .ok_or_else(|| Error::Protocol("Missing ServiceID in ADD_ONION reply".into()))?,
+            private_key,
+        })
+    }
+}
+
+/// Client authorization credentials, as listed by `ONION_CLIENT_AUTH_VIEW`
+#[derive(Debug, Clone)]
+pub struct ClientAuthCredential {
+    /// Address of the service
+    pub address: OnionAddressV3,
+    /// Secret key used to decrypt the descriptor of the service
+    pub key: ClientAuthSecretKey,
+    /// Nickname of the credentials, if any
+    pub client_name: Option<String>,
+    /// Whether the credentials are stored in the `ClientOnionAuthDir`
+    pub permanent: bool,
+}
+
+impl ClientAuthCredential {
+    /// Parse an `ONION_CLIENT_AUTH_VIEW` reply
+    pub fn from_reply(reply: &Reply) -> Result<Vec<ClientAuthCredential>, Error> {
+        let invalid = |text: &str| Error::Protocol(format!("Invalid client credentials: {}", text));
+
+        let mut credentials = vec![];
+        for line in &reply.lines {
+            let rest = match line.text.strip_prefix("CLIENT ") {
+                Some(rest) => rest,
+                None => continue,
+            };
+
+            // The base64 key ends with `=`, split it before parsing the keyword arguments
+            let mut parts = rest.splitn(3, ' ');
+            let address = parts.next().unwrap_or_default();
+            let key = parts.next().unwrap_or_default();
+            let args = parse_arguments(parts.next().unwrap_or_default())?;
+
+            credentials.push(ClientAuthCredential {
+                address: address.parse().map_err(|_| invalid(&line.text))?,
+                key: ClientAuthSecretKey::from_control_key(key).map_err(|_| invalid(&line.text))?,
+                client_name: args.get("ClientName").map(str::to_string),
+                permanent: args
+                    .get("Flags")
+                    .is_some_and(|flags| flags.split(',').any(|f| f == "Permanent")),
+            });
+        }
+
+        Ok(credentials)
+    }
+}
+
+/// Check that `value` can be sent as a single argument, without spaces or line breaks that
+/// would inject other arguments or commands
+pub(crate) fn check_argument<'a>(kind: &str, value: &'a str) -> Result<&'a str, Error> {
+    if value.is_empty() || value.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(Error::InvalidValue {
+            kind: kind.into(),
+            value: value.into(),
+        });
+    }
+
+    Ok(value)
+}
+
+/// Check every argument with [`check_argument`], and join them with spaces
+pub(crate) fn join_arguments(kind: &str, values: &[&str]) -> Result<String, Error> {
+    values
+        .iter()
+        .map(|value| check_argument(kind, value))
+        .collect::<Result<Vec<_>, _>>()
+        .map(|values| values.join(" "))
+}
+
+pub(crate) fn onion_client_auth_add_command(
+    address: &OnionAddressV3,
+    key: &ClientAuthSecretKey,
+    client_name: Option<&str>,
+    permanent: bool,
+) -> Result<String, Error> {
+    let mut command = format!(
+        "ONION_CLIENT_AUTH_ADD {} {}",
+        address.service_id(),
+        key.to_control_key()
+    );
+    if let Some(client_name) = client_name {
+        command.push_str(&format!(
+            " ClientName={}",
+            check_argument("client name", client_name)?
+        ));
+    }
+    if permanent {
+        command.push_str(" Flags=Permanent");
+    }
+
+    Ok(command)
+}
+
+pub(crate) fn onion_client_auth_view_command(address: Option<&OnionAddressV3>) -> String {
+    match address {
+        Some(address) => format!("ONION_CLIENT_AUTH_VIEW {}", address.service_id()),
+        None => "ONION_CLIENT_AUTH_VIEW".to_string(),
+    }
+}
+
+pub(crate) fn setconf_command(flags: &[TorFlag]) -> String {
+    let args = flags
+        .iter()
+        .map(|flag| {
+            let mut parts = flag.expand().into_iter();
+            let keyword = parts.next().unwrap_or_default();
+            match parts.next() {
+                Some(value) => format!("{}={}", keyword, quote(&value)),
+                None => keyword,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    format!("SETCONF {}", args.join(" "))
+}
+
+/// Build an `ADD_ONION` command
+///
+/// `key` is either `NEW:<KeyType>` or `<KeyType>:<KeyBlob>`, and every port mapping is
+/// expanded to `Port=<VirtPort>[,<Target>]`.
+pub(crate) fn add_onion_command(
+    key: &str,
+    flags: &[&str],
+    max_streams: Option<u16>,
+    ports: &[(u16, Option<TorAddress>)],
+) -> Result<String, Error> {
+    let mut command = format!("ADD_ONION {}", check_argument("onion service key", key)?);
+    if !flags.is_empty() {
+        let flags = join_arguments("onion service flag", flags)?;
+        command.push_str(&format!(" Flags={}", flags.replace(' ', ",")));
+    }
+    if let Some(max_streams) = max_streams {
+        command.push_str(&format!(" MaxStreams={}", max_streams));
+    }
+    for (port, target) in ports {
+        command.push_str(&format!(" Port={}", port));
+        if let Some(target) = target {
+            command.push_str(&format!(",{}", target));
+        }
+    }
+
+    Ok(command)
+}
+
+/// TCP or Unix socket connected to one of Tor's listeners
+#[derive(Debug)]
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(target_family = "unix")]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub(crate) fn connect(addr: &ListenerAddr) -> io::Result<Stream> {
+        match addr {
+            ListenerAddr::Tcp(addr) => TcpStream::connect(addr).map(Stream::Tcp),
+            #[cfg(target_family = "unix")]
+            ListenerAddr::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(timeout),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.set_write_timeout(timeout),
+        }
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(s) => s.try_clone().map(Stream::Tcp),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.try_clone().map(Stream::Unix),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            #[cfg(target_family = "unix")]
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+type ReplySender = mpsc::Sender<Result<Reply, Error>>;
+
+/// Commands waiting for their reply, in the order they were sent
+#[derive(Debug, Default)]
+struct Pending {
+    senders: VecDeque<ReplySender>,
+    /// Set once the connection is unusable, returned to every following command
+    closed: Option<Error>,
+}
+
+impl Pending {
+    fn close(&mut self, error: Error) {
+        for sender in self.senders.drain(..) {
+            let _ = sender.send(Err(error.clone()));
+        }
+        self.closed.get_or_insert(error);
+    }
+}
+
+/// Read every reply from `stream`, dispatching command replies to the senders in `pending`
+/// and asynchronous events to `events`
+fn read_replies(stream: Stream, pending: Arc<Mutex<Pending>>, events: ReplySender) {
+    let mut reader = BufReader::new(stream);
+    let mut parser = ReplyParser::default();
+    let mut buf = Vec::new();
+
+    let error = loop {
+        buf.clear();
+        let result = match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buf);
+                let line = line.trim_end_matches(['\r', '\n']);
+                trace!("<- {}", line);
+                parser.feed(line)
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        match result {
+            Ok(None) => {}
+            // The events may not be consumed, in which case they are discarded
+            Ok(Some(reply)) if reply.is_async() => {
+                let _ = events.send(Ok(reply));
+            }
+            Ok(Some(reply)) => {
+                let sender = lock(&pending).senders.pop_front();
+                match sender {
+                    // The command may have timed out, in which case its reply is discarded
+                    Some(sender) => {
+                        let _ = sender.send(Ok(reply));
+                    }
+                    None => {
+                        break Error::Protocol(format!("Unexpected synchronous reply: {:?}", reply))
+                    }
+                }
+            }
+            Err(e) => break e,
+        }
+    };
+
+    lock(&pending).close(error.clone());
+    let _ = events.send(Err(error));
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn recv(
+    receiver: &mpsc::Receiver<Result<Reply, Error>>,
+    timeout: Option<Duration>,
+) -> Result<Reply, Error> {
+    let closed = || io::Error::from(io::ErrorKind::UnexpectedEof).into();
+    match timeout {
+        Some(timeout) => match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(Error::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(closed()),
+        },
+        None => receiver.recv().unwrap_or_else(|_| Err(closed())),
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    writer: Mutex<Stream>,
+    pending: Arc<Mutex<Pending>>,
+    events: Mutex<mpsc::Receiver<Result<Reply, Error>>>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Unblock the reader thread, which owns a clone of the socket
+        let _ = lock(&self.writer).shutdown();
+    }
+}
+
+/// Synchronous client for Tor's control port
+///
+/// The connection can be cloned cheaply and shared across threads: every clone talks over the
+/// same socket. A background thread reads from the socket, so that a thread waiting for
+/// events doesn't block the commands sent by the others.
+#[derive(Debug, Clone)]
+pub struct ControlConnection {
+    inner: Arc<Inner>,
+}
+
+impl ControlConnection {
+    /// Connect to a control port, without authenticating
+    pub fn connect(addr: &ListenerAddr) -> Result<ControlConnection, Error> {
+        debug!("Connecting to the control port at {}", addr);
+
+        let stream = Stream::connect(addr)?;
+        let reader = stream.try_clone()?;
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (events_tx, events_rx) = mpsc::channel();
+
+        let thread_pending = Arc::clone(&pending);
+        thread::Builder::new()
+            .name("libtor-control".into())
+            .spawn(move || read_replies(reader, thread_pending, events_tx))?;
+
+        Ok(ControlConnection {
+            inner: Arc::new(Inner {
+                writer: Mutex::new(stream),
+                pending,
+                events: Mutex::new(events_rx),
+                read_timeout: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// Connect to the control port configured in `tor` and authenticate
+    pub fn connect_config(
+        tor: &Tor,
+        authenticator: &Authenticator,
+    ) -> Result<ControlConnection, Error> {
+        let addr = tor.control_addr().ok_or(Error::NoControlPort)?;
+        let conn = ControlConnection::connect(&addr)?;
+        conn.authenticate(authenticator)?;
+
+        Ok(conn)
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        *lock(&self.inner.read_timeout)
+    }
+
+    /// Send a raw command and return its reply, whatever the status code is
+    pub fn send_command(&self, command: &str) -> Result<Reply, Error> {
+        let (sender, receiver) = mpsc::channel();
+        {
+            // Replies come in the order of the commands, so queue the sender while writing
+            let mut writer = lock(&self.inner.writer);
+            let mut pending = lock(&self.inner.pending);
+            if let Some(error) = &pending.closed {
+                return Err(error.clone());
+            }
+            pending.senders.push_back(sender);
+            drop(pending);
+
+            trace!("-> {}", command);
+            let written = writer
+                .write_all(command.as_bytes())
+                .and_then(|_| writer.write_all(b"\r\n"))
+                .and_then(|_| writer.flush());
+            if let Err(e) = written {
+                // The command may have been partially sent, nothing can be sent after it
+                let error = Error::from(e);
+                lock(&self.inner.pending).close(error.clone());
+                return Err(error);
+            }
+        }
+
+        recv(&receiver, self.read_timeout())
+    }
+
+    fn command(&self, command: &str) -> Result<Reply, Error> {
+        self.send_command(command)?.into_result()
+    }
+
+    /// Ask Tor which authentication methods it supports
+    pub fn protocol_info(&self) -> Result<ProtocolInfo, Error> {
+        ProtocolInfo::from_reply(&self.command("PROTOCOLINFO 1")?)
+    }
+
+    /// Authenticate with the control port, picking the strongest method supported by both
+    /// Tor and `authenticator`
+    pub fn authenticate(&self, authenticator: &Authenticator) -> Result<(), Error> {
+        let info = self.protocol_info()?;
+        debug!("Control port PROTOCOLINFO: {:?}", info);
+
+        let exchange = authenticator.start(&info)?;
+        let command = match exchange {
+            AuthExchange::Authenticate(command) => command,
+            AuthExchange::Challenge { .. } => {
+                exchange.respond(&self.command(&exchange.command())?)?
+            }
+        };
+
+        self.command(&command).map(|_| ())
+    }
+
+    /// Query a single `GETINFO` key
+    pub fn getinfo(&self, key: &str) -> Result<String, Error> {
+        self.getinfo_many(&[key])?
+            .remove(key)
+            .ok_or_else(|| Error::Protocol(format!("Missing GETINFO key: {}", key)))
+    }
+
+    /// Query multiple `GETINFO` keys at once
+    pub fn getinfo_many(&self, keys: &[&str]) -> Result<HashMap<String, String>, Error> {
+        self.send_command(&format!("GETINFO {}", join_arguments("GETINFO key", keys)?))?
+            .into_getinfo()
+    }
+
+    /// Change the value of configuration options
+    pub fn setconf(&self, flags: &[TorFlag]) -> Result<(), Error> {
+        self.command(&setconf_command(flags)).map(|_| ())
+    }
+
+    /// Reset configuration options to their default value
+    pub fn resetconf(&self, keywords: &[&str]) -> Result<(), Error> {
+        self.command(&format!(
+            "RESETCONF {}",
+            join_arguments("keyword", keywords)?
+        ))
+        .map(|_| ())
+    }
+
+    /// Send a signal to Tor
+    pub fn signal(&self, signal: Signal) -> Result<(), Error> {
+        self.command(&format!("SIGNAL {}", signal)).map(|_| ())
+    }
+
+    /// Create an ephemeral onion service
+    ///
+    /// `key` is either `NEW:<KeyType>` or `<KeyType>:<KeyBlob>`, `flags` are passed as-is in
+    /// the `Flags=` argument. See [`OnionServiceBuilder`] for a typed alternative.
+    pub fn add_onion(
+        &self,
+        key: &str,
+        flags: &[&str],
+        ports: &[(u16, Option<TorAddress>)],
+    ) -> Result<AddedOnion, Error> {
+        let reply = self.command(&add_onion_command(key, flags, None, ports)?)?;
+        AddedOnion::from_reply(&reply)
+    }
+
+    /// Remove an ephemeral onion service
+    pub fn del_onion(&self, service_id: &str) -> Result<(), Error> {
+        self.command(&format!(
+            "DEL_ONION {}",
+            check_argument("service ID", service_id)?
+        ))
+        .map(|_| ())
+    }
+
+    /// Register the credentials to access a v3 onion service with client authorization
+    ///
+    /// With `permanent`, Tor also stores them in its `ClientOnionAuthDir`, which must be set.
+    pub fn onion_client_auth_add(
+        &self,
+        address: &OnionAddressV3,
+        key: &ClientAuthSecretKey,
+        client_name: Option<&str>,
+        permanent: bool,
+    ) -> Result<(), Error> {
+        self.command(&onion_client_auth_add_command(
+            address,
+            key,
+            client_name,
+            permanent,
+        )?)
+        .map(|_| ())
+    }
+
+    /// Remove the credentials registered for an onion service
+    pub fn onion_client_auth_remove(&self, address: &OnionAddressV3) -> Result<(), Error> {
+        self.command(&format!(
+            "ONION_CLIENT_AUTH_REMOVE {}",
+            address.service_id()
+        ))
+        .map(|_| ())
+    }
+
+    /// List the registered credentials, for all the services or only for `address`
+    pub fn onion_client_auth_view(
+        &self,
+        address: Option<&OnionAddressV3>,
+    ) -> Result<Vec<ClientAuthCredential>, Error> {
+        ClientAuthCredential::from_reply(&self.command(&onion_client_auth_view_command(address))?)
+    }
+
+    /// Subscribe to the given asynchronous events, replacing the previous subscriptions
+    pub fn set_events(&self, events: &[&str]) -> Result<(), Error> {
+        self.command(&format!("SETEVENTS {}", join_arguments("event", events)?))
+            .map(|_| ())
+    }
+
+    /// Return the next asynchronous event, blocking until one is received
+    ///
+    /// Events are queued as soon as they are received, and returned in order. Fails with
+    /// [`Error::Timeout`] if a read timeout is set and expires.
+    ///
+    /// When multiple threads wait for events, each event is returned to only one of them.
+    pub fn next_event(&self) -> Result<Reply, Error> {
+        recv(&lock(&self.inner.events), self.read_timeout())
+    }
+
+    /// Like [`ControlConnection::next_event`], but wait at most `timeout` instead of the read
+    /// timeout of the connection
+    pub(crate) fn next_event_timeout(&self, timeout: Duration) -> Result<Reply, Error> {
+        recv(&lock(&self.inner.events), Some(timeout))
+    }
+
+    /// Subscribe to `events` and call `callback` with every event received, until the
+    /// connection is closed by Tor
+    pub(crate) fn for_each_event<F>(&self, events: &[&str], mut callback: F) -> Result<(), Error>
+    where
+        F: FnMut(&Reply),
+    {
+        self.set_events(events)?;
+
+        loop {
+            match self.next_event() {
+                Ok(event) => callback(&event),
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Set the timeout for reads from the control port, `None` blocks indefinitely
+    ///
+    /// The timeout applies to both events and command replies, for every clone of the
+    /// connection: a command whose reply takes too long fails with [`Error::Timeout`], and its
+    /// reply is discarded when it's received.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) {
+        *lock(&self.inner.read_timeout) = timeout;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reply::fixtures::*;
+    use super::testing::FakeControlServer;
+    use super::*;
+
+    #[test]
+    fn test_control_addr() {
+        assert_eq!(Tor::new().control_addr(), None);
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::ControlPortAuto)
+                .flag(TorFlag::ControlPort(9051))
+                .control_addr(),
+            Some(ListenerAddr::Tcp("127.0.0.1:9051".parse().unwrap()))
+        );
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::ControlPortAddress(
+                    TorAddress::AddressPort("127.0.0.2".into(), 9000),
+                    None.into()
+                ))
+                .control_addr(),
+            Some(ListenerAddr::Tcp("127.0.0.2:9000".parse().unwrap()))
+        );
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::ControlPortAddress(
+                    "[::1]:9000".parse().unwrap(),
+                    None.into()
+                ))
+                .control_addr(),
+            Some(ListenerAddr::Tcp("[::1]:9000".parse().unwrap()))
+        );
+        #[cfg(target_family = "unix")]
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::ControlSocket("/tmp/control".into()))
+                .control_addr(),
+            Some(ListenerAddr::Unix("/tmp/control".into()))
+        );
+    }
+
+    #[test]
+    fn test_port_file() {
+        assert_eq!(
+            ListenerAddr::from_port_file("PORT=127.0.0.1:36421\n").unwrap(),
+            vec![ListenerAddr::Tcp("127.0.0.1:36421".parse().unwrap())]
+        );
+        #[cfg(target_family = "unix")]
+        assert_eq!(
+            ListenerAddr::from_port_file("PORT=[::1]:9051\nUNIX_PORT=/tmp/control\n").unwrap(),
+            vec![
+                ListenerAddr::Tcp("[::1]:9051".parse().unwrap()),
+                ListenerAddr::Unix("/tmp/control".into()),
+            ]
+        );
+        assert!(ListenerAddr::from_port_file("").is_err());
+        assert!(ListenerAddr::from_port_file("PORT=localhost\n").is_err());
+
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::ControlPortWriteToFile("/tmp/control-port".into()))
+                .control_port_file(),
+            Some(PathBuf::from("/tmp/control-port"))
+        );
+    }
+
+    #[test]
+    fn test_signal_display() {
+        assert_eq!(Signal::NewNym.to_string(), "NEWNYM");
+        assert_eq!(Signal::ClearDnsCache.to_string(), "CLEARDNSCACHE");
+    }
+
+    #[test]
+    fn test_authenticate_null() {
+        let server = FakeControlServer::new(vec![
+            ("PROTOCOLINFO 1", PROTOCOLINFO_NULL),
+            ("AUTHENTICATE", OK),
+        ]);
+        let tor = Tor::new()
+            .flag(TorFlag::ControlPort(server.addr().port()))
+            .clone();
+        let conn = ControlConnection::connect_config(&tor, &Authenticator::new()).unwrap();
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_authenticate_password() {
+        let server = FakeControlServer::new(vec![
+            ("PROTOCOLINFO 1", PROTOCOLINFO_PASSWORD),
+            ("AUTHENTICATE \"wrong\"", BAD_AUTH),
+            ("PROTOCOLINFO 1", PROTOCOLINFO_PASSWORD),
+            ("AUTHENTICATE \"secret\"", OK),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        match conn.authenticate(Authenticator::new().password("wrong")) {
+            Err(Error::Control { code: 515, .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        conn.authenticate(Authenticator::new().password("secret"))
+            .unwrap();
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_authenticate_no_method() {
+        let server = FakeControlServer::new(vec![("PROTOCOLINFO 1", PROTOCOLINFO_PASSWORD)]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        match conn.authenticate(&Authenticator::new()) {
+            Err(Error::Authentication(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_command_while_waiting_for_events() {
+        let server = FakeControlServer::new(vec![
+            ("SETEVENTS STATUS_CLIENT", OK),
+            (
+                "SIGNAL NEWNYM",
+                "250 OK\r\n650 STATUS_CLIENT NOTICE CIRCUIT_ESTABLISHED\r\n",
+            ),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        conn.set_events(&["STATUS_CLIENT"]).unwrap();
+
+        let waiter = {
+            let conn = conn.clone();
+            thread::spawn(move || conn.next_event())
+        };
+        // Let the other thread block on the event first
+        thread::sleep(Duration::from_millis(50));
+        conn.signal(Signal::NewNym).unwrap();
+
+        let event = waiter.join().unwrap().unwrap();
+        assert_eq!(event.code(), 650);
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let server = FakeControlServer::new(vec![("SIGNAL NEWNYM", "")]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        conn.set_read_timeout(Some(Duration::from_millis(50)));
+
+        assert!(matches!(conn.signal(Signal::NewNym), Err(Error::Timeout)));
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_commands() {
+        let server = FakeControlServer::new(vec![
+            ("GETINFO version config-text", GETINFO_MULTI),
+            (
+                "SETCONF SocksPort=\"9050\" DisableNetwork=\"1\"",
+                "650 NOTICE something\r\n250 OK\r\n",
+            ),
+            ("RESETCONF SocksPort", OK),
+            ("SIGNAL NEWNYM", OK),
+            (
+                "ADD_ONION NEW:ED25519-V3 Port=80",
+                "250-ServiceID=abcdef\r\n250-PrivateKey=ED25519-V3:a2V5\r\n250 OK\r\n",
+            ),
+            ("DEL_ONION abcdef", OK),
+            ("FOO", UNRECOGNIZED),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        let info = conn.getinfo_many(&["version", "config-text"]).unwrap();
+        assert_eq!(info["version"], "0.4.7.13");
+        conn.setconf(&[
+            TorFlag::SocksPort(9050),
+            TorFlag::DisableNetwork(true.into()),
+        ])
+        .unwrap();
+        conn.resetconf(&["SocksPort"]).unwrap();
+        conn.signal(Signal::NewNym).unwrap();
+        let onion = conn
+            .add_onion("NEW:ED25519-V3", &[], &[(80, None)])
+            .unwrap();
+        assert_eq!(onion.service_id, "abcdef");
+        conn.del_onion(&onion.service_id).unwrap();
+        assert_eq!(conn.send_command("FOO").unwrap().code(), 510);
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_argument_injection() {
+        assert_eq!(check_argument("keyword", "SocksPort").unwrap(), "SocksPort");
+        for value in &["", "SocksPort\r\nSIGNAL HALT", "a b", "a\nb", "a\0b"] {
+            assert!(check_argument("keyword", value).is_err(), "{:?}", value);
+        }
+
+        let server = FakeControlServer::new(vec![]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+        assert!(conn.resetconf(&["SocksPort", "x\r\nSIGNAL HALT"]).is_err());
+        assert!(conn.del_onion("abcdef\r\nSIGNAL HALT").is_err());
+        assert!(conn.getinfo("version config-text").is_err());
+        assert!(conn.set_events(&["CIRC\r\n"]).is_err());
+        drop(conn);
+        server.join();
+
+        let address: OnionAddressV3 = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid"
+            .parse()
+            .unwrap();
+        let key = ClientAuthSecretKey::generate();
+        assert!(onion_client_auth_add_command(&address, &key, Some("alice"), false).is_ok());
+        assert!(onion_client_auth_add_command(
+            &address,
+            &key,
+            Some("alice Flags=Permanent"),
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_onion_client_auth() {
+        const ADDRESS: &str = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid";
+        const KEY: &str = "x25519:dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo=";
+
+        let add = format!(
+            "ONION_CLIENT_AUTH_ADD {} {} ClientName=alice Flags=Permanent",
+            ADDRESS, KEY
+        );
+        let remove = format!("ONION_CLIENT_AUTH_REMOVE {}", ADDRESS);
+        let view_reply = format!(
+            "250-ONION_CLIENT_AUTH_VIEW\r\n\
+             250-CLIENT {} {} Flags=Permanent ClientName=alice\r\n\
+             250 OK\r\n",
+            ADDRESS, KEY
+        );
+        let server = FakeControlServer::with_script(vec![
+            (add, "252 Registered client and decrypted desc\r\n".into()),
+            ("ONION_CLIENT_AUTH_VIEW".into(), view_reply),
+            (remove, "251 No credentials for \"abc\"\r\n".into()),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        let address = ADDRESS.parse().unwrap();
+        let key = ClientAuthSecretKey::from_control_key(KEY).unwrap();
+        conn.onion_client_auth_add(&address, &key, Some("alice"), true)
+            .unwrap();
+        let credentials = conn.onion_client_auth_view(None).unwrap();
+        assert_eq!(credentials.len(), 1);
+        assert_eq!(credentials[0].address, address);
+        assert_eq!(credentials[0].key.as_bytes(), key.as_bytes());
+        assert_eq!(credentials[0].client_name.as_deref(), Some("alice"));
+        assert!(credentials[0].permanent);
+        conn.onion_client_auth_remove(&address).unwrap();
+
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_protocol_info() {
+        let reply = parse_all(
+            "250-PROTOCOLINFO 1\r\n\
+             250-AUTH METHODS=COOKIE,SAFECOOKIE,HASHEDPASSWORD COOKIEFILE=\"/var/run/tor/control.authcookie\"\r\n\
+             250-VERSION Tor=\"0.4.7.13\"\r\n\
+             250 OK\r\n",
+        )
+        .remove(0);
+        let info = ProtocolInfo::from_reply(&reply).unwrap();
+        assert_eq!(
+            info,
+            ProtocolInfo {
+                protocol_version: 1,
+                auth_methods: vec![
+                    AuthMethod::Cookie,
+                    AuthMethod::SafeCookie,
+                    AuthMethod::HashedPassword
+                ],
+                cookie_file: Some("/var/run/tor/control.authcookie".into()),
+                tor_version: Some("0.4.7.13".into()),
+            }
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use log_crate::{debug, warn};
+
+#[cfg(feature = "tokio")]
+use super::AsyncControlConnection;
+use super::{add_onion_command, AddedOnion, ControlConnection, Reply};
+use crate::hs::{HiddenServiceSecretKey, OnionAddressV3};
+use crate::{Error, TorAddress};
+
+/// Flags of an onion service created with `ADD_ONION`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnionFlag {
+    /// Keep the service running after the control connection is closed
+    Detach,
+    /// Don't return the private key of a newly generated service
+    DiscardPK,
+    /// Close the circuit when `MaxStreams` is exceeded, instead of the stream
+    MaxStreamsCloseCircuit,
+}
+
+display_like_debug!(OnionFlag);
+
+/// Builder for an ephemeral onion service, created at runtime through the control port
+///
+/// Unlike services configured with `HiddenServiceDir`, nothing is written to disk: the private
+/// key is returned by Tor when the service is created, and can be passed back with
+/// [`OnionServiceBuilder::private_key`] to publish the same address again.
+#[derive(Debug, Clone, Default)]
+pub struct OnionServiceBuilder {
+    private_key: Option<HiddenServiceSecretKey>,
+    flags: Vec<OnionFlag>,
+    max_streams: Option<u16>,
+    ports: Vec<(u16, Option<TorAddress>)>,
+}
+
+impl OnionServiceBuilder {
+    /// Create a new v3 service with a newly generated key
+    pub fn new() -> OnionServiceBuilder {
+        Default::default()
+    }
+
+    /// Use an existing key, e.g. the one returned by [`OnionService::private_key`] or read with
+    /// [`HiddenServiceSecretKey::read_from_dir`]
+    pub fn private_key(&mut self, key: &HiddenServiceSecretKey) -> &mut OnionServiceBuilder {
+        self.private_key = Some(key.clone());
+        self
+    }
+
+    /// Add a flag
+    pub fn flag(&mut self, flag: OnionFlag) -> &mut OnionServiceBuilder {
+        if !self.flags.contains(&flag) {
+            self.flags.push(flag);
+        }
+        self
+    }
+
+    /// Limit the number of concurrent streams per rendezvous circuit
+    pub fn max_streams(&mut self, max_streams: u16) -> &mut OnionServiceBuilder {
+        self.max_streams = Some(max_streams);
+        self
+    }
+
+    /// Forward connections to the virtual port `port` to `target`, or to the same port on
+    /// localhost if `None`
+    pub fn port(&mut self, port: u16, target: Option<TorAddress>) -> &mut OnionServiceBuilder {
+        self.ports.push((port, target));
+        self
+    }
+
+    fn is_detached(&self) -> bool {
+        self.flags.contains(&OnionFlag::Detach)
+    }
+
+    pub(crate) fn command(&self) -> Result<String, Error> {
+        if self.ports.is_empty() {
+            return Err(Error::InvalidValue {
+                kind: "OnionServiceBuilder".into(),
+                value: "at least one port is required".into(),
+            });
+        }
+
+        let key = self
+            .private_key
+            .as_ref()
+            .map(HiddenServiceSecretKey::to_control_key)
+            .unwrap_or_else(|| "NEW:ED25519-V3".into());
+        let flags = self.flags.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        let flags = flags.iter().map(String::as_str).collect::<Vec<_>>();
+
+        add_onion_command(&key, &flags, self.max_streams, &self.ports)
+    }
+
+    /// Parse the reply to `ADD_ONION`, returning the address and the key of the service
+    fn parse_reply(
+        &self,
+        reply: &Reply,
+    ) -> Result<(AddedOnion, OnionAddressV3, Option<HiddenServiceSecretKey>), Error> {
+        let added = AddedOnion::from_reply(reply)?;
+        debug!("Created onion service {}", added.service_id);
+
+        let address = added.service_id.parse()?;
+        let private_key = match &added.private_key {
+            Some(key) => Some(HiddenServiceSecretKey::from_control_key(key)?),
+            None => self.private_key.clone(),
+        };
+
+        Ok((added, address, private_key))
+    }
+
+    /// Create the service
+    ///
+    /// The returned [`OnionService`] keeps the connection open, and removes the service when
+    /// dropped unless it was created with [`OnionFlag::Detach`].
+    pub fn create(&self, conn: &ControlConnection) -> Result<OnionService, Error> {
+        let reply = conn.command(&self.command()?)?;
+        let (added, address, private_key) = self.parse_reply(&reply)?;
+
+        Ok(OnionService {
+            conn: conn.clone(),
+            added,
+            address,
+            private_key,
+            detached: self.is_detached(),
+        })
+    }
+
+    /// Create the service with an async connection
+    ///
+    /// See [`AsyncOnionService`] for how the service is removed.
+    #[cfg(feature = "tokio")]
+    pub async fn create_async<'a>(
+        &self,
+        conn: &'a AsyncControlConnection,
+    ) -> Result<AsyncOnionService<'a>, Error> {
+        let reply = conn.send_command(&self.command()?).await?.into_result()?;
+        let (added, address, private_key) = self.parse_reply(&reply)?;
+
+        Ok(AsyncOnionService {
+            conn,
+            added,
+            address,
+            private_key,
+            detached: self.is_detached(),
+        })
+    }
+}
+
+/// Ephemeral onion service created with [`OnionServiceBuilder::create`]
+#[derive(Debug)]
+pub struct OnionService {
+    conn: ControlConnection,
+    added: AddedOnion,
+    address: OnionAddressV3,
+    private_key: Option<HiddenServiceSecretKey>,
+    detached: bool,
+}
+
+impl OnionService {
+    /// Onion address of the service, without the `.onion` suffix
+    pub fn service_id(&self) -> &str {
+        &self.added.service_id
+    }
+
+    /// Onion address of the service
+    pub fn onion_address(&self) -> OnionAddressV3 {
+        self.address
+    }
+
+    /// Private key of the service, unless it was newly generated and discarded with
+    /// [`OnionFlag::DiscardPK`]
+    pub fn private_key(&self) -> Option<&HiddenServiceSecretKey> {
+        self.private_key.as_ref()
+    }
+
+    /// Whether the service keeps running after being dropped
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Remove the service, returning an error if Tor refuses to
+    pub fn remove(mut self) -> Result<(), Error> {
+        // Removed explicitly, nothing left to do on drop
+        self.detached = true;
+        self.conn.del_onion(&self.added.service_id)
+    }
+}
+
+/// Ephemeral onion service created with [`OnionServiceBuilder::create_async`]
+///
+/// The service can't be removed on drop, since that requires waiting for Tor: unless it was
+/// created with [`OnionFlag::Detach`], Tor removes it when the connection is closed, or it can
+/// be removed explicitly with [`AsyncOnionService::remove`].
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct AsyncOnionService<'a> {
+    conn: &'a AsyncControlConnection,
+    added: AddedOnion,
+    address: OnionAddressV3,
+    private_key: Option<HiddenServiceSecretKey>,
+    detached: bool,
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncOnionService<'_> {
+    /// Onion address of the service, without the `.onion` suffix
+    pub fn service_id(&self) -> &str {
+        &self.added.service_id
+    }
+
+    /// Onion address of the service
+    pub fn onion_address(&self) -> OnionAddressV3 {
+        self.address
+    }
+
+    /// Private key of the service, unless it was newly generated and discarded with
+    /// [`OnionFlag::DiscardPK`]
+    pub fn private_key(&self) -> Option<&HiddenServiceSecretKey> {
+        self.private_key.as_ref()
+    }
+
+    /// Whether the service keeps running after the connection is closed
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Remove the service, returning an error if Tor refuses to
+    pub async fn remove(self) -> Result<(), Error> {
+        self.conn.del_onion(&self.added.service_id).await
+    }
+}
+
+impl Drop for OnionService {
+    fn drop(&mut self) {
+        if self.detached {
+            return;
+        }
+
+        debug!("Removing onion service {}", self.added.service_id);
+        if let Err(e) = self.conn.del_onion(&self.added.service_id) {
+            warn!(
+                "Failed to remove onion service {}: {}",
+                self.added.service_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::reply::fixtures::OK;
+    use super::super::testing::FakeControlServer;
+    use super::super::ListenerAddr;
+    use super::*;
+
+    fn add_onion_reply(key: &HiddenServiceSecretKey) -> String {
+        format!(
+            "250-ServiceID={}\r\n250-PrivateKey={}\r\n250 OK\r\n",
+            key.onion_address().service_id(),
+            key.to_control_key()
+        )
+    }
+
+    #[test]
+    fn test_create_and_drop() {
+        let key = HiddenServiceSecretKey::generate();
+        let address = key.onion_address();
+        let server = FakeControlServer::with_script(vec![
+            (
+                "ADD_ONION NEW:ED25519-V3 Flags=MaxStreamsCloseCircuit MaxStreams=10 Port=80,127.0.0.1:8080 Port=443".into(),
+                add_onion_reply(&key),
+            ),
+            (format!("DEL_ONION {}", address.service_id()), OK.into()),
+        ]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        let service = OnionServiceBuilder::new()
+            .flag(OnionFlag::MaxStreamsCloseCircuit)
+            .max_streams(10)
+            .port(80, Some(TorAddress::AddressPort("127.0.0.1".into(), 8080)))
+            .port(443, None)
+            .create(&conn)
+            .unwrap();
+        drop(conn);
+
+        assert_eq!(service.onion_address(), address);
+        assert_eq!(
+            service.private_key().map(|k| k.to_control_key()),
+            Some(key.to_control_key())
+        );
+        drop(service);
+        server.join();
+    }
+
+    #[test]
+    fn test_detached() {
+        let key = HiddenServiceSecretKey::generate();
+        let server = FakeControlServer::with_script(vec![(
+            format!(
+                "ADD_ONION {} Flags=Detach,DiscardPK Port=80",
+                key.to_control_key()
+            ),
+            format!(
+                "250-ServiceID={}\r\n250 OK\r\n",
+                key.onion_address().service_id()
+            ),
+        )]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        let service = OnionServiceBuilder::new()
+            .private_key(&key)
+            .flag(OnionFlag::Detach)
+            .flag(OnionFlag::DiscardPK)
+            .port(80, None)
+            .create(&conn)
+            .unwrap();
+        assert!(service.is_detached());
+        assert_eq!(service.onion_address(), key.onion_address());
+        assert!(service.private_key().is_some());
+
+        drop((conn, service));
+        server.join();
+    }
+
+    #[test]
+    fn test_invalid_reply() {
+        let server = FakeControlServer::new(vec![(
+            "ADD_ONION NEW:ED25519-V3 Port=80",
+            "250-ServiceID=abcdef\r\n250 OK\r\n",
+        )]);
+        let conn = ControlConnection::connect(&ListenerAddr::Tcp(server.addr())).unwrap();
+
+        match OnionServiceBuilder::new().port(80, None).create(&conn) {
+            Err(Error::InvalidValue { .. }) => {}
+            other => panic!("unexpected result: {:?}", other.map(|s| s.onion_address())),
+        }
+
+        drop(conn);
+        server.join();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_create_async() {
+        let key = HiddenServiceSecretKey::generate();
+        let address = key.onion_address();
+        let server = FakeControlServer::with_script(vec![
+            (
+                "ADD_ONION NEW:ED25519-V3 Port=80".into(),
+                add_onion_reply(&key),
+            ),
+            (format!("DEL_ONION {}", address.service_id()), OK.into()),
+        ]);
+        let conn = AsyncControlConnection::connect(&ListenerAddr::Tcp(server.addr()))
+            .await
+            .unwrap();
+
+        let service = OnionServiceBuilder::new()
+            .port(80, None)
+            .create_async(&conn)
+            .await
+            .unwrap();
+        assert_eq!(service.onion_address(), address);
+        assert_eq!(
+            service.private_key().map(|k| k.to_control_key()),
+            Some(key.to_control_key())
+        );
+        assert!(!service.is_detached());
+        service.remove().await.unwrap();
+
+        drop(conn);
+        server.join();
+    }
+
+    #[test]
+    fn test_missing_port() {
+        match OnionServiceBuilder::new().command() {
+            Err(Error::InvalidValue { .. }) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use crate::Error;
+
+/// A single line of a control port reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyLine {
+    /// Three-digit status code
+    pub code: u16,
+    /// Text following the status code and separator
+    pub text: String,
+    /// Data block attached to the line, only present for `+` lines
+    pub data: Option<String>,
+}
+
+/// A complete (possibly multi-line) control port reply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    pub lines: Vec<ReplyLine>,
+}
+
+impl Reply {
+    /// Status code of the reply, taken from its final line
+    pub fn code(&self) -> u16 {
+        self.lines.last().map(|l| l.code).unwrap_or_default()
+    }
+
+    /// Whether this is a `2xx` reply
+    pub fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    /// Whether this is an asynchronous event notification (`650`)
+    pub fn is_async(&self) -> bool {
+        self.code() == 650
+    }
+
+    /// Text of the final line of the reply
+    pub fn message(&self) -> &str {
+        self.lines.last().map(|l| l.text.as_str()).unwrap_or("")
+    }
+
+    /// Turn a non-`2xx` reply into an [`Error::Control`]
+    pub fn into_result(self) -> Result<Reply, Error> {
+        if self.is_ok() {
+            Ok(self)
+        } else {
+            Err(Error::Control {
+                code: self.code(),
+                message: self.message().to_string(),
+            })
+        }
+    }
+
+    /// Interpret the reply as the answer to a `GETINFO`, returning the `key=value` pairs
+    pub fn into_getinfo(self) -> Result<HashMap<String, String>, Error> {
+        let reply = self.into_result()?;
+        let mut values = HashMap::new();
+        for line in reply.lines {
+            let (key, value) = match line.text.find('=') {
+                Some(pos) => match line.data {
+                    Some(data) => (line.text[..pos].to_string(), data),
+                    None => (
+                        line.text[..pos].to_string(),
+                        line.text[pos + 1..].to_string(),
+                    ),
+                },
+                None if line.text == "OK" => continue,
+                None => {
+                    return Err(Error::Protocol(format!(
+                        "Invalid GETINFO line: {}",
+                        line.text
+                    )))
+                }
+            };
+            values.insert(key, value);
+        }
+
+        Ok(values)
+    }
+}
+
+/// Incremental parser that turns lines read from the control port into [`Reply`]s
+///
+/// The parser is independent from the underlying I/O, so that both the blocking and the async
+/// clients can share it.
+#[derive(Debug, Default)]
+pub(crate) struct ReplyParser {
+    lines: Vec<ReplyLine>,
+    data: Option<(ReplyLine, String)>,
+}
+
+impl ReplyParser {
+    /// Feed a single line, stripped of its trailing CRLF
+    ///
+    /// Returns `Some(reply)` once the final line of a reply has been consumed
+    pub fn feed(&mut self, line: &str) -> Result<Option<Reply>, Error> {
+        if let Some((reply_line, mut data)) = self.data.take() {
+            if line == "." {
+                self.lines.push(ReplyLine {
+                    data: Some(data),
+                    ..reply_line
+                });
+            } else {
+                // Lines starting with a dot are escaped by adding an extra one
+                let line = line.strip_prefix('.').unwrap_or(line);
+                data.push_str(line);
+                data.push('\n');
+                self.data = Some((reply_line, data));
+            }
+
+            return Ok(None);
+        }
+
+        if line.len() < 4 || !line.is_char_boundary(3) || !line.is_char_boundary(4) {
+            return Err(Error::Protocol(format!("Reply line too short: {:?}", line)));
+        }
+        let code = line[..3]
+            .parse::<u16>()
+            .map_err(|_| Error::Protocol(format!("Invalid status code: {:?}", line)))?;
+        let reply_line = ReplyLine {
+            code,
+            text: line[4..].to_string(),
+            data: None,
+        };
+
+        match &line[3..4] {
+            "-" => self.lines.push(reply_line),
+            "+" => self.data = Some((reply_line, String::new())),
+            " " => {
+                self.lines.push(reply_line);
+                return Ok(Some(Reply {
+                    lines: std::mem::take(&mut self.lines),
+                }));
+            }
+            _ => return Err(Error::Protocol(format!("Invalid separator: {:?}", line))),
+        }
+
+        Ok(None)
+    }
+}
+
+/// Positional and `KEY=VALUE` arguments parsed from a reply line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Arguments {
+    pub positional: Vec<String>,
+    pub keywords: Vec<(String, String)>,
+}
+
+impl Arguments {
+    /// Value of the first keyword argument named `key`
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.keywords
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Split a reply line into positional and keyword arguments, unquoting quoted values
+///
+/// Once the first keyword argument is found, every following argument is parsed as a keyword
+/// argument too, and the ones without a `=` are stored with an empty value.
+pub fn parse_arguments(line: &str) -> Result<Arguments, Error> {
+    let mut args = Arguments::default();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+
+        if chars.peek() == Some(&'"') {
+            args.positional.push(read_quoted(&mut chars)?);
+            continue;
+        }
+
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == ' ' || c == '=' {
+                break;
+            }
+            word.push(c);
+            chars.next();
+        }
+
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            let value = if chars.peek() == Some(&'"') {
+                read_quoted(&mut chars)?
+            } else {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                value
+            };
+            args.keywords.push((word, value));
+        } else if !args.keywords.is_empty() {
+            args.keywords.push((word, String::new()));
+        } else {
+            args.positional.push(word);
+        }
+    }
+
+    Ok(args)
+}
+
+pub(crate) fn read_quoted<I: Iterator<Item = char>>(
+    chars: &mut std::iter::Peekable<I>,
+) -> Result<String, Error> {
+    // Octal escapes encode raw bytes, which may form multi-byte UTF-8 sequences
+    let mut value = Vec::new();
+    let push = |value: &mut Vec<u8>, c: char| {
+        value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+    };
+    chars.next();
+
+    loop {
+        match chars.next() {
+            None => return Err(Error::Protocol("Unterminated quoted string".into())),
+            Some('"') => return Ok(String::from_utf8_lossy(&value).into_owned()),
+            Some('\\') => match chars.next() {
+                Some('n') => push(&mut value, '\n'),
+                Some('r') => push(&mut value, '\r'),
+                Some('t') => push(&mut value, '\t'),
+                Some('x') => {
+                    let digits = (chars.next(), chars.next());
+                    match (
+                        digits.0.and_then(|c| c.to_digit(16)),
+                        digits.1.and_then(|c| c.to_digit(16)),
+                    ) {
+                        (Some(high), Some(low)) => value.push((high * 16 + low) as u8),
+                        _ => {
+                            return Err(Error::Protocol(
+                                "Invalid hex escape in quoted string".into(),
+                            ))
+                        }
+                    }
+                }
+                Some(c @ '0'..='7') => {
+                    let mut code = c.to_digit(8).unwrap();
+                    for _ in 0..2 {
+                        match chars.peek().and_then(|c| c.to_digit(8)) {
+                            Some(d) => {
+                                code = code * 8 + d;
+                                chars.next();
+                            }
+                            None => break,
+                        }
+                    }
+                    value.push(code as u8);
+                }
+                Some(c) => push(&mut value, c),
+                None => return Err(Error::Protocol("Unterminated quoted string".into())),
+            },
+            Some(c) => push(&mut value, c),
+        }
+    }
+}
+
+/// Quote a string so that it can be sent as a `QuotedString` to the control port
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Recorded replies shared by the tests of every control port client
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{Reply, ReplyParser};
+
+    /// Parse every reply contained in a recorded transcript
+    pub fn parse_all(raw: &str) -> Vec<Reply> {
+        let mut parser = ReplyParser::default();
+        raw.split("\r\n")
+            .filter(|l| !l.is_empty())
+            .filter_map(|l| parser.feed(l).unwrap())
+            .collect()
+    }
+
+    pub const PROTOCOLINFO_NULL: &str = "250-PROTOCOLINFO 1\r\n\
+                                         250-AUTH METHODS=NULL\r\n\
+                                         250-VERSION Tor=\"0.4.7.13\"\r\n\
+                                         250 OK\r\n";
+    pub const PROTOCOLINFO_PASSWORD: &str = "250-PROTOCOLINFO 1\r\n\
+                                             250-AUTH METHODS=HASHEDPASSWORD\r\n\
+                                             250-VERSION Tor=\"0.4.7.13\"\r\n\
+                                             250 OK\r\n";
+    pub const GETINFO_MULTI: &str = "250-version=0.4.7.13\r\n\
+                                     250+config-text=\r\n\
+                                     SocksPort 9050\r\n\
+                                     ..dotted\r\n\
+                                     .\r\n\
+                                     250 OK\r\n";
+    pub const BAD_AUTH: &str =
+        "515 Authentication failed: Password did not match HashedControlPassword value from configuration\r\n";
+    pub const UNRECOGNIZED: &str = "510 Unrecognized command \"FOO\"\r\n";
+    pub const OK: &str = "250 OK\r\n";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+
+    #[test]
+    fn test_parse_getinfo() {
+        let replies = parse_all(GETINFO_MULTI);
+        assert_eq!(replies.len(), 1);
+
+        let values = replies[0].clone().into_getinfo().unwrap();
+        assert_eq!(values["version"], "0.4.7.13");
+        assert_eq!(values["config-text"], "SocksPort 9050\n.dotted\n");
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let reply = parse_all(UNRECOGNIZED).remove(0);
+        assert_eq!(reply.code(), 510);
+        match reply.into_result() {
+            Err(Error::Control { code: 510, message }) => {
+                assert_eq!(message, "Unrecognized command \"FOO\"")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_line() {
+        let mut parser = ReplyParser::default();
+        assert!(parser.feed("25").is_err());
+        assert!(parser.feed("abc OK").is_err());
+        assert!(parser.feed("250*OK").is_err());
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let args = parse_arguments(
+            "AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"/var/lib/tor/control \\\"auth\\\" cookie\"",
+        )
+        .unwrap();
+        assert_eq!(args.positional, vec!["AUTH"]);
+        assert_eq!(args.get("METHODS"), Some("COOKIE,SAFECOOKIE"));
+        assert_eq!(
+            args.get("COOKIEFILE"),
+            Some("/var/lib/tor/control \"auth\" cookie")
+        );
+
+        let args = parse_arguments("\"quoted\\101\" word KEY=1 FLAG").unwrap();
+        assert_eq!(args.positional, vec!["quotedA", "word"]);
+        assert_eq!(
+            args.keywords,
+            vec![("KEY".into(), "1".into()), ("FLAG".into(), "".into())]
+        );
+
+        assert!(parse_arguments("KEY=\"unterminated").is_err());
+
+        let args = parse_arguments("K=\"\\x41\\x62\"").unwrap();
+        assert_eq!(args.get("K"), Some("Ab"));
+        assert!(parse_arguments("K=\"\\x4\"").is_err());
+
+        // Escaped bytes are decoded as UTF-8
+        let args = parse_arguments("PATH=\"/tmp/caf\\303\\251 \\377\"").unwrap();
+        assert_eq!(args.get("PATH"), Some("/tmp/caf\u{e9} \u{fffd}"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a \"b\" \\c\n"), "\"a \\\"b\\\" \\\\c\\n\"");
+
+        let args = parse_arguments(&format!("K={}", quote("a \"b\" \\c\n"))).unwrap();
+        assert_eq!(args.get("K"), Some("a \"b\" \\c\n"));
+    }
+}
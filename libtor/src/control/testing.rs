@@ -0,0 +1,57 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread::{self, JoinHandle};
+
+/// Fake control port that accepts a single connection and replays a recorded transcript
+///
+/// Every entry of the script is a pair of the command expected from the client and the raw
+/// reply sent back.
+pub(crate) struct FakeControlServer {
+    addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl FakeControlServer {
+    pub fn new(script: Vec<(&'static str, &'static str)>) -> FakeControlServer {
+        let owned = script
+            .into_iter()
+            .map(|(cmd, reply)| (cmd.to_string(), reply.to_string()))
+            .collect();
+        FakeControlServer::with_script(owned)
+    }
+
+    pub fn with_script(script: Vec<(String, String)>) -> FakeControlServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+
+            for (expected, reply) in script {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line.trim_end(), expected);
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+
+            // Keep the connection open until the client closes it
+            let mut rest = String::new();
+            reader.read_line(&mut rest).unwrap();
+            assert_eq!(rest, "", "unexpected command after the end of the script");
+        });
+
+        FakeControlServer { addr, handle }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the whole script to be consumed and the connection to be closed, propagating
+    /// any assertion failure
+    pub fn join(self) {
+        self.handle.join().unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use tracing::{debug, error, info, warn};
+
+use super::events::{Event, HsDescAction, StatusKind};
+use super::{ControlConnection, Reply};
+use crate::log::LogLevel;
+use crate::Error;
+
+/// Events converted by [`trace_event`]
+pub const TRACED_EVENTS: &[&str] = &["CIRC", "STREAM", "STATUS_CLIENT", "HS_DESC"];
+
+/// Emit a `tracing` event at the level matching a Tor severity
+macro_rules! event_with_severity {
+    ($severity:expr, $($args:tt)*) => {
+        match $severity {
+            LogLevel::Err => error!($($args)*),
+            LogLevel::Warn => warn!($($args)*),
+            _ => info!($($args)*),
+        }
+    };
+}
+
+/// Join a list argument back into its comma separated form, `None` if empty
+fn join(list: &[String]) -> Option<String> {
+    Some(list.join(",")).filter(|joined| !joined.is_empty())
+}
+
+/// Emit a `CIRC`, `STREAM`, `STATUS_CLIENT` or `HS_DESC` event received from the control port
+/// as a structured `tracing` event
+///
+/// The events use the `tor::circ`, `tor::stream`, `tor::status` and `tor::hs_desc` targets,
+/// and have a field for each argument. Returns `false` for any other or malformed event.
+pub fn trace_event(event: &Reply) -> bool {
+    match Event::from_reply(event) {
+        Ok(Event::Circ(circ)) => debug!(
+            target: "tor::circ",
+            circuit_id = circ.id.as_str(),
+            status = %circ.status,
+            path = join(&circ.path).as_deref(),
+            build_flags = join(&circ.build_flags).as_deref(),
+            purpose = circ.purpose.as_deref(),
+            hs_state = circ.hs_state.as_deref(),
+            rend_query = circ.rend_query.as_deref(),
+            reason = circ.reason.as_deref(),
+            remote_reason = circ.remote_reason.as_deref(),
+            "circuit event"
+        ),
+        // `socks_password` is left out on purpose
+        Ok(Event::Stream(stream)) => debug!(
+            target: "tor::stream",
+            stream_id = stream.id.as_str(),
+            status = %stream.status,
+            circuit_id = stream.circuit_id.as_str(),
+            target = stream.target.as_str(),
+            reason = stream.reason.as_deref(),
+            remote_reason = stream.remote_reason.as_deref(),
+            source = stream.source.as_deref(),
+            source_addr = stream.source_addr.as_deref(),
+            purpose = stream.purpose.as_deref(),
+            socks_username = stream.socks_username.as_deref(),
+            "stream event"
+        ),
+        Ok(Event::Status(status)) if status.kind == StatusKind::Client => {
+            let args = &status.arguments;
+            event_with_severity!(
+                status.severity,
+                target: "tor::status",
+                severity = status.severity.event_name().as_str(),
+                action = status.action.as_str(),
+                progress = args.get("PROGRESS"),
+                tag = args.get("TAG"),
+                summary = args.get("SUMMARY"),
+                warning = args.get("WARNING"),
+                reason = args.get("REASON"),
+                address = args.get("ADDRESS"),
+                hostname = args.get("HOSTNAME"),
+                "client status event"
+            )
+        }
+        Ok(Event::HsDesc(hs_desc)) => event_with_severity!(
+            if hs_desc.action == HsDescAction::Failed {
+                LogLevel::Warn
+            } else {
+                LogLevel::Notice
+            },
+            target: "tor::hs_desc",
+            action = %hs_desc.action,
+            address = hs_desc.address.as_str(),
+            auth_type = hs_desc.auth_type.as_str(),
+            hs_dir = hs_desc.hs_dir.as_str(),
+            descriptor_id = hs_desc.descriptor_id.as_deref(),
+            reason = hs_desc.reason.as_deref(),
+            replica = hs_desc.replica.as_deref(),
+            hs_dir_index = hs_desc.hs_dir_index.as_deref(),
+            "hidden service descriptor event"
+        ),
+        _ => return false,
+    }
+
+    true
+}
+
+impl ControlConnection {
+    /// Subscribe to the [`TRACED_EVENTS`] and emit them as `tracing` events, see
+    /// [`trace_event`]
+    ///
+    /// This replaces the event subscriptions of the connection, and blocks until the connection
+    /// is closed by Tor.
+    pub fn trace_events(&self) -> Result<(), Error> {
+        self.for_each_event(TRACED_EVENTS, |event| {
+            trace_event(event);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use super::super::reply::fixtures::parse_all;
+    use super::*;
+
+    /// Records the target, level and fields of every event
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    struct FieldVisitor(String);
+
+    impl Visit for FieldVisitor {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push_str(&format!(" {}={}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let metadata = event.metadata();
+            let mut visitor = FieldVisitor(format!("{} {}", metadata.level(), metadata.target()));
+            event.record(&mut visitor);
+            self.0.lock().unwrap().push(visitor.0);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_trace_event() {
+        let events = parse_all(
+            "650 CIRC 12 BUILT $AAAA~relay1,$BBBB~relay2 BUILD_FLAGS=NEED_CAPACITY PURPOSE=GENERAL\r\n\
+             650 STREAM 34 SUCCEEDED 12 example.com:443 SOCKS_USERNAME=\"user\" SOCKS_PASSWORD=\"secret\"\r\n\
+             650 STATUS_CLIENT WARN BOOTSTRAP PROGRESS=5 TAG=conn SUMMARY=\"Connecting to a relay\" WARNING=\"Connection refused\"\r\n\
+             650 HS_DESC FAILED abcdef NO_AUTH $CCCC~hsdir REASON=NOT_FOUND\r\n\
+             650 NOTICE Not traced\r\n\
+             650 STATUS_SERVER NOTICE EXTERNAL_ADDRESS ADDRESS=1.2.3.4\r\n\
+             650 CIRC 12\r\n",
+        );
+
+        let collector = Collector::default();
+        let recognized = tracing::subscriber::with_default(collector.clone(), || {
+            events.iter().map(trace_event).collect::<Vec<_>>()
+        });
+        assert_eq!(
+            recognized,
+            vec![true, true, true, true, false, false, false]
+        );
+        assert_eq!(collector.0.lock().unwrap().len(), 4);
+
+        let traced = collector.0.lock().unwrap();
+        assert_eq!(
+            traced[0],
+            "DEBUG tor::circ message=circuit event circuit_id=12 status=BUILT \
+             path=$AAAA~relay1,$BBBB~relay2 build_flags=NEED_CAPACITY purpose=GENERAL"
+        );
+        assert_eq!(
+            traced[1],
+            "DEBUG tor::stream message=stream event stream_id=34 status=SUCCEEDED circuit_id=12 \
+             target=example.com:443 socks_username=user"
+        );
+        assert_eq!(
+            traced[2],
+            "WARN tor::status message=client status event severity=WARN action=BOOTSTRAP \
+             progress=5 tag=conn summary=Connecting to a relay warning=Connection refused"
+        );
+        assert_eq!(
+            traced[3],
+            "WARN tor::hs_desc message=hidden service descriptor event action=FAILED \
+             address=abcdef auth_type=NO_AUTH hs_dir=$CCCC~hsdir reason=NOT_FOUND"
+        );
+    }
+}
@@ -0,0 +1,354 @@
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log_crate::{debug, info};
+
+#[cfg(feature = "tokio")]
+use crate::control::AsyncControlConnection;
+use crate::control::{Authenticator, BootstrapPhase, ControlConnection, ListenerAddr, Signal};
+use crate::instance::TOR_INSTANCE;
+use crate::socks::TorStreamBuilder;
+use crate::{Error, Tor, TorFlag};
+
+/// Name of the file written in the data directory when the control port is `auto`
+const CONTROL_PORT_FILE: &str = "control-port";
+
+/// Delay between attempts to connect to a control port that is not open yet
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Handle to a Tor daemon running in a background thread
+#[derive(Debug)]
+pub struct TorHandle {
+    config: Tor,
+    authenticator: Authenticator,
+    thread: JoinHandle<Result<(), Error>>,
+}
+
+impl Tor {
+    /// Start the Tor daemon in a background thread, returning a handle to interact with it
+    ///
+    /// Since Tor keeps its state in static variables, only one daemon can run in a process,
+    /// and it can't be restarted: this fails with [`Error::AlreadyRunning`] or
+    /// [`Error::AlreadyRan`] respectively.
+    ///
+    /// When the control port is configured with `ControlPortAuto`, Tor is also told to write
+    /// the port it picks to a `control-port` file in the `DataDirectory`, unless
+    /// `ControlPortWriteToFile` is already set.
+    pub fn spawn(&self) -> Result<TorHandle, Error> {
+        let config = self.with_control_port_file();
+        let (argv, mut guard) = config.prepare_run(&TOR_INSTANCE)?;
+
+        // Don't mistake the file left by a previous run for the new one
+        if let Some(path) = config.control_port_file() {
+            if std::fs::remove_file(&path).is_ok() {
+                debug!("Removed stale control port file {}", path.display());
+            }
+        }
+
+        let thread = thread::spawn(move || Tor::run(argv, &mut guard));
+
+        Ok(TorHandle {
+            config,
+            authenticator: Authenticator::new(),
+            thread,
+        })
+    }
+
+    fn with_control_port_file(&self) -> Tor {
+        let mut config = self.clone();
+        let auto = self
+            .flags
+            .iter()
+            .any(|flag| matches!(flag, TorFlag::ControlPortAuto));
+        let data_dir = self.flags.iter().find_map(|flag| match flag {
+            TorFlag::DataDirectory(dir) => Some(dir),
+            _ => None,
+        });
+
+        if let (true, None, Some(data_dir)) = (auto, self.control_port_file(), data_dir) {
+            let path = std::path::Path::new(data_dir).join(CONTROL_PORT_FILE);
+            config.flag(TorFlag::ControlPortWriteToFile(
+                path.to_string_lossy().into_owned(),
+            ));
+        }
+
+        config
+    }
+}
+
+impl TorHandle {
+    /// Configuration the daemon was started with
+    pub fn config(&self) -> &Tor {
+        &self.config
+    }
+
+    /// Whether the daemon is still running
+    pub fn is_running(&self) -> bool {
+        !self.thread.is_finished()
+    }
+
+    /// Set the credentials used to authenticate with the control port
+    ///
+    /// By default only the cookie advertised by Tor or `NULL` authentication are tried, so
+    /// this is required when the daemon is configured with `HashedControlPassword`.
+    pub fn set_authenticator(&mut self, authenticator: Authenticator) -> &mut TorHandle {
+        self.authenticator = authenticator;
+        self
+    }
+
+    /// Address of the control port, either configured or read from the
+    /// `ControlPortWriteToFile` file
+    fn control_addr(&self) -> Result<ListenerAddr, Error> {
+        if let Some(addr) = self.config.control_addr() {
+            return Ok(addr);
+        }
+
+        match self.config.control_port_file() {
+            Some(path) => Ok(ListenerAddr::read_port_file(path)?.remove(0)),
+            None => Err(Error::NoControlPort),
+        }
+    }
+
+    /// Open a new authenticated connection to the control port of the daemon
+    ///
+    /// With `ControlPortAuto`, the port is read from the `ControlPortWriteToFile` file, so
+    /// this fails with an [`Error::Io`] until Tor has opened it.
+    pub fn control_connection(&self) -> Result<ControlConnection, Error> {
+        let conn = ControlConnection::connect(&self.control_addr()?)?;
+        conn.authenticate(&self.authenticator)?;
+
+        Ok(conn)
+    }
+
+    /// Open a new authenticated asynchronous connection to the control port of the daemon
+    ///
+    /// See [`TorHandle::control_connection`].
+    #[cfg(feature = "tokio")]
+    pub async fn async_control_connection(&self) -> Result<AsyncControlConnection, Error> {
+        let conn = AsyncControlConnection::connect(&self.control_addr()?).await?;
+        conn.authenticate(&self.authenticator).await?;
+
+        Ok(conn)
+    }
+
+    /// Call `f` until it succeeds or fails with something else than an I/O error, retrying
+    /// until `deadline` while Tor is starting up
+    fn retry_until<T, F>(&self, deadline: Instant, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        loop {
+            match f() {
+                Ok(value) => return Ok(value),
+                Err(Error::Io(e)) => {
+                    if self.thread.is_finished() {
+                        return Err(Error::NotRunning);
+                    }
+                    if Instant::now() + CONNECT_RETRY_INTERVAL > deadline {
+                        return Err(Error::Timeout);
+                    }
+
+                    debug!("Control port not available yet: {}", e);
+                    thread::sleep(CONNECT_RETRY_INTERVAL);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Open a new authenticated connection to the control port, retrying until `deadline`
+    /// while the port is not open yet
+    fn control_connection_until(&self, deadline: Instant) -> Result<ControlConnection, Error> {
+        self.retry_until(deadline, || self.control_connection())
+    }
+
+    /// Return the addresses the control port is listening on, waiting up to `timeout` for
+    /// Tor to open it
+    ///
+    /// This is mostly useful with `ControlPortAuto`: the addresses are read from the
+    /// `ControlPortWriteToFile` file, or are the configured ones if it is not set.
+    pub fn control_listeners(&self, timeout: Duration) -> Result<Vec<ListenerAddr>, Error> {
+        let deadline = Instant::now() + timeout;
+        match self.config.control_port_file() {
+            Some(path) => self.retry_until(deadline, || ListenerAddr::read_port_file(&path)),
+            None => self.control_addr().map(|addr| vec![addr]),
+        }
+    }
+
+    /// Address of the SOCKS port
+    ///
+    /// This is the address configured with `SocksPort` or `SocksPortAddress`, or the first one
+    /// reported by `GETINFO net/listeners/socks` with `SocksPortAuto`.
+    pub fn socks_addr(&self) -> Result<ListenerAddr, Error> {
+        if let Some(addr) = self.config.socks_addr() {
+            return Ok(addr);
+        }
+
+        self.control_connection()?
+            .socks_listeners()?
+            .into_iter()
+            .next()
+            .ok_or(Error::NoSocksPort)
+    }
+
+    /// Asynchronous version of [`TorHandle::socks_addr`], querying the listeners with an
+    /// [`AsyncControlConnection`]
+    #[cfg(feature = "tokio")]
+    pub async fn async_socks_addr(&self) -> Result<ListenerAddr, Error> {
+        if let Some(addr) = self.config.socks_addr() {
+            return Ok(addr);
+        }
+
+        self.async_control_connection()
+            .await?
+            .socks_listeners()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::NoSocksPort)
+    }
+
+    /// Builder for connections through the SOCKS port of the daemon, see
+    /// [`TorHandle::socks_addr`]
+    pub fn stream_builder(&self) -> Result<TorStreamBuilder, Error> {
+        match TorStreamBuilder::from_config(&self.config) {
+            // `SocksPortAuto` can't have isolation flags, it uses the defaults
+            Err(Error::NoSocksPort) => Ok(TorStreamBuilder::new(self.socks_addr()?)),
+            other => other,
+        }
+    }
+
+    /// Return the addresses the SOCKS port is listening on, waiting up to `timeout` for
+    /// Tor to open the control port
+    ///
+    /// The addresses are queried with `GETINFO net/listeners/socks`, so this also works with
+    /// `SocksPortAuto`.
+    pub fn socks_listeners(&self, timeout: Duration) -> Result<Vec<ListenerAddr>, Error> {
+        let deadline = Instant::now() + timeout;
+        self.control_connection_until(deadline)?.socks_listeners()
+    }
+
+    /// Block until Tor has finished bootstrapping
+    ///
+    /// Requires a control port to be configured. See [`TorHandle::wait_for_bootstrap_with`]
+    /// for details.
+    pub fn wait_for_bootstrap(&self, timeout: Duration) -> Result<BootstrapPhase, Error> {
+        self.wait_for_bootstrap_with(timeout, |_| {})
+    }
+
+    /// Block until Tor has finished bootstrapping, calling `callback` with every new phase
+    ///
+    /// Fails with [`Error::Timeout`] if Tor is not ready within `timeout`, and with
+    /// [`Error::Bootstrap`] if Tor reports a problem that it recommends to warn about.
+    pub fn wait_for_bootstrap_with<F>(
+        &self,
+        timeout: Duration,
+        callback: F,
+    ) -> Result<BootstrapPhase, Error>
+    where
+        F: FnMut(&BootstrapPhase),
+    {
+        let deadline = Instant::now() + timeout;
+        let conn = self.control_connection_until(deadline)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        let phase = conn.wait_for_bootstrap(remaining, callback)?;
+        info!("Tor bootstrapped: {}", phase);
+
+        Ok(phase)
+    }
+
+    /// Ask the daemon to shut down cleanly, with `SIGNAL SHUTDOWN`
+    ///
+    /// Relays wait for `ShutdownWaitLength` before exiting, clients exit immediately. Use
+    /// [`TorHandle::join`] to wait for the daemon to exit.
+    pub fn shutdown(&self) -> Result<(), Error> {
+        self.signal_exit(Signal::Shutdown)
+    }
+
+    /// Ask the daemon to exit immediately, with `SIGNAL HALT`
+    pub fn halt(&self) -> Result<(), Error> {
+        self.signal_exit(Signal::Halt)
+    }
+
+    fn signal_exit(&self, signal: Signal) -> Result<(), Error> {
+        if !self.is_running() {
+            return Err(Error::NotRunning);
+        }
+
+        tracing_span!(info_span, "tor_shutdown", %signal);
+        debug!("Sending {} to the Tor daemon", signal);
+        self.control_connection()?.signal(signal)
+    }
+
+    /// Wait for the daemon to exit
+    ///
+    /// Returns [`Error::Exited`] if Tor exited with a non-zero code.
+    pub fn join(self) -> Result<(), Error> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_port_file() {
+        let config = Tor::new()
+            .flag(TorFlag::DataDirectory("/tmp/tor-rust".into()))
+            .flag(TorFlag::ControlPortAuto)
+            .with_control_port_file();
+        assert_eq!(
+            config.control_port_file(),
+            Some("/tmp/tor-rust/control-port".into())
+        );
+
+        let config = Tor::new()
+            .flag(TorFlag::DataDirectory("/tmp/tor-rust".into()))
+            .flag(TorFlag::ControlPortAuto)
+            .flag(TorFlag::ControlPortWriteToFile("/tmp/port".into()))
+            .with_control_port_file();
+        assert_eq!(config.control_port_file(), Some("/tmp/port".into()));
+
+        let config = Tor::new()
+            .flag(TorFlag::DataDirectory("/tmp/tor-rust".into()))
+            .flag(TorFlag::ControlPort(9051))
+            .with_control_port_file();
+        assert_eq!(config.control_port_file(), None);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_socks_addr() {
+        use crate::control::reply::fixtures::{OK, PROTOCOLINFO_NULL};
+        use crate::control::testing::FakeControlServer;
+
+        let server = FakeControlServer::new(vec![
+            ("PROTOCOLINFO 1", PROTOCOLINFO_NULL),
+            ("AUTHENTICATE", OK),
+            (
+                "GETINFO net/listeners/socks",
+                "250-net/listeners/socks=\"127.0.0.1:39050\"\r\n250 OK\r\n",
+            ),
+        ]);
+        // Stands in for a daemon started with `SocksPortAuto`
+        let handle = TorHandle {
+            config: Tor::new()
+                .flag(TorFlag::ControlPort(server.addr().port()))
+                .flag(TorFlag::SocksPortAuto)
+                .clone(),
+            authenticator: Authenticator::new(),
+            thread: thread::spawn(|| Ok(())),
+        };
+
+        assert_eq!(
+            handle.async_socks_addr().await.unwrap(),
+            ListenerAddr::Tcp("127.0.0.1:39050".parse().unwrap())
+        );
+        server.join();
+    }
+}
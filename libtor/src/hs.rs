@@ -1,10 +1,26 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+use std::convert::TryFrom;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use curve25519_dalek::{EdwardsPoint, MontgomeryPoint};
+use data_encoding::{BASE32_NOPAD, BASE64};
+use rand::RngCore;
+use sha2::Sha512;
+use sha3::{Digest, Sha3_256};
+
+use crate::validate::is_legacy_v2_option;
+use crate::{Error, Expand, Tor, TorFlag};
+
 /// Hidden service version
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum HiddenServiceVersion {
+    /// Obsolete, rejected by Tor since 0.4.6. See [`Tor::migrate_v2_hidden_services`]
+    #[cfg(feature = "legacy-v2")]
     #[deprecated(note = "Please migrate to V3 hidden services")]
     V2 = 2,
     V3 = 3,
@@ -16,10 +32,983 @@ impl std::fmt::Display for HiddenServiceVersion {
     }
 }
 
+impl std::str::FromStr for HiddenServiceVersion {
+    type Err = Error;
+
+    #[allow(deprecated)]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            #[cfg(feature = "legacy-v2")]
+            "2" => Ok(HiddenServiceVersion::V2),
+            "3" => Ok(HiddenServiceVersion::V3),
+            _ => Err(Error::InvalidValue {
+                kind: "HiddenServiceVersion".into(),
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
 /// Hidden service authorization type for authorized clients
+///
+/// Only supported by v2 hidden services, see [`ClientAuthSecretKey`] for v3 services.
+#[cfg(feature = "legacy-v2")]
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum HiddenServiceAuthType {
     Basic,
     Stealth,
 }
+
+#[cfg(feature = "legacy-v2")]
+from_str_like_debug!(HiddenServiceAuthType, [Basic, Stealth]);
+
+const ONION_CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+const ONION_V3_VERSION: u8 = 3;
+
+/// Address of a v3 onion service, derived from its ed25519 public key
+///
+/// The address is `base32(pubkey || checksum || version)`, where the checksum is the first two
+/// bytes of `SHA3-256(".onion checksum" || pubkey || version)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddressV3 {
+    public_key: [u8; 32],
+}
+
+impl OnionAddressV3 {
+    /// Derive the address from an ed25519 public key
+    pub fn from_public_key(public_key: [u8; 32]) -> OnionAddressV3 {
+        OnionAddressV3 { public_key }
+    }
+
+    /// Ed25519 public key of the service
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    /// Address without the `.onion` suffix, as used in `ServiceID` by the control port
+    pub fn service_id(&self) -> String {
+        let mut raw = self.public_key.to_vec();
+        raw.extend_from_slice(&checksum(&self.public_key));
+        raw.push(ONION_V3_VERSION);
+
+        BASE32_NOPAD.encode(&raw).to_lowercase()
+    }
+}
+
+fn checksum(public_key: &[u8; 32]) -> [u8; 2] {
+    let hash = Sha3_256::new()
+        .chain_update(ONION_CHECKSUM_PREFIX)
+        .chain_update(public_key)
+        .chain_update([ONION_V3_VERSION])
+        .finalize();
+
+    [hash[0], hash[1]]
+}
+
+impl std::fmt::Display for OnionAddressV3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.onion", self.service_id())
+    }
+}
+
+impl std::str::FromStr for OnionAddressV3 {
+    type Err = Error;
+
+    /// Parse an address, with or without the `.onion` suffix, validating its checksum
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidValue {
+            kind: "OnionAddressV3".into(),
+            value: s.to_string(),
+        };
+
+        let service_id = s.strip_suffix(".onion").unwrap_or(s);
+        let raw = BASE32_NOPAD
+            .decode(service_id.to_uppercase().as_bytes())
+            .map_err(|_| invalid())?;
+        if raw.len() != 35 || raw[34] != ONION_V3_VERSION {
+            return Err(invalid());
+        }
+
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&raw[..32]);
+        if raw[32..34] != checksum(&public_key) {
+            return Err(invalid());
+        }
+
+        Ok(OnionAddressV3 { public_key })
+    }
+}
+
+const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+const HOSTNAME_FILE: &str = "hostname";
+const SECRET_KEY_HEADER: &[u8] = b"== ed25519v1-secret: type0 ==";
+const PUBLIC_KEY_HEADER: &[u8] = b"== ed25519v1-public: type0 ==";
+const KEY_HEADER_LEN: usize = 32;
+
+/// Header of the key files, padded with NUL bytes
+fn key_header(header: &[u8]) -> [u8; KEY_HEADER_LEN] {
+    let mut padded = [0; KEY_HEADER_LEN];
+    padded[..header.len()].copy_from_slice(header);
+    padded
+}
+
+/// Strip the header from the content of a key file, checking its length
+fn strip_key_header<'a>(
+    path: &Path,
+    content: &'a [u8],
+    header: &[u8],
+    len: usize,
+) -> Result<&'a [u8], Error> {
+    if content.len() != KEY_HEADER_LEN + len || content[..KEY_HEADER_LEN] != key_header(header) {
+        return Err(Error::InvalidKey(format!(
+            "{} is not a valid key file",
+            path.display()
+        )));
+    }
+
+    Ok(&content[KEY_HEADER_LEN..])
+}
+
+/// Fail if the hidden service directory is accessible by other users, like Tor does
+///
+/// Only the permission bits are checked, on Unix systems.
+pub fn check_hidden_service_dir<P: AsRef<Path>>(dir: P) -> Result<(), Error> {
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = fs::metadata(dir.as_ref())?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(Error::InsecurePermissions {
+                path: dir.as_ref().to_path_buf(),
+                mode,
+            });
+        }
+    }
+    #[cfg(not(target_family = "unix"))]
+    let _ = dir;
+
+    Ok(())
+}
+
+/// Create a directory with `0700` permissions, or check the permissions of an existing one
+fn create_private_dir(dir: &Path) -> Result<(), Error> {
+    if !dir.exists() {
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(target_family = "unix")]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder.create(dir)?;
+    }
+
+    check_hidden_service_dir(dir)
+}
+
+/// Write a file readable only by its owner
+pub(crate) fn write_private_file(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_family = "unix")]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(content)?;
+    Ok(())
+}
+
+/// Secret key of a v3 hidden service
+///
+/// Tor stores the key in its "expanded" form: the clamped scalar followed by the second half of
+/// `SHA-512(seed)`, which is also the format used by `ADD_ONION`.
+#[derive(Clone)]
+pub struct HiddenServiceSecretKey {
+    expanded: [u8; 64],
+}
+
+impl std::fmt::Debug for HiddenServiceSecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HiddenServiceSecretKey")
+            .field("onion_address", &self.onion_address())
+            .finish()
+    }
+}
+
+impl HiddenServiceSecretKey {
+    /// Generate a new random key
+    pub fn generate() -> HiddenServiceSecretKey {
+        let mut seed = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut seed);
+
+        HiddenServiceSecretKey::from_seed(&seed)
+    }
+
+    /// Expand a 32-byte ed25519 seed, as defined in RFC 8032
+    pub fn from_seed(seed: &[u8; 32]) -> HiddenServiceSecretKey {
+        let mut expanded = [0; 64];
+        expanded.copy_from_slice(&Sha512::digest(seed));
+        expanded[0] &= 248;
+        expanded[31] &= 63;
+        expanded[31] |= 64;
+
+        HiddenServiceSecretKey { expanded }
+    }
+
+    /// Use an already expanded key, e.g. from a secrets manager
+    pub fn from_expanded(expanded: [u8; 64]) -> Result<HiddenServiceSecretKey, Error> {
+        if expanded[0] & 7 != 0 || expanded[31] & 192 != 64 {
+            return Err(Error::InvalidKey("The scalar is not clamped".into()));
+        }
+
+        Ok(HiddenServiceSecretKey { expanded })
+    }
+
+    /// Expanded key, the scalar followed by the hash prefix
+    pub fn expanded(&self) -> &[u8; 64] {
+        &self.expanded
+    }
+
+    /// Ed25519 public key
+    pub fn public_key(&self) -> [u8; 32] {
+        let mut scalar = [0; 32];
+        scalar.copy_from_slice(&self.expanded[..32]);
+
+        EdwardsPoint::mul_base_clamped(scalar).compress().to_bytes()
+    }
+
+    /// Onion address of the service
+    pub fn onion_address(&self) -> OnionAddressV3 {
+        OnionAddressV3::from_public_key(self.public_key())
+    }
+
+    /// Key in the `ED25519-V3:<KeyBlob>` format used by `ADD_ONION`
+    pub fn to_control_key(&self) -> String {
+        format!("ED25519-V3:{}", BASE64.encode(&self.expanded))
+    }
+
+    /// Parse a key in the `ED25519-V3:<KeyBlob>` format, as returned by `ADD_ONION`
+    pub fn from_control_key(s: &str) -> Result<HiddenServiceSecretKey, Error> {
+        let raw = s
+            .strip_prefix("ED25519-V3:")
+            .and_then(|blob| BASE64.decode(blob.as_bytes()).ok())
+            .and_then(|raw| <[u8; 64]>::try_from(raw).ok())
+            .ok_or_else(|| Error::InvalidValue {
+                kind: "HiddenServiceSecretKey".into(),
+                value: s.to_string(),
+            })?;
+
+        HiddenServiceSecretKey::from_expanded(raw)
+    }
+
+    /// Read the key from `hs_ed25519_secret_key` in a hidden service directory
+    ///
+    /// If `hs_ed25519_public_key` or `hostname` exist too, they must match the secret key.
+    pub fn read_from_dir<P: AsRef<Path>>(dir: P) -> Result<HiddenServiceSecretKey, Error> {
+        let dir = dir.as_ref();
+        check_hidden_service_dir(dir)?;
+
+        let path = dir.join(SECRET_KEY_FILE);
+        let content = fs::read(&path)?;
+        let mut expanded = [0; 64];
+        expanded.copy_from_slice(strip_key_header(&path, &content, SECRET_KEY_HEADER, 64)?);
+        let key = HiddenServiceSecretKey::from_expanded(expanded)?;
+
+        let path = dir.join(PUBLIC_KEY_FILE);
+        if path.exists() {
+            let content = fs::read(&path)?;
+            if strip_key_header(&path, &content, PUBLIC_KEY_HEADER, 32)? != key.public_key() {
+                return Err(Error::InvalidKey(format!(
+                    "{} doesn't match the secret key",
+                    path.display()
+                )));
+            }
+        }
+
+        let path = dir.join(HOSTNAME_FILE);
+        if path.exists() {
+            let hostname = fs::read_to_string(&path)?;
+            if hostname.trim() != key.onion_address().to_string() {
+                return Err(Error::InvalidKey(format!(
+                    "{} doesn't match the secret key",
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(key)
+    }
+
+    /// Write `hs_ed25519_secret_key`, `hs_ed25519_public_key` and `hostname` to a hidden
+    /// service directory, creating it with `0700` permissions if it doesn't exist
+    pub fn write_to_dir<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>, Error> {
+        let dir = dir.as_ref();
+        create_private_dir(dir)?;
+
+        let mut secret = key_header(SECRET_KEY_HEADER).to_vec();
+        secret.extend_from_slice(&self.expanded);
+        let mut public = key_header(PUBLIC_KEY_HEADER).to_vec();
+        public.extend_from_slice(&self.public_key());
+        let hostname = format!("{}\n", self.onion_address());
+
+        let files = vec![
+            (dir.join(SECRET_KEY_FILE), secret),
+            (dir.join(PUBLIC_KEY_FILE), public),
+            (dir.join(HOSTNAME_FILE), hostname.into_bytes()),
+        ];
+        for (path, content) in &files {
+            write_private_file(path, content)?;
+        }
+
+        Ok(files.into_iter().map(|(path, _)| path).collect())
+    }
+}
+
+const AUTHORIZED_CLIENTS_DIR: &str = "authorized_clients";
+const AUTH_EXTENSION: &str = "auth";
+const AUTH_PRIVATE_EXTENSION: &str = "auth_private";
+const AUTH_PREFIX: &str = "descriptor:x25519:";
+
+/// Encode a key in base32, in lowercase like Tor does
+fn encode_base32_key(key: &[u8; 32]) -> String {
+    BASE32_NOPAD.encode(key).to_lowercase()
+}
+
+/// Decode a base32 key, in any case
+fn decode_base32_key(kind: &'static str, s: &str) -> Result<[u8; 32], Error> {
+    let invalid = || Error::InvalidValue {
+        kind: kind.into(),
+        value: s.to_string(),
+    };
+
+    let raw = BASE32_NOPAD
+        .decode(s.to_uppercase().as_bytes())
+        .map_err(|_| invalid())?;
+    if raw.len() != 32 {
+        return Err(invalid());
+    }
+
+    let mut key = [0; 32];
+    key.copy_from_slice(&raw);
+    Ok(key)
+}
+
+/// Name of a client, used as the name of its file
+fn check_client_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.contains(['/', '\\', '.']) {
+        return Err(Error::InvalidValue {
+            kind: "client name".into(),
+            value: name.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Files in `dir` with the given extension, sorted by name. Returns nothing if `dir` doesn't
+/// exist
+fn list_files(dir: &Path, extension: &str) -> Result<Vec<PathBuf>, Error> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == extension) {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// Secret x25519 key of a client authorized to access a v3 hidden service
+///
+/// The client either stores it in its `ClientOnionAuthDir`, or registers it at runtime with
+/// [`ControlConnection::onion_client_auth_add`](crate::control::ControlConnection::onion_client_auth_add).
+#[derive(Clone)]
+pub struct ClientAuthSecretKey {
+    secret_key: [u8; 32],
+}
+
+impl std::fmt::Debug for ClientAuthSecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientAuthSecretKey")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+impl ClientAuthSecretKey {
+    /// Generate a new random key
+    pub fn generate() -> ClientAuthSecretKey {
+        let mut secret_key = [0; 32];
+        rand::rngs::OsRng.fill_bytes(&mut secret_key);
+
+        ClientAuthSecretKey { secret_key }
+    }
+
+    /// Use an existing key. It doesn't have to be clamped
+    pub fn from_bytes(secret_key: [u8; 32]) -> ClientAuthSecretKey {
+        ClientAuthSecretKey { secret_key }
+    }
+
+    /// Raw secret key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.secret_key
+    }
+
+    /// Public key, to add to the `authorized_clients` of the service
+    pub fn public_key(&self) -> ClientAuthPublicKey {
+        ClientAuthPublicKey {
+            public_key: MontgomeryPoint::mul_base_clamped(self.secret_key).to_bytes(),
+        }
+    }
+
+    /// Key in the `x25519:<KeyBlob>` format used by `ONION_CLIENT_AUTH_ADD`
+    pub fn to_control_key(&self) -> String {
+        format!("x25519:{}", BASE64.encode(&self.secret_key))
+    }
+
+    /// Parse a key in the `x25519:<KeyBlob>` format
+    pub fn from_control_key(s: &str) -> Result<ClientAuthSecretKey, Error> {
+        let invalid = || Error::InvalidValue {
+            kind: "ClientAuthSecretKey".into(),
+            value: s.to_string(),
+        };
+
+        let raw = s
+            .strip_prefix("x25519:")
+            .and_then(|blob| BASE64.decode(blob.as_bytes()).ok())
+            .ok_or_else(invalid)?;
+        if raw.len() != 32 {
+            return Err(invalid());
+        }
+
+        let mut secret_key = [0; 32];
+        secret_key.copy_from_slice(&raw);
+        Ok(ClientAuthSecretKey { secret_key })
+    }
+
+    /// Content of the `.auth_private` file to access `address`
+    pub fn to_auth_private(&self, address: &OnionAddressV3) -> String {
+        format!(
+            "{}:{}{}",
+            address.service_id(),
+            AUTH_PREFIX,
+            encode_base32_key(&self.secret_key)
+        )
+    }
+
+    /// Parse the content of an `.auth_private` file
+    pub fn from_auth_private(s: &str) -> Result<(OnionAddressV3, ClientAuthSecretKey), Error> {
+        let s = s.trim();
+        let (address, key) = s
+            .split_once(':')
+            .and_then(|(address, rest)| Some((address, rest.strip_prefix(AUTH_PREFIX)?)))
+            .ok_or_else(|| Error::InvalidValue {
+                kind: "auth_private".into(),
+                value: s.to_string(),
+            })?;
+
+        Ok((
+            address.parse()?,
+            ClientAuthSecretKey::from_bytes(decode_base32_key("auth_private", key)?),
+        ))
+    }
+
+    /// Write the key to `<dir>/<service_id>.auth_private`, creating `dir` with `0700`
+    /// permissions if it doesn't exist
+    ///
+    /// `dir` is meant to be used as the `ClientOnionAuthDir`.
+    pub fn write_auth_private<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        address: &OnionAddressV3,
+    ) -> Result<PathBuf, Error> {
+        let dir = dir.as_ref();
+        create_private_dir(dir)?;
+
+        let path = dir
+            .join(address.service_id())
+            .with_extension(AUTH_PRIVATE_EXTENSION);
+        write_private_file(&path, self.to_auth_private(address).as_bytes())?;
+
+        Ok(path)
+    }
+
+    /// Read all the `.auth_private` files in a `ClientOnionAuthDir`
+    pub fn read_auth_private_dir<P: AsRef<Path>>(
+        dir: P,
+    ) -> Result<Vec<(OnionAddressV3, ClientAuthSecretKey)>, Error> {
+        list_files(dir.as_ref(), AUTH_PRIVATE_EXTENSION)?
+            .into_iter()
+            .map(|path| ClientAuthSecretKey::from_auth_private(&fs::read_to_string(path)?))
+            .collect()
+    }
+}
+
+/// Public x25519 key of a client authorized to access a v3 hidden service
+///
+/// Formatted as the content of an `.auth` file, `descriptor:x25519:<base32>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientAuthPublicKey {
+    public_key: [u8; 32],
+}
+
+impl ClientAuthPublicKey {
+    /// Use an existing key
+    pub fn from_bytes(public_key: [u8; 32]) -> ClientAuthPublicKey {
+        ClientAuthPublicKey { public_key }
+    }
+
+    /// Raw public key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    /// Authorize the client by writing the key to `<hs_dir>/authorized_clients/<name>.auth`
+    ///
+    /// Tor only reads the authorized clients on startup or when reloading its configuration.
+    pub fn write_authorized_client<P: AsRef<Path>>(
+        &self,
+        hs_dir: P,
+        name: &str,
+    ) -> Result<PathBuf, Error> {
+        check_client_name(name)?;
+        let dir = hs_dir.as_ref();
+        create_private_dir(dir)?;
+        let dir = dir.join(AUTHORIZED_CLIENTS_DIR);
+        create_private_dir(&dir)?;
+
+        let path = dir.join(name).with_extension(AUTH_EXTENSION);
+        write_private_file(&path, self.to_string().as_bytes())?;
+
+        Ok(path)
+    }
+
+    /// Read the clients authorized in `<hs_dir>/authorized_clients`, with their names
+    pub fn read_authorized_clients<P: AsRef<Path>>(
+        hs_dir: P,
+    ) -> Result<Vec<(String, ClientAuthPublicKey)>, Error> {
+        list_files(
+            &hs_dir.as_ref().join(AUTHORIZED_CLIENTS_DIR),
+            AUTH_EXTENSION,
+        )?
+        .into_iter()
+        .map(|path| {
+            let name = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            Ok((name, fs::read_to_string(&path)?.trim().parse()?))
+        })
+        .collect()
+    }
+}
+
+impl std::fmt::Display for ClientAuthPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}", AUTH_PREFIX, encode_base32_key(&self.public_key))
+    }
+}
+
+impl std::str::FromStr for ClientAuthPublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = s
+            .strip_prefix(AUTH_PREFIX)
+            .ok_or_else(|| Error::InvalidValue {
+                kind: "ClientAuthPublicKey".into(),
+                value: s.to_string(),
+            })?;
+
+        Ok(ClientAuthPublicKey::from_bytes(decode_base32_key(
+            "ClientAuthPublicKey",
+            key,
+        )?))
+    }
+}
+
+/// Hidden service converted to v3 by [`Tor::migrate_v2_hidden_services`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigratedHiddenService {
+    /// `HiddenServiceDir` of the service
+    pub dir: String,
+    /// Clients that were listed in `HiddenServiceAuthorizeClient`, which need new v3 keys
+    pub clients: Vec<String>,
+}
+
+impl MigratedHiddenService {
+    /// Generate a new key for each client and authorize it in the `authorized_clients`
+    /// directory of the service
+    ///
+    /// The returned secret keys must be given to the clients, along with the new onion address.
+    pub fn authorize_clients(&self) -> Result<Vec<(String, ClientAuthSecretKey)>, Error> {
+        self.clients
+            .iter()
+            .map(|name| {
+                let key = ClientAuthSecretKey::generate();
+                key.public_key().write_authorized_client(&self.dir, name)?;
+                Ok((name.clone(), key))
+            })
+            .collect()
+    }
+}
+
+impl Tor {
+    /// Convert the v2 hidden services to v3
+    ///
+    /// `HiddenServiceVersion 2` is replaced with `HiddenServiceVersion 3` and
+    /// `HiddenServiceAuthorizeClient` is removed, keeping track of its clients. The v2 keys can't
+    /// be converted: Tor generates a new key on startup unless one is written with
+    /// [`HiddenServiceSecretKey::write_to_dir`], so the services get a new onion address.
+    ///
+    /// This also applies to options set with [`TorFlag::Custom`] or read from a torrc.
+    pub fn migrate_v2_hidden_services(&mut self) -> Vec<MigratedHiddenService> {
+        let mut migrated: Vec<MigratedHiddenService> = vec![];
+        let mut current_dir = String::new();
+
+        let flags = std::mem::take(&mut self.flags);
+        for flag in flags {
+            let expanded = flag.expand();
+            let keyword = expanded.first().map(String::as_str).unwrap_or_default();
+            let value = expanded.get(1).map(|v| v.trim()).unwrap_or_default();
+
+            if keyword.eq_ignore_ascii_case("HiddenServiceDir") {
+                current_dir = value.to_string();
+            }
+            if !is_legacy_v2_option(&flag) {
+                self.flags.push(flag);
+                continue;
+            }
+
+            let service = match migrated.iter().position(|s| s.dir == current_dir) {
+                Some(i) => &mut migrated[i],
+                None => {
+                    migrated.push(MigratedHiddenService {
+                        dir: current_dir.clone(),
+                        clients: vec![],
+                    });
+                    migrated.last_mut().unwrap()
+                }
+            };
+            if keyword.eq_ignore_ascii_case("HiddenServiceVersion") {
+                self.flags
+                    .push(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3));
+            } else {
+                // `<auth-type> <client>,<client>,...`
+                let clients = value.split_once(' ').map(|(_, c)| c).unwrap_or_default();
+                service.clients.extend(
+                    clients
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|c| !c.is_empty())
+                        .map(str::to_string),
+                );
+            }
+        }
+
+        migrated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::HEXLOWER;
+
+    use super::*;
+
+    // From the test vectors in rend-spec-v3
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+    const ADDRESS: &str = "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sid.onion";
+
+    #[test]
+    fn test_onion_address_v3() {
+        let mut public_key = [0; 32];
+        public_key.copy_from_slice(&HEXLOWER.decode(PUBLIC_KEY.as_bytes()).unwrap());
+
+        let address = OnionAddressV3::from_public_key(public_key);
+        assert_eq!(address.to_string(), ADDRESS);
+        assert_eq!(address.service_id(), ADDRESS.trim_end_matches(".onion"));
+
+        assert_eq!(ADDRESS.parse::<OnionAddressV3>().unwrap(), address);
+        assert_eq!(
+            ADDRESS
+                .trim_end_matches(".onion")
+                .to_uppercase()
+                .parse::<OnionAddressV3>()
+                .unwrap(),
+            address
+        );
+    }
+
+    #[test]
+    fn test_invalid_onion_address_v3() {
+        // Wrong checksum
+        assert!(
+            "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5aid.onion"
+                .parse::<OnionAddressV3>()
+                .is_err()
+        );
+        // Wrong version
+        assert!(
+            "25njqamcweflpvkl73j4szahhihoc4xt3ktcgjnpaingr5yhkenl5sia.onion"
+                .parse::<OnionAddressV3>()
+                .is_err()
+        );
+        // Wrong length
+        assert!("expyuzz4wqqyqhjn.onion".parse::<OnionAddressV3>().is_err());
+    }
+
+    #[test]
+    fn test_secret_key() {
+        // Seed of the first test vector of RFC 8032, whose public key is `PUBLIC_KEY`
+        let mut seed = [0; 32];
+        seed.copy_from_slice(
+            &HEXLOWER
+                .decode(b"9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+                .unwrap(),
+        );
+        let key = HiddenServiceSecretKey::from_seed(&seed);
+        assert_eq!(HEXLOWER.encode(&key.public_key()), PUBLIC_KEY);
+        assert_eq!(key.onion_address().to_string(), ADDRESS);
+        assert!(key.to_control_key().starts_with("ED25519-V3:"));
+
+        let mut unclamped = *key.expanded();
+        unclamped[0] |= 1;
+        assert!(HiddenServiceSecretKey::from_expanded(unclamped).is_err());
+    }
+
+    #[test]
+    fn test_control_key() {
+        let key = HiddenServiceSecretKey::generate();
+        let parsed = HiddenServiceSecretKey::from_control_key(&key.to_control_key()).unwrap();
+        assert_eq!(parsed.onion_address(), key.onion_address());
+
+        assert!(HiddenServiceSecretKey::from_control_key("ED25519-V3:a2V5").is_err());
+        assert!(HiddenServiceSecretKey::from_control_key("RSA1024:a2V5").is_err());
+    }
+
+    #[test]
+    fn test_secret_key_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("libtor-test-hs-{}", std::process::id()))
+            .join("hs");
+        let key = HiddenServiceSecretKey::generate();
+
+        let files = key.write_to_dir(&dir).unwrap();
+        assert_eq!(files.len(), 3);
+        let secret = fs::read(dir.join(SECRET_KEY_FILE)).unwrap();
+        assert_eq!(secret.len(), 96);
+        assert!(secret.starts_with(b"== ed25519v1-secret: type0 ==\0\0\0"));
+        assert_eq!(
+            fs::read_to_string(dir.join(HOSTNAME_FILE)).unwrap(),
+            format!("{}\n", key.onion_address())
+        );
+
+        let read = HiddenServiceSecretKey::read_from_dir(&dir).unwrap();
+        assert_eq!(read.expanded()[..], key.expanded()[..]);
+
+        fs::write(dir.join(HOSTNAME_FILE), format!("{}\n", ADDRESS)).unwrap();
+        match HiddenServiceSecretKey::read_from_dir(&dir) {
+            Err(Error::InvalidKey(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert_eq!(
+                fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+                0o700
+            );
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+            match key.write_to_dir(&dir) {
+                Err(Error::InsecurePermissions { mode, .. }) => assert_eq!(mode, 0o755),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_client_auth_keys() {
+        // Test vector of RFC 7748, section 6.1
+        let mut secret_key = [0; 32];
+        secret_key.copy_from_slice(
+            &HEXLOWER
+                .decode(b"77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a")
+                .unwrap(),
+        );
+        let key = ClientAuthSecretKey::from_bytes(secret_key);
+        assert_eq!(
+            HEXLOWER.encode(key.public_key().as_bytes()),
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a"
+        );
+
+        let public_key = key.public_key().to_string();
+        assert!(public_key.starts_with("descriptor:x25519:"));
+        assert_eq!(public_key.len(), 18 + 52);
+        assert_eq!(
+            public_key.parse::<ClientAuthPublicKey>().unwrap(),
+            key.public_key()
+        );
+        let uppercase = format!(
+            "descriptor:x25519:{}",
+            BASE32_NOPAD.encode(key.public_key().as_bytes())
+        );
+        assert_eq!(
+            uppercase.parse::<ClientAuthPublicKey>().unwrap(),
+            key.public_key()
+        );
+        assert!("descriptor:x25519:abc"
+            .parse::<ClientAuthPublicKey>()
+            .is_err());
+        assert!("x25519:abc".parse::<ClientAuthPublicKey>().is_err());
+
+        let address = ADDRESS.parse::<OnionAddressV3>().unwrap();
+        let auth_private = key.to_auth_private(&address);
+        assert!(auth_private.starts_with(&format!("{}:descriptor:x25519:", address.service_id())));
+        let (parsed_address, parsed_key) =
+            ClientAuthSecretKey::from_auth_private(&format!("{}\n", auth_private)).unwrap();
+        assert_eq!(parsed_address, address);
+        assert_eq!(parsed_key.as_bytes(), key.as_bytes());
+        assert!(ClientAuthSecretKey::from_auth_private("descriptor:x25519:abc").is_err());
+
+        assert_eq!(
+            key.to_control_key(),
+            "x25519:dwdtCnMYpX08FsFyUbJmRd9ML4frwJkqsXf7pR25LCo="
+        );
+        let parsed_key = ClientAuthSecretKey::from_control_key(&key.to_control_key()).unwrap();
+        assert_eq!(parsed_key.as_bytes(), key.as_bytes());
+        assert!(ClientAuthSecretKey::from_control_key("x25519:a2V5").is_err());
+    }
+
+    #[test]
+    fn test_client_auth_files() {
+        let dir = std::env::temp_dir().join(format!("libtor-test-auth-{}", std::process::id()));
+        let address = ADDRESS.parse::<OnionAddressV3>().unwrap();
+        let alice = ClientAuthSecretKey::generate();
+        let bob = ClientAuthSecretKey::generate();
+
+        let hs_dir = dir.join("hs");
+        let path = bob
+            .public_key()
+            .write_authorized_client(&hs_dir, "bob")
+            .unwrap();
+        assert_eq!(path, hs_dir.join("authorized_clients").join("bob.auth"));
+        alice
+            .public_key()
+            .write_authorized_client(&hs_dir, "alice")
+            .unwrap();
+        assert_eq!(
+            ClientAuthPublicKey::read_authorized_clients(&hs_dir).unwrap(),
+            vec![
+                ("alice".to_string(), alice.public_key()),
+                ("bob".to_string(), bob.public_key())
+            ]
+        );
+        assert!(alice
+            .public_key()
+            .write_authorized_client(&hs_dir, "../alice")
+            .is_err());
+
+        let auth_dir = dir.join("auth");
+        assert!(ClientAuthSecretKey::read_auth_private_dir(&auth_dir)
+            .unwrap()
+            .is_empty());
+        let path = alice.write_auth_private(&auth_dir, &address).unwrap();
+        assert_eq!(
+            path.file_name().unwrap().to_str().unwrap(),
+            format!("{}.auth_private", address.service_id())
+        );
+        let keys = ClientAuthSecretKey::read_auth_private_dir(&auth_dir).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].0, address);
+        assert_eq!(keys[0].1.as_bytes(), alice.as_bytes());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_v2_hidden_services() {
+        let mut tor = Tor::new();
+        tor.flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs-1".into()))
+            .flag(TorFlag::Custom("HiddenServiceVersion 2".into()))
+            .flag(TorFlag::Custom("HiddenServicePort 80".into()))
+            .flag(TorFlag::Custom(
+                "HiddenServiceAuthorizeClient stealth alice,bob".into(),
+            ))
+            .flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs-2".into()))
+            .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
+            .flag(TorFlag::Custom("HiddenServicePort 80".into()));
+        #[cfg(feature = "legacy-v2")]
+        tor.flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs-3".into()))
+            .flag(TorFlag::HiddenServicePort(
+                crate::TorAddress::Port(80),
+                None.into(),
+            ))
+            .flag(TorFlag::HiddenServiceAuthorizeClient(
+                HiddenServiceAuthType::Basic,
+                vec!["carol".to_string()].into(),
+            ));
+        // Only a warning when the v2 flags are explicitly enabled
+        assert_eq!(
+            tor.validate_for_start().is_err(),
+            cfg!(not(feature = "legacy-v2"))
+        );
+        assert!(!tor.validate().is_empty());
+
+        let migrated = tor.migrate_v2_hidden_services();
+        assert_eq!(
+            migrated[0],
+            MigratedHiddenService {
+                dir: "/tmp/tor-rust/hs-1".into(),
+                clients: vec!["alice".into(), "bob".into()],
+            }
+        );
+        #[cfg(feature = "legacy-v2")]
+        assert_eq!(
+            migrated[1],
+            MigratedHiddenService {
+                dir: "/tmp/tor-rust/hs-3".into(),
+                clients: vec!["carol".into()],
+            }
+        );
+        assert_eq!(
+            migrated.len(),
+            if cfg!(feature = "legacy-v2") { 2 } else { 1 }
+        );
+        assert_eq!(tor.validate(), vec![]);
+        assert!(tor.to_torrc().contains(concat!(
+            "HiddenServiceDir /tmp/tor-rust/hs-1\n",
+            "HiddenServiceVersion 3\n",
+            "HiddenServicePort 80\n",
+        )));
+        assert!(tor.migrate_v2_hidden_services().is_empty());
+    }
+
+    #[test]
+    fn test_migrated_authorize_clients() {
+        let dir = std::env::temp_dir().join(format!("libtor-test-v2-{}", std::process::id()));
+        let migrated = MigratedHiddenService {
+            dir: dir.to_str().unwrap().into(),
+            clients: vec!["alice".into()],
+        };
+
+        let keys = migrated.authorize_clients().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            ClientAuthPublicKey::read_authorized_clients(&dir).unwrap(),
+            vec![("alice".to_string(), keys[0].1.public_key())]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::Error;
+
+const IDLE: u8 = 0;
+const RUNNING: u8 = 1;
+const EXITED: u8 = 2;
+
+/// Tracks whether Tor has been started in this process
+///
+/// Tor keeps its state in static variables, so concurrent calls to `tor_run_main` are
+/// undefined behavior, and calling it again after it has returned is not reliably supported
+/// either.
+#[derive(Debug)]
+pub(crate) struct InstanceState(AtomicU8);
+
+/// Marks the daemon as running until dropped
+///
+/// If Tor was never actually started with the guard held, dropping it allows starting Tor
+/// again.
+#[derive(Debug)]
+pub(crate) struct InstanceGuard<'a> {
+    state: &'a InstanceState,
+    started: bool,
+}
+
+/// State of the daemon of this process
+pub(crate) static TOR_INSTANCE: InstanceState = InstanceState::new();
+
+impl InstanceState {
+    pub const fn new() -> InstanceState {
+        InstanceState(AtomicU8::new(IDLE))
+    }
+
+    /// Mark the daemon as running, failing if it's already running or it has already exited
+    pub fn acquire(&self) -> Result<InstanceGuard<'_>, Error> {
+        match self
+            .0
+            .compare_exchange(IDLE, RUNNING, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => Ok(InstanceGuard {
+                state: self,
+                started: false,
+            }),
+            Err(RUNNING) => Err(Error::AlreadyRunning),
+            Err(_) => Err(Error::AlreadyRan),
+        }
+    }
+}
+
+impl InstanceGuard<'_> {
+    /// Record that `tor_run_main` has been called
+    pub fn set_started(&mut self) {
+        self.started = true;
+    }
+}
+
+impl Drop for InstanceGuard<'_> {
+    fn drop(&mut self) {
+        let state = if self.started { EXITED } else { IDLE };
+        self.state.0.store(state, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_concurrent_acquire() {
+        const THREADS: usize = 8;
+
+        let state = InstanceState::new();
+        let acquired = Barrier::new(THREADS);
+
+        let results = thread::scope(|s| {
+            let handles = (0..THREADS)
+                .map(|_| {
+                    s.spawn(|| {
+                        let guard = state.acquire();
+                        // Keep the guard alive until every thread has tried to acquire it
+                        acquired.wait();
+                        guard.map(|mut guard| guard.set_started())
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .filter_map(|r| r.as_ref().err())
+            .all(|e| matches!(e, Error::AlreadyRunning)));
+
+        match state.acquire() {
+            Err(Error::AlreadyRan) => {}
+            other => panic!("unexpected result: {:?}", other),
+        };
+    }
+
+    #[test]
+    fn test_release_without_start() {
+        let state = InstanceState::new();
+
+        // Tor was never started, e.g. the torrc couldn't be written
+        drop(state.acquire().unwrap());
+        let mut guard = state.acquire().unwrap();
+        assert!(matches!(state.acquire(), Err(Error::AlreadyRunning)));
+
+        guard.set_started();
+        drop(guard);
+        assert!(matches!(state.acquire(), Err(Error::AlreadyRan)));
+    }
+}
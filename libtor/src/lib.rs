@@ -30,7 +30,9 @@ extern crate serde;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use std::ffi::CString;
+use std::ffi::{CString, NulError};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::thread::{self, JoinHandle};
 
 use rand::Rng;
@@ -40,21 +42,37 @@ use log_crate::{debug, error, info, trace};
 
 #[macro_use]
 mod utils;
+/// Client for Tor's control port
+pub mod control;
+/// Handle to a running Tor daemon
+pub mod handle;
 /// Hidden services related flags
 pub mod hs;
+mod instance;
 /// Log related flags
 pub mod log;
 /// ControlPort and SocksPort related flags
 pub mod ports;
-
+/// SOCKS5 client to connect through Tor
+pub mod socks;
+/// Render and parse torrc files
+pub mod torrc;
+/// Validation of configurations before starting Tor
+pub mod validate;
+
+pub use crate::handle::TorHandle;
 pub use crate::hs::*;
+use crate::instance::{InstanceGuard, InstanceState};
 pub use crate::log::*;
 pub use crate::ports::*;
 use crate::utils::*;
+pub use crate::validate::{Diagnostic, Problem, Severity};
 
 trait Expand: std::fmt::Debug {
     fn expand(&self) -> Vec<String>;
 
+    /// Only used by the tests generated by `libtor-derive`
+    #[cfg(test)]
     fn expand_cli(&self) -> String {
         let mut parts = self.expand();
         if parts.len() > 1 {
@@ -83,6 +101,10 @@ pub enum SizeUnit {
 }
 
 display_like_debug!(SizeUnit);
+from_str_like_debug!(
+    SizeUnit,
+    [Bytes, KBytes, MBytes, GBytes, TBytes, Bits, KBits, MBits, GBits, TBits]
+);
 
 /// Enum that represents a bool, rendered as `1` for true/enabled and `0` for false/disabled
 #[derive(Debug, Clone, Copy)]
@@ -104,6 +126,21 @@ impl std::fmt::Display for TorBool {
     }
 }
 
+impl std::str::FromStr for TorBool {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(TorBool::True),
+            "0" => Ok(TorBool::False),
+            _ => Err(Error::InvalidValue {
+                kind: "TorBool".into(),
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
 impl From<bool> for TorBool {
     fn from(other: bool) -> TorBool {
         if other {
@@ -177,6 +214,39 @@ impl std::fmt::Display for TorAddress {
     }
 }
 
+impl std::str::FromStr for TorAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(target_family = "unix")]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(TorAddress::Unix(path.to_string()));
+        }
+
+        let invalid = || Error::InvalidValue {
+            kind: "TorAddress".into(),
+            value: s.to_string(),
+        };
+
+        if s.is_empty() || s.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        if let Ok(port) = s.parse() {
+            return Ok(TorAddress::Port(port));
+        }
+        // A bare IPv6 address, whose last group must not be mistaken for a port
+        if s.parse::<std::net::IpAddr>().is_ok() {
+            return Ok(TorAddress::Address(s.to_string()));
+        }
+        match s.rsplit_once(':').map(|(addr, port)| (addr, port.parse())) {
+            // IPv6 addresses followed by a port must be enclosed in brackets
+            Some((addr, _)) if addr.contains(':') && !addr.starts_with('[') => Err(invalid()),
+            Some((addr, Ok(port))) => Ok(TorAddress::AddressPort(addr.to_string(), port)),
+            _ => Ok(TorAddress::Address(s.to_string())),
+        }
+    }
+}
+
 /// Enum that represents a subset of the options supported by Tor
 ///
 /// Generally speaking, all the server-only features have not been mapped since this crate is
@@ -301,12 +371,16 @@ pub enum TorFlag {
     HiddenServiceDir(String),
     HiddenServicePort(TorAddress, DisplayOption<TorAddress>),
     HiddenServiceVersion(HiddenServiceVersion),
+    /// Only supported by v2 hidden services
+    #[cfg(feature = "legacy-v2")]
     #[expand_to("HiddenServiceAuthorizeClient {:?} {}")]
     HiddenServiceAuthorizeClient(HiddenServiceAuthType, DisplayVec<String, CommaJoiner>),
     HiddenServiceAllowUnknownPorts(TorBool),
     HiddenServiceMaxStreams(usize),
     HiddenServiceMaxStreamsCloseCircuit(TorBool),
 
+    ClientOnionAuthDir(String),
+
     /// Custom argument, expanded as `<first_word> "<second_word> <third_word> ..."`
     #[expand_to("{}")]
     Custom(String),
@@ -319,22 +393,215 @@ pub enum TorFlag {
     Hush(),
 }
 
+/// Reason why the Tor daemon exited with a non-zero code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ExitReason {
+    /// Tor failed to start, usually because the configuration is invalid or a port is already
+    /// in use (code `-1`)
+    Startup,
+    /// Tor hit a fatal error while running, e.g. it couldn't reload its configuration (code
+    /// `1`)
+    Fatal,
+    /// Any other exit code
+    Other(i32),
+}
+
+impl ExitReason {
+    /// Map the return value of `tor_run_main`, returning `None` if Tor exited cleanly
+    pub fn from_code(code: i32) -> Option<ExitReason> {
+        match code {
+            0 => None,
+            -1 => Some(ExitReason::Startup),
+            1 => Some(ExitReason::Fatal),
+            other => Some(ExitReason::Other(other)),
+        }
+    }
+
+    /// Raw exit code
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitReason::Startup => -1,
+            ExitReason::Fatal => 1,
+            ExitReason::Other(code) => *code,
+        }
+    }
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Startup => write!(f, "startup failed")?,
+            ExitReason::Fatal => write!(f, "fatal error")?,
+            ExitReason::Other(_) => write!(f, "unknown error")?,
+        }
+        write!(f, " (code {})", self.code())
+    }
+}
+
 /// Error enum
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Error {
+    /// The Tor daemon is not running
     NotRunning,
+    /// Another Tor daemon is already running in this process
+    AlreadyRunning,
+    /// Tor has already run and exited in this process, and can't be restarted
+    AlreadyRan,
+    /// A flag expands to an argument containing a NUL byte
+    InvalidArgument {
+        flag: Box<TorFlag>,
+        #[cfg_attr(feature = "serde", serde(with = "error_serde::nul_error"))]
+        source: NulError,
+    },
+    /// The Tor daemon exited with a non-zero code
+    Exited(ExitReason),
+    /// No control port is configured, or its address can't be determined
+    NoControlPort,
+    /// No SOCKS port is configured, or its address can't be determined
+    NoSocksPort,
+    /// I/O error while talking to Tor
+    ///
+    /// Only the message is kept when serialized, the kind is always `Other` once deserialized.
+    Io(#[cfg_attr(feature = "serde", serde(with = "error_serde::io_error"))] Arc<std::io::Error>),
+    /// Tor replied to a control port command with an error status code
+    Control { code: u16, message: String },
+    /// Tor sent a reply that couldn't be parsed
+    Protocol(String),
+    /// None of the authentication methods supported by Tor could be used
+    Authentication(String),
+    /// The string is not a valid `HashedControlPassword` value
+    InvalidHashedPassword(String),
+    /// The string can't be parsed as a value of type `kind`
+    InvalidValue { kind: String, value: String },
+    /// The torrc file is malformed
+    InvalidTorrc(String),
+    /// A hidden service key is malformed, or doesn't match the other files
+    InvalidKey(String),
+    /// A directory is accessible by other users, and Tor would refuse to use it
+    InsecurePermissions { path: PathBuf, mode: u32 },
+    /// The configuration has errors that would prevent Tor from starting
+    InvalidConfig(Vec<Diagnostic>),
+    /// The operation didn't complete in time
+    Timeout,
+    /// Tor reported a problem while bootstrapping
+    Bootstrap(Box<control::BootstrapPhase>),
+    /// The SOCKS proxy refused the connection
+    Socks(socks::SocksError),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::NotRunning => write!(f, "Tor service is not running"),
+            Error::AlreadyRunning => write!(
+                f,
+                "Tor service is already running, only one instance can run in a process"
+            ),
+            Error::AlreadyRan => write!(
+                f,
+                "Tor service has already run in this process and can't be restarted"
+            ),
+            Error::InvalidArgument { flag, .. } => write!(f, "Invalid argument in {:?}", flag),
+            Error::Exited(reason) => write!(f, "Tor service exited: {}", reason),
+            Error::NoControlPort => write!(f, "No control port configured"),
+            Error::NoSocksPort => write!(f, "No SOCKS port configured"),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Control { code, message } => {
+                write!(f, "Control port error {}: {}", code, message)
+            }
+            Error::Protocol(message) => write!(f, "Invalid control port reply: {}", message),
+            Error::Authentication(message) => write!(f, "Authentication failed: {}", message),
+            Error::InvalidHashedPassword(value) => {
+                write!(f, "Invalid hashed password: {}", value)
+            }
+            Error::InvalidValue { kind, value } => write!(f, "Invalid {}: {:?}", kind, value),
+            Error::InvalidTorrc(message) => write!(f, "Invalid torrc: {}", message),
+            Error::InvalidKey(message) => write!(f, "Invalid key: {}", message),
+            Error::InsecurePermissions { path, mode } => write!(
+                f,
+                "Permissions {:o} of {} are too permissive",
+                mode,
+                path.display()
+            ),
+            Error::InvalidConfig(errors) => {
+                write!(f, "Invalid configuration: ")?;
+                for (i, error) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            Error::Timeout => write!(f, "Operation timed out"),
+            Error::Bootstrap(phase) => write!(f, "Bootstrap failed at {}", phase),
+            Error::Socks(e) => write!(f, "SOCKS error: {}", e),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidArgument { source, .. } => Some(source),
+            Error::Io(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<socks::SocksError> for Error {
+    fn from(other: socks::SocksError) -> Error {
+        Error::Socks(other)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(other: std::io::Error) -> Error {
+        Error::Io(Arc::new(other))
+    }
+}
+
+/// Serialization of the foreign errors wrapped by [`Error`]
+#[cfg(feature = "serde")]
+mod error_serde {
+    pub mod io_error {
+        use std::io;
+        use std::sync::Arc;
+
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(error: &Arc<io::Error>, s: S) -> Result<S::Ok, S::Error> {
+            s.collect_str(error)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Arc<io::Error>, D::Error> {
+            let message = String::deserialize(d)?;
+            Ok(Arc::new(io::Error::other(message)))
+        }
+    }
+
+    pub mod nul_error {
+        use std::ffi::{CString, NulError};
+
+        use serde::de::Error;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(error: &NulError, s: S) -> Result<S::Ok, S::Error> {
+            error.clone().into_vec().serialize(s)
+        }
+
+        /// Rebuild the error from the bytes that failed the conversion
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<NulError, D::Error> {
+            let bytes = Vec::<u8>::deserialize(d)?;
+            CString::new(bytes)
+                .err()
+                .ok_or_else(|| D::Error::custom("the bytes don't contain a NUL byte"))
+        }
+    }
+}
 
 /// Configuration builder for a Tor daemon
 ///
@@ -344,6 +611,10 @@ impl std::error::Error for Error {}
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Tor {
     flags: Vec<TorFlag>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    torrc_file: Option<PathBuf>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    skip_validation: bool,
 }
 
 impl Tor {
@@ -358,23 +629,82 @@ impl Tor {
         self
     }
 
+    /// Add multiple configuration flags, e.g. the ones parsed with [`torrc::read`]
+    pub fn flags<I: IntoIterator<Item = TorFlag>>(&mut self, flags: I) -> &mut Tor {
+        self.flags.extend(flags);
+        self
+    }
+
     /// Start the Tor daemon in the current thread
-    pub fn start(&self) -> Result<u8, Error> {
-        unsafe {
-            let config = tor_sys::tor_main_configuration_new();
-            let mut argv = vec![String::from("tor")];
-            argv.extend_from_slice(
-                &self
-                    .flags
-                    .iter()
-                    .map(TorFlag::expand)
-                    .flatten()
-                    .collect::<Vec<String>>(),
-            );
+    ///
+    /// The configuration is checked with [`Tor::validate`] first, unless disabled with
+    /// [`Tor::validate_on_start`].
+    ///
+    /// Returns [`Error::Exited`] if Tor exits with a non-zero code. Only one daemon can run in
+    /// a process, and it can't be restarted once it has exited: this fails with
+    /// [`Error::AlreadyRunning`] or [`Error::AlreadyRan`] respectively.
+    pub fn start(&self) -> Result<(), Error> {
+        let (argv, mut guard) = self.prepare_run(&instance::TOR_INSTANCE)?;
+        Tor::run(argv, &mut guard)
+    }
 
-            debug!("Starting tor with args: {:#?}", argv);
+    /// Starts the Tor daemon in a background detached thread and return its handle
+    ///
+    /// See [`Tor::start`] for the errors returned.
+    pub fn start_background(&self) -> JoinHandle<Result<(), Error>> {
+        let started = self.prepare_run(&instance::TOR_INSTANCE);
+        thread::spawn(move || {
+            let (argv, mut guard) = started?;
+            Tor::run(argv, &mut guard)
+        })
+    }
+
+    /// Build the arguments passed to `tor_run_main`
+    ///
+    /// The torrc enabled with [`Tor::torrc_file`] is not written yet, see
+    /// [`Tor::write_torrc_file`].
+    pub(crate) fn command_line(&self) -> Result<Vec<CString>, Error> {
+        tracing_span!(
+            debug_span,
+            "tor_config",
+            flags = self.flags.len(),
+            torrc_file = ?self.torrc_file
+        );
+        self.validate_for_start()?;
+        if let Some(path) = &self.torrc_file {
+            return self.torrc_command_line(path);
+        }
 
-            let argv: Vec<_> = argv.into_iter().map(|s| CString::new(s).unwrap()).collect();
+        let mut argv = vec![CString::new("tor").expect("No NUL bytes")];
+        for flag in &self.flags {
+            push_arguments(&mut argv, flag)?;
+        }
+
+        Ok(argv)
+    }
+
+    /// Build the arguments passed to `tor_run_main`, mark `instance` as running and write the
+    /// torrc, in this order
+    pub(crate) fn prepare_run<'a>(
+        &self,
+        instance: &'a InstanceState,
+    ) -> Result<(Vec<CString>, InstanceGuard<'a>), Error> {
+        let argv = self.command_line()?;
+        let guard = instance.acquire()?;
+        self.write_torrc_file()?;
+
+        Ok((argv, guard))
+    }
+
+    /// Run `tor_run_main` while holding the instance guard
+    pub(crate) fn run(argv: Vec<CString>, guard: &mut InstanceGuard<'_>) -> Result<(), Error> {
+        guard.set_started();
+        tracing_span!(info_span, "tor", args = argv.len());
+        tracing_event!(info, "starting Tor");
+        debug!("Starting tor with args: {:#?}", argv);
+
+        let result = unsafe {
+            let config = tor_sys::tor_main_configuration_new();
             let argv: Vec<_> = argv.iter().map(|s| s.as_ptr()).collect();
             tor_sys::tor_main_configuration_set_command_line(
                 config,
@@ -386,49 +716,156 @@ impl Tor {
 
             tor_sys::tor_main_configuration_free(config);
 
-            Ok(result as u8)
+            result
+        };
+
+        match ExitReason::from_code(result) {
+            None => {
+                tracing_event!(info, "Tor exited");
+                Ok(())
+            }
+            Some(reason) => {
+                tracing_event!(error, code = reason.code(), %reason, "Tor exited");
+                error!("Tor exited: {}", reason);
+                Err(Error::Exited(reason))
+            }
         }
     }
+}
 
-    /// Starts the Tor daemon in a background detached thread and return its handle
-    pub fn start_background(&self) -> JoinHandle<Result<u8, Error>> {
-        let cloned = self.clone();
-        thread::spawn(move || cloned.start())
+/// Expand `flag` and append its arguments to `argv`
+fn push_arguments(argv: &mut Vec<CString>, flag: &TorFlag) -> Result<(), Error> {
+    for arg in flag.expand() {
+        let arg = CString::new(arg).map_err(|source| Error::InvalidArgument {
+            flag: Box::new(flag.clone()),
+            source,
+        })?;
+        argv.push(arg);
     }
+
+    Ok(())
 }
 
 /// Generate a hashed password to use HashedControlPassword
 pub fn generate_hashed_password(secret: &str) -> String {
-    // This code is rewrite of
-    // https://gist.github.com/s4w3d0ff/9d65ec5866d78842547183601b2fa4d5
-    // s4w3d0ff and jamesacampbell, Thank you!
-
-    let c: usize = 96;
-    const EXPBIAS: usize = 6;
-    let mut count = (16_usize + (c & 15_usize)) << ((c >> 4_usize) + EXPBIAS);
-    let mut d = sha1::Sha1::new();
-
-    let slen = 8 + (secret.as_bytes().len());
-    let mut tmp = Vec::with_capacity(slen);
-    tmp.extend_from_slice(&rand::rngs::OsRng.gen::<u64>().to_ne_bytes());
-    tmp.extend_from_slice(secret.as_bytes());
-
-    while count != 0 {
-        if count > slen {
-            d.update(&tmp);
-            count -= slen;
-        } else {
-            d.update(&tmp[..count]);
-            break;
+    HashedPassword::generate(secret).to_string()
+}
+
+/// Password hashed with Tor's salted S2K algorithm, in the format used by
+/// `HashedControlPassword`
+///
+/// The string representation is `16:<salt><count byte><digest>`, all hex-encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashedPassword {
+    salt: [u8; 8],
+    count_byte: u8,
+    digest: [u8; 20],
+}
+
+impl HashedPassword {
+    const DEFAULT_COUNT_BYTE: u8 = 96;
+
+    /// Hash `secret` with a random salt
+    pub fn generate(secret: &str) -> HashedPassword {
+        HashedPassword::with_salt(secret, rand::rngs::OsRng.gen())
+    }
+
+    /// Hash `secret` with the given salt, to get deterministic results
+    pub fn with_salt(secret: &str, salt: [u8; 8]) -> HashedPassword {
+        HashedPassword {
+            salt,
+            count_byte: Self::DEFAULT_COUNT_BYTE,
+            digest: Self::s2k(secret, &salt, Self::DEFAULT_COUNT_BYTE),
         }
     }
-    let hashed = d.digest().to_string();
-    let tmp = tmp[..8]
-        .iter()
-        .map(|n| format!("{:02x}", n))
-        .collect::<String>();
 
-    format!("16:{}{:02x}{}", tmp, c as u8, hashed)
+    fn s2k(secret: &str, salt: &[u8; 8], count_byte: u8) -> [u8; 20] {
+        // This code is rewrite of
+        // https://gist.github.com/s4w3d0ff/9d65ec5866d78842547183601b2fa4d5
+        // s4w3d0ff and jamesacampbell, Thank you!
+
+        let mut count = Self::count_from_byte(count_byte);
+        let mut d = sha1::Sha1::new();
+
+        let slen = 8 + secret.len();
+        let mut tmp = Vec::with_capacity(slen);
+        tmp.extend_from_slice(salt);
+        tmp.extend_from_slice(secret.as_bytes());
+
+        while count != 0 {
+            if count > slen {
+                d.update(&tmp);
+                count -= slen;
+            } else {
+                d.update(&tmp[..count]);
+                break;
+            }
+        }
+
+        d.digest().bytes()
+    }
+
+    fn count_from_byte(c: u8) -> usize {
+        const EXPBIAS: usize = 6;
+        let c = c as usize;
+        (16_usize + (c & 15_usize)) << ((c >> 4_usize) + EXPBIAS)
+    }
+
+    /// Salt used to hash the password
+    pub fn salt(&self) -> [u8; 8] {
+        self.salt
+    }
+
+    /// Number of bytes fed to the hash function, derived from the count byte
+    pub fn iterations(&self) -> usize {
+        Self::count_from_byte(self.count_byte)
+    }
+
+    /// Check whether `secret` matches this hashed password, in constant time
+    pub fn verify(&self, secret: &str) -> bool {
+        let digest = Self::s2k(secret, &self.salt, self.count_byte);
+        digest
+            .iter()
+            .zip(self.digest.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+impl std::fmt::Display for HashedPassword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut bytes = self.salt.to_vec();
+        bytes.push(self.count_byte);
+        bytes.extend_from_slice(&self.digest);
+
+        write!(f, "16:{}", data_encoding::HEXUPPER.encode(&bytes))
+    }
+}
+
+impl std::str::FromStr for HashedPassword {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidHashedPassword(s.to_string());
+
+        let hex = s.strip_prefix("16:").ok_or_else(invalid)?;
+        let bytes = data_encoding::HEXUPPER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .map_err(|_| invalid())?;
+        if bytes.len() != 29 {
+            return Err(invalid());
+        }
+
+        let mut hashed = HashedPassword {
+            salt: [0; 8],
+            count_byte: bytes[8],
+            digest: [0; 20],
+        };
+        hashed.salt.copy_from_slice(&bytes[..8]);
+        hashed.digest.copy_from_slice(&bytes[9..]);
+
+        Ok(hashed)
+    }
 }
 
 #[cfg(test)]
@@ -438,18 +875,107 @@ mod tests {
     #[test]
     #[ignore]
     fn test_run() {
-        Tor::new()
+        let handle = Tor::new()
             .flag(TorFlag::DataDirectory("/tmp/tor-rust".into()))
+            .flag(TorFlag::ControlPortAuto)
             .flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs-dir".into()))
             .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
             .flag(TorFlag::HiddenServicePort(
                 TorAddress::Port(80),
                 Some(TorAddress::AddressPort("example.org".into(), 80)).into(),
             ))
-            .flag(TorFlag::SocksPort(0))
-            .start_background();
+            .flag(TorFlag::SocksPortAuto)
+            .spawn()
+            .unwrap();
+
+        let phase = handle
+            .wait_for_bootstrap(std::time::Duration::from_secs(120))
+            .unwrap();
+        assert!(phase.is_done());
+        assert!(handle.is_running());
+
+        let timeout = std::time::Duration::from_secs(10);
+        assert!(matches!(
+            handle.control_listeners(timeout).unwrap()[..],
+            [control::ListenerAddr::Tcp(_)]
+        ));
+        assert!(!handle.socks_listeners(timeout).unwrap().is_empty());
+
+        handle.shutdown().unwrap();
+        handle.join().unwrap();
+    }
 
-        std::thread::sleep(std::time::Duration::from_secs(10));
+    #[test]
+    fn test_invalid_argument() {
+        use std::error::Error as _;
+
+        let err = Tor::new()
+            .flag(TorFlag::SocksPort(19050))
+            .flag(TorFlag::DataDirectory("/tmp/tor\0rust".into()))
+            .start()
+            .unwrap_err();
+        match &err {
+            Error::InvalidArgument { flag, .. } => {
+                assert!(matches!(**flag, TorFlag::DataDirectory(_)))
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(err.source().unwrap().is::<NulError>());
+    }
+
+    #[test]
+    fn test_already_running() {
+        // A local instance, so that the daemon of this process is left alone
+        let state = InstanceState::new();
+        let path = std::env::temp_dir().join(format!("libtor-running-{}", std::process::id()));
+        let tor = Tor::new()
+            .flag(TorFlag::SocksPort(19050))
+            .torrc_file(&path)
+            .clone();
+
+        // Stands in for a daemon started by another thread
+        let running = state.acquire().unwrap();
+        assert!(matches!(
+            tor.prepare_run(&state),
+            Err(Error::AlreadyRunning)
+        ));
+        // The torrc of a running daemon is not overwritten
+        assert!(!path.exists());
+
+        // The daemon was never marked as started, so the instance is released
+        drop(running);
+        let (_, _guard) = tor.prepare_run(&state).unwrap();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_tor_address() {
+        let parse = |s: &str| s.parse::<TorAddress>();
+
+        assert!(matches!(parse("9050"), Ok(TorAddress::Port(9050))));
+        assert!(matches!(parse("127.0.0.1"), Ok(TorAddress::Address(a)) if a == "127.0.0.1"));
+        assert!(matches!(
+            parse("127.0.0.1:9050"),
+            Ok(TorAddress::AddressPort(a, 9050)) if a == "127.0.0.1"
+        ));
+        assert!(matches!(parse("::1"), Ok(TorAddress::Address(a)) if a == "::1"));
+        assert!(matches!(parse("fe80::1:80"), Ok(TorAddress::Address(a)) if a == "fe80::1:80"));
+        assert!(matches!(
+            parse("[::1]:9050"),
+            Ok(TorAddress::AddressPort(a, 9050)) if a == "[::1]"
+        ));
+        assert!(parse("fe80::g:80").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn test_exit_reason() {
+        assert_eq!(ExitReason::from_code(0), None);
+        assert_eq!(ExitReason::from_code(-1), Some(ExitReason::Startup));
+        assert_eq!(ExitReason::from_code(1), Some(ExitReason::Fatal));
+        assert_eq!(ExitReason::from_code(2), Some(ExitReason::Other(2)));
+        assert_eq!(ExitReason::Startup.to_string(), "startup failed (code -1)");
     }
 
     #[test]
@@ -458,6 +984,44 @@ mod tests {
             let secret = i.to_string();
             let hashed_pwd = generate_hashed_password(&secret);
             assert_eq!(hashed_pwd.len(), 61);
+            assert!(hashed_pwd
+                .parse::<HashedPassword>()
+                .unwrap()
+                .verify(&secret));
+        }
+    }
+
+    #[test]
+    fn test_hashed_password_vectors() {
+        // Generated with `tor --hash-password`
+        let vectors = [(
+            "my_password",
+            "16:E600ADC1B52C80BB6022A0E999A7734571A451EB6AE50FED489B72E3DF",
+        )];
+
+        for (secret, expected) in vectors.iter() {
+            let hashed = expected.parse::<HashedPassword>().unwrap();
+            assert!(hashed.verify(secret));
+            assert!(!hashed.verify("wrong"));
+            assert_eq!(hashed.iterations(), 65536);
+            assert_eq!(&hashed.to_string(), expected);
+            assert_eq!(
+                HashedPassword::with_salt(secret, hashed.salt()).to_string(),
+                *expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_hashed_password() {
+        for invalid in [
+            "E600ADC1B52C80BB6022A0E999A7734571A451EB6AE50FED489B72E3DF",
+            "16:E600ADC1B52C80BB60",
+            "16:ZZ00ADC1B52C80BB6022A0E999A7734571A451EB6AE50FED489B72E3DF",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<HashedPassword>().is_err());
         }
     }
 }
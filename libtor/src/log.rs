@@ -1,8 +1,19 @@
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::control::Reply;
+use crate::Error;
+
 /// Log level
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LogLevel {
     Debug,
@@ -12,6 +23,38 @@ pub enum LogLevel {
     Err,
 }
 
+from_str_like_debug!(LogLevel, [Debug, Info, Notice, Warn, Err]);
+
+impl LogLevel {
+    /// All the levels, from the most verbose
+    pub const ALL: [LogLevel; 5] = [
+        LogLevel::Debug,
+        LogLevel::Info,
+        LogLevel::Notice,
+        LogLevel::Warn,
+        LogLevel::Err,
+    ];
+
+    /// Name of the control port event for this level, e.g. `NOTICE`
+    pub fn event_name(&self) -> String {
+        format!("{:?}", self).to_uppercase()
+    }
+}
+
+impl From<LogLevel> for log_crate::Level {
+    /// Tor's `info` is about as verbose as `debug` usually is, so every level is shifted down
+    /// by one: `notice`, the default of Tor, becomes `Info`
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Debug => log_crate::Level::Trace,
+            LogLevel::Info => log_crate::Level::Debug,
+            LogLevel::Notice => log_crate::Level::Info,
+            LogLevel::Warn => log_crate::Level::Warn,
+            LogLevel::Err => log_crate::Level::Error,
+        }
+    }
+}
+
 /// Log destination
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -24,6 +67,32 @@ pub enum LogDestination {
     #[cfg(target_os = "android")]
     Android,
 }
+
+impl std::str::FromStr for LogDestination {
+    type Err = crate::Error;
+
+    /// Parse a destination as written in a `Log` line, e.g. `stdout` or `file /var/log/tor.log`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, char::is_whitespace);
+        let kind = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let arg = parts.next().map(str::trim).unwrap_or_default();
+
+        match (kind.as_str(), arg) {
+            ("stdout", "") => Ok(LogDestination::Stdout),
+            ("stderr", "") => Ok(LogDestination::Stderr),
+            #[cfg(target_family = "unix")]
+            ("syslog", "") => Ok(LogDestination::Syslog),
+            #[cfg(target_os = "android")]
+            ("android", "") => Ok(LogDestination::Android),
+            ("file", path) if !path.is_empty() => Ok(LogDestination::File(path.to_string())),
+            _ => Err(crate::Error::InvalidValue {
+                kind: "LogDestination".into(),
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
 impl std::fmt::Display for LogDestination {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -34,7 +103,7 @@ impl std::fmt::Display for LogDestination {
 }
 
 /// Log domain, for fine grained control
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LogDomain {
     General,
@@ -68,3 +137,287 @@ pub enum LogDomain {
     Btrack,
     Mesg,
 }
+
+from_str_like_debug!(
+    LogDomain,
+    [
+        General, Crypto, Net, Config, Fs, Protocol, Mm, Http, App, Control, Circ, Rend, Bug, Dir,
+        Dirserv, Or, Edge, Acct, Hist, Handshake, Heartbeat, Channel, Sched, Guard, Consdiff, Dos,
+        Process, Pt, Btrack, Mesg,
+    ]
+);
+
+impl LogDomain {
+    /// Target of the `log` records for this domain, e.g. `tor::circ`
+    pub fn target(&self) -> String {
+        format!("tor::{:?}", self).to_lowercase()
+    }
+}
+
+/// Log message emitted by Tor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogMessage {
+    pub level: LogLevel,
+    /// Domains of the message. Only known when reading a log file written with
+    /// `LogMessageDomains 1`
+    pub domains: Vec<LogDomain>,
+    pub message: String,
+}
+
+impl LogMessage {
+    /// Parse a log event received from the control port, e.g. `650 NOTICE message`
+    pub fn from_event(event: &Reply) -> Option<LogMessage> {
+        let line = event.lines.first().filter(|_| event.is_async())?;
+        let (level, message) = match &line.data {
+            Some(data) => (line.text.as_str(), data.trim_end()),
+            None => line.text.split_once(' ')?,
+        };
+
+        Some(LogMessage {
+            level: level.parse().ok()?,
+            domains: vec![],
+            message: message.to_string(),
+        })
+    }
+
+    /// Parse a line of a log file, e.g. `Oct 18 12:00:00.000 [notice] {CIRC} message`
+    pub fn from_line(line: &str) -> Option<LogMessage> {
+        let start = line.find(" [")? + 2;
+        let (level, message) = line[start..].split_once("] ")?;
+
+        let mut domains = vec![];
+        let mut message = message.trim_end();
+        if let Some((names, rest)) = message.strip_prefix('{').and_then(|m| m.split_once("} ")) {
+            if let Ok(parsed) = names.split(',').map(str::parse).collect() {
+                domains = parsed;
+                message = rest;
+            }
+        }
+
+        Some(LogMessage {
+            level: level.parse().ok()?,
+            domains,
+            message: message.to_string(),
+        })
+    }
+
+    /// Target of the `log` record, `tor::<domain>` for the first domain or `tor` if unknown
+    pub fn target(&self) -> String {
+        match self.domains.first() {
+            Some(domain) => domain.target(),
+            None => "tor".to_string(),
+        }
+    }
+
+    /// Emit the message as a `log` record
+    pub fn forward(&self) {
+        log_crate::log!(
+            target: &self.target(),
+            self.level.into(),
+            "{}",
+            self.message
+        );
+    }
+}
+
+/// How often [`LogFileForwarder`] checks the file for new lines
+const LOG_FILE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Follows a log file written by Tor from a background thread, forwarding every new line
+///
+/// Use it with a [`LogDestination::File`], and enable `LogMessageDomains` to get the domains
+/// of the messages. Unlike the control port, this also captures the messages logged before
+/// the control port is open.
+#[derive(Debug)]
+pub struct LogFileForwarder {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<Result<(), Error>>,
+}
+
+impl LogFileForwarder {
+    /// Forward the lines appended to `path` to the `log` crate
+    ///
+    /// The lines already in the file are skipped, and the file doesn't have to exist yet.
+    pub fn start<P: Into<PathBuf>>(path: P) -> LogFileForwarder {
+        LogFileForwarder::start_with(path, |message| message.forward())
+    }
+
+    /// Call `callback` for every line appended to `path`
+    pub fn start_with<P, F>(path: P, mut callback: F) -> LogFileForwarder
+    where
+        P: Into<PathBuf>,
+        F: FnMut(LogMessage) + Send + 'static,
+    {
+        let path = path.into();
+        let stop = Arc::new(AtomicBool::new(false));
+        let offset = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || follow_file(path, offset, &stop, &mut callback))
+        };
+
+        LogFileForwarder { stop, thread }
+    }
+
+    /// Stop following the file, after forwarding the lines already written to it
+    pub fn stop(self) -> Result<(), Error> {
+        self.stop.store(true, Ordering::SeqCst);
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("log forwarder panicked").into()))
+    }
+}
+
+fn follow_file(
+    path: PathBuf,
+    mut offset: u64,
+    stop: &AtomicBool,
+    callback: &mut dyn FnMut(LogMessage),
+) -> Result<(), Error> {
+    let mut reader = None;
+    let mut line = String::new();
+
+    loop {
+        // Check before reading, to forward everything written before stopping
+        let stopping = stop.load(Ordering::SeqCst);
+
+        // Start over if the file was truncated, e.g. with `TruncateLogFile`
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.len() < offset => {
+                reader = None;
+                offset = 0;
+                line.clear();
+            }
+            _ => {}
+        }
+
+        if reader.is_none() {
+            match File::open(&path) {
+                Ok(mut file) => {
+                    file.seek(SeekFrom::Start(offset))?;
+                    reader = Some(BufReader::new(file));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if let Some(reader) = reader.as_mut() {
+            loop {
+                let read = reader.read_line(&mut line)?;
+                offset += read as u64;
+                // Partial lines are completed on the next read
+                if read == 0 || !line.ends_with('\n') {
+                    break;
+                }
+
+                if let Some(message) = LogMessage::from_line(&line) {
+                    callback(message);
+                }
+                line.clear();
+            }
+        }
+
+        if stopping {
+            return Ok(());
+        }
+        thread::sleep(LOG_FILE_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::control::reply::fixtures::parse_all;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(
+            log_crate::Level::from(LogLevel::Notice),
+            log_crate::Level::Info
+        );
+        assert_eq!(
+            log_crate::Level::from(LogLevel::Debug),
+            log_crate::Level::Trace
+        );
+        assert_eq!(LogLevel::Err.event_name(), "ERR");
+        assert!(LogLevel::Warn > LogLevel::Notice);
+        assert_eq!(LogDomain::Circ.target(), "tor::circ");
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let events = parse_all(
+            "650 NOTICE Bootstrapped 100% (done): Done\r\n\
+             650+WARN\r\nFirst line\r\nSecond line\r\n.\r\n650 OK\r\n\
+             650 STREAM 1 NEW 0 1.2.3.4:80\r\n",
+        );
+        assert_eq!(
+            LogMessage::from_event(&events[0]),
+            Some(LogMessage {
+                level: LogLevel::Notice,
+                domains: vec![],
+                message: "Bootstrapped 100% (done): Done".into(),
+            })
+        );
+        let message = LogMessage::from_event(&events[1]).unwrap();
+        assert_eq!(message.level, LogLevel::Warn);
+        assert_eq!(message.message, "First line\nSecond line");
+        assert_eq!(message.target(), "tor");
+        assert_eq!(LogMessage::from_event(&events[2]), None);
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            LogMessage::from_line("Oct 18 12:00:00.000 [notice] {CIRC,GENERAL} Circuit built\n"),
+            Some(LogMessage {
+                level: LogLevel::Notice,
+                domains: vec![LogDomain::Circ, LogDomain::General],
+                message: "Circuit built".into(),
+            })
+        );
+        let message =
+            LogMessage::from_line("Oct 18 12:00:00.000 [warn] {not a domain} message").unwrap();
+        assert_eq!(message.level, LogLevel::Warn);
+        assert!(message.domains.is_empty());
+        assert_eq!(message.message, "{not a domain} message");
+        assert_eq!(LogMessage::from_line("not a log line"), None);
+        assert_eq!(
+            LogMessage::from_line("Oct 18 12:00:00.000 [verbose] message"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_log_file_forwarder() {
+        let path = std::env::temp_dir().join(format!("libtor-test-log-{}.log", std::process::id()));
+        std::fs::write(&path, "Oct 18 12:00:00.000 [notice] Old message\n").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let forwarder = LogFileForwarder::start_with(&path, move |m| tx.send(m).unwrap());
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"Oct 18 12:00:01.000 [warn] {NET} First\nOct 18 12:00:02.000 [err] Sec")
+            .unwrap();
+        let message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(message.message, "First");
+        assert_eq!(message.target(), "tor::net");
+
+        file.write_all(b"ond\n").unwrap();
+        forwarder.stop().unwrap();
+        let messages = rx.iter().collect::<Vec<_>>();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].level, LogLevel::Err);
+        assert_eq!(messages[0].message, "Second");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -36,6 +36,8 @@ pub enum SocksPortFlag {
     UseDNSCache,
     PreferIPv6Automap,
     PreferSOCKSNoAuth,
+    /// Report onion service failures with Tor's extended SOCKS error codes
+    ExtendedErrors,
 }
 
 /// Flags to change the isolation of clients connected to the control port
@@ -48,8 +50,56 @@ pub enum SocksPortIsolationFlag {
     IsolateDestPort,
     IsolateDestAddr,
     KeepAliveIsolateSOCKSAuth,
+    /// Disable `IsolateSOCKSAuth`, which is enabled by default
+    NoIsolateSOCKSAuth,
 }
 
 display_like_debug!(ControlPortFlag);
 display_like_debug!(SocksPortFlag);
 display_like_debug!(SocksPortIsolationFlag);
+
+from_str_like_debug!(
+    ControlPortFlag,
+    [
+        #[cfg(target_family = "unix")]
+        GroupWritable,
+        #[cfg(target_family = "unix")]
+        WorldWritable,
+        #[cfg(target_family = "unix")]
+        RelaxDirModeCheck,
+    ]
+);
+from_str_like_debug!(
+    SocksPortFlag,
+    [
+        NoIPv4Traffic,
+        IPv6Traffic,
+        PreferIPv6,
+        NoDNSRequest,
+        NoOnionTraffic,
+        OnionTrafficOnly,
+        CacheIPv4DNS,
+        CacheIPv6DNS,
+        GroupWritable,
+        WorldWritable,
+        CacheDNS,
+        UseIPv4Cache,
+        UseIPv6Cache,
+        UseDNSCache,
+        PreferIPv6Automap,
+        PreferSOCKSNoAuth,
+        ExtendedErrors,
+    ]
+);
+from_str_like_debug!(
+    SocksPortIsolationFlag,
+    [
+        IsolateClientAddr,
+        IsolateSOCKSAuth,
+        IsolateClientProtocol,
+        IsolateDestPort,
+        IsolateDestAddr,
+        KeepAliveIsolateSOCKSAuth,
+        NoIsolateSOCKSAuth,
+    ]
+);
@@ -0,0 +1,337 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(target_family = "unix")]
+use tokio::net::UnixStream;
+
+use log_crate::debug;
+
+use super::{Handshake, SocksAuth, Step, TargetAddr, ToTargetAddr, COMMAND_CONNECT};
+use crate::control::ListenerAddr;
+use crate::{Error, TorHandle};
+
+#[derive(Debug)]
+enum AsyncStream {
+    Tcp(TcpStream),
+    #[cfg(target_family = "unix")]
+    Unix(UnixStream),
+}
+
+impl AsyncStream {
+    async fn connect(addr: &ListenerAddr) -> io::Result<AsyncStream> {
+        match addr {
+            ListenerAddr::Tcp(addr) => TcpStream::connect(addr).await.map(AsyncStream::Tcp),
+            #[cfg(target_family = "unix")]
+            ListenerAddr::Unix(path) => UnixStream::connect(path).await.map(AsyncStream::Unix),
+        }
+    }
+}
+
+/// Run the whole SOCKS5 handshake for `command`, returning the address in the reply
+///
+/// Runs the same [`Handshake`] as the blocking client, only the I/O differs
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    command: u8,
+    target: &TargetAddr,
+    auth: Option<&SocksAuth>,
+) -> Result<TargetAddr, Error> {
+    let (mut handshake, mut step) = Handshake::start(command, target, auth);
+    loop {
+        match step {
+            Step::Exchange { send, read } => {
+                stream.write_all(&send).await?;
+                let mut data = vec![0; read];
+                stream.read_exact(&mut data).await?;
+                step = handshake.receive(&data)?;
+            }
+            Step::Done(address) => return Ok(address),
+        }
+    }
+}
+
+/// Send a single request for `command`, which doesn't open a stream
+pub(super) async fn socks_command(
+    proxy: &ListenerAddr,
+    command: u8,
+    target: &TargetAddr,
+    auth: Option<&SocksAuth>,
+) -> Result<TargetAddr, Error> {
+    let mut stream = AsyncStream::connect(proxy).await?;
+    handshake(&mut stream, command, target, auth).await
+}
+
+/// Connection to a remote host through Tor's SOCKS port, built on `tokio`
+///
+/// ```no_run
+/// # use libtor::socks::AsyncTorStream;
+/// # use libtor::{Tor, TorFlag};
+/// # use tokio::io::AsyncWriteExt;
+/// # async fn example() -> Result<(), libtor::Error> {
+/// let handle = Tor::new().flag(TorFlag::SocksPort(19050)).spawn()?;
+/// let mut stream = AsyncTorStream::connect(&handle, "example.org:80").await?;
+/// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AsyncTorStream {
+    stream: AsyncStream,
+    target: TargetAddr,
+    bound_addr: TargetAddr,
+}
+
+impl AsyncTorStream {
+    /// Connect to `target` through the SOCKS port of a running daemon, see
+    /// [`TorHandle::async_socks_addr`]
+    pub async fn connect<T: ToTargetAddr>(
+        handle: &TorHandle,
+        target: T,
+    ) -> Result<AsyncTorStream, Error> {
+        let proxy = handle.async_socks_addr().await?;
+        AsyncTorStream::connect_proxy(&proxy, target, None).await
+    }
+
+    /// Connect to `target` through the SOCKS port of a running daemon, authenticating with
+    /// `auth`
+    ///
+    /// With `IsolateSOCKSAuth`, streams opened with different credentials never share a
+    /// circuit.
+    pub async fn connect_with_auth<T: ToTargetAddr>(
+        handle: &TorHandle,
+        target: T,
+        auth: &SocksAuth,
+    ) -> Result<AsyncTorStream, Error> {
+        let proxy = handle.async_socks_addr().await?;
+        AsyncTorStream::connect_proxy(&proxy, target, Some(auth)).await
+    }
+
+    /// Connect to `target` through the SOCKS proxy at `proxy`, optionally authenticating
+    /// with `auth`
+    pub async fn connect_proxy<T: ToTargetAddr>(
+        proxy: &ListenerAddr,
+        target: T,
+        auth: Option<&SocksAuth>,
+    ) -> Result<AsyncTorStream, Error> {
+        let target = target.to_target_addr()?;
+        debug!(
+            "Connecting to {} through the SOCKS proxy at {}",
+            target, proxy
+        );
+
+        let mut stream = AsyncStream::connect(proxy).await?;
+        let bound_addr = handshake(&mut stream, COMMAND_CONNECT, &target, auth).await?;
+
+        Ok(AsyncTorStream {
+            stream,
+            target,
+            bound_addr,
+        })
+    }
+
+    /// Destination of the connection
+    pub fn target(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Address returned by the proxy in its reply, which Tor always sets to `0.0.0.0:0`
+    pub fn bound_addr(&self) -> &TargetAddr {
+        &self.bound_addr
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(target_family = "unix")]
+            AsyncStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(target_family = "unix")]
+            AsyncStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(target_family = "unix")]
+            AsyncStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(target_family = "unix")]
+            AsyncStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for AsyncTorStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncTorStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::FakeSocksServer;
+    use std::net::IpAddr;
+
+    use super::super::{SocksError, TorStreamBuilder};
+    use super::*;
+
+    const BOUND_IPV4: &[u8] = &[0x01, 0, 0, 0, 0, 0, 0];
+
+    /// Blocking stream replaying `input` and recording what is written to it
+    struct Scripted<'a> {
+        input: &'a [u8],
+        output: Vec<u8>,
+    }
+
+    impl std::io::Read for Scripted<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::io::Read::read(&mut self.input, buf)
+        }
+    }
+
+    impl std::io::Write for Scripted<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            std::io::Write::write(&mut self.output, buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_matches_blocking() {
+        let target = TargetAddr::Domain("xyz.onion".into(), 80);
+        let auth = SocksAuth::new("user", "isolation");
+        let cases = [
+            (
+                None,
+                [&[0x05, 0x00][..], &[0x05, 0x00, 0x00], BOUND_IPV4].concat(),
+            ),
+            (
+                Some(&auth),
+                [
+                    &[0x05, 0x02][..],
+                    &[0x01, 0x00],
+                    &[0x05, 0x00, 0x00],
+                    BOUND_IPV4,
+                ]
+                .concat(),
+            ),
+        ];
+
+        for (auth, replies) in cases {
+            let mut blocking = Scripted {
+                input: &replies,
+                output: vec![],
+            };
+            let blocking_reply =
+                super::super::handshake(&mut blocking, COMMAND_CONNECT, &target, auth).unwrap();
+
+            let mut stream = tokio::io::join(&replies[..], vec![]);
+            let async_reply = handshake(&mut stream, COMMAND_CONNECT, &target, auth)
+                .await
+                .unwrap();
+            let (unread, written) = stream.into_inner();
+
+            assert_eq!(async_reply, blocking_reply);
+            assert_eq!(written, blocking.output);
+            assert!(unread.is_empty() && blocking.input.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_proxy() {
+        let server = FakeSocksServer::new(0x00, BOUND_IPV4);
+        let proxy = ListenerAddr::Tcp(server.addr());
+        let auth = SocksAuth::new("user", "isolation");
+
+        let mut stream = AsyncTorStream::connect_proxy(&proxy, "xyz.onion:80", Some(&auth))
+            .await
+            .unwrap();
+        assert_eq!(stream.target(), &TargetAddr::Domain("xyz.onion".into(), 80));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(stream);
+
+        let received = server.join();
+        assert_eq!(received.auth, Some(auth));
+        assert_eq!(
+            received.target,
+            Some(TargetAddr::Domain("xyz.onion".into(), 80))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_async() {
+        let server = FakeSocksServer::new(0x00, &[0x01, 192, 0, 2, 1, 0, 0]);
+        let builder = TorStreamBuilder::new(ListenerAddr::Tcp(server.addr()));
+
+        let ip = builder.resolve_async("example.org").await.unwrap();
+        assert_eq!(ip, IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(server.join().command, 0xF0);
+    }
+
+    #[tokio::test]
+    async fn test_connect_error() {
+        let server = FakeSocksServer::new(0xF6, BOUND_IPV4);
+        let proxy = ListenerAddr::Tcp(server.addr());
+
+        match AsyncTorStream::connect_proxy(&proxy, ("invalid.onion", 80), None).await {
+            Err(Error::Socks(SocksError::OnionServiceInvalidAddress)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        server.join();
+    }
+}
@@ -0,0 +1,1259 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use data_encoding::HEXLOWER;
+use rand::RngCore;
+
+use log_crate::{debug, warn};
+
+use crate::control::{ListenerAddr, Stream};
+use crate::{Error, Expand, SocksPortFlag, SocksPortIsolationFlag, Tor, TorAddress, TorFlag};
+
+/// Asynchronous SOCKS client
+#[cfg(feature = "tokio")]
+mod async_client;
+#[cfg(test)]
+mod testing;
+
+#[cfg(feature = "tokio")]
+pub use self::async_client::AsyncTorStream;
+
+/// Port used by Tor when no `SocksPort` is configured
+const DEFAULT_SOCKS_PORT: u16 = 9050;
+
+/// SOCKS username sent with the [`IsolationToken`]s
+const ISOLATION_USERNAME: &str = "libtor-isolation";
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+
+const COMMAND_CONNECT: u8 = 0x01;
+/// Tor extension to resolve a hostname
+const COMMAND_RESOLVE: u8 = 0xF0;
+/// Tor extension to look up the hostname of an IP address
+const COMMAND_RESOLVE_PTR: u8 = 0xF1;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+/// Failure reported by the SOCKS proxy
+///
+/// The `OnionService*` errors are only reported by Tor when the SOCKS port is configured with
+/// [`SocksPortFlag::ExtendedErrors`](crate::SocksPortFlag::ExtendedErrors).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SocksError {
+    /// The proxy doesn't accept any of the offered authentication methods
+    NoAcceptableMethod,
+    /// The proxy rejected the username and password
+    AuthenticationFailed,
+    GeneralFailure,
+    NotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    /// The descriptor of the onion service can't be found
+    OnionServiceNotFound,
+    /// The descriptor of the onion service was found but is invalid
+    OnionServiceInvalidDescriptor,
+    /// All the introduction points of the onion service failed
+    OnionServiceIntroFailed,
+    /// The rendezvous with the onion service failed
+    OnionServiceRendezvousFailed,
+    /// The onion service requires client authorization, and no key is configured for it
+    OnionServiceMissingClientAuth,
+    /// The client authorization key configured for the onion service is wrong
+    OnionServiceWrongClientAuth,
+    /// The onion address is invalid
+    OnionServiceInvalidAddress,
+    /// All the introduction points of the onion service timed out
+    OnionServiceIntroTimedOut,
+    /// Reply code not defined by RFC 1928 or Tor
+    Unknown(u8),
+    /// The proxy sent a reply that couldn't be parsed
+    InvalidReply(String),
+    /// The SOCKS port is configured with `NoDNSRequest`, so hostnames can't be resolved
+    NoDnsRequest,
+}
+
+impl SocksError {
+    /// Map the reply code of a SOCKS5 request, `None` for success
+    pub fn from_code(code: u8) -> Option<SocksError> {
+        Some(match code {
+            0x00 => return None,
+            0x01 => SocksError::GeneralFailure,
+            0x02 => SocksError::NotAllowed,
+            0x03 => SocksError::NetworkUnreachable,
+            0x04 => SocksError::HostUnreachable,
+            0x05 => SocksError::ConnectionRefused,
+            0x06 => SocksError::TtlExpired,
+            0x07 => SocksError::CommandNotSupported,
+            0x08 => SocksError::AddressTypeNotSupported,
+            0xF0 => SocksError::OnionServiceNotFound,
+            0xF1 => SocksError::OnionServiceInvalidDescriptor,
+            0xF2 => SocksError::OnionServiceIntroFailed,
+            0xF3 => SocksError::OnionServiceRendezvousFailed,
+            0xF4 => SocksError::OnionServiceMissingClientAuth,
+            0xF5 => SocksError::OnionServiceWrongClientAuth,
+            0xF6 => SocksError::OnionServiceInvalidAddress,
+            0xF7 => SocksError::OnionServiceIntroTimedOut,
+            code => SocksError::Unknown(code),
+        })
+    }
+
+    /// Whether the error is one of Tor's extended errors about onion services
+    pub fn is_onion_service_error(&self) -> bool {
+        matches!(
+            self,
+            SocksError::OnionServiceNotFound
+                | SocksError::OnionServiceInvalidDescriptor
+                | SocksError::OnionServiceIntroFailed
+                | SocksError::OnionServiceRendezvousFailed
+                | SocksError::OnionServiceMissingClientAuth
+                | SocksError::OnionServiceWrongClientAuth
+                | SocksError::OnionServiceInvalidAddress
+                | SocksError::OnionServiceIntroTimedOut
+        )
+    }
+}
+
+impl std::fmt::Display for SocksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SocksError::NoAcceptableMethod => write!(f, "No acceptable authentication method"),
+            SocksError::AuthenticationFailed => write!(f, "Authentication failed"),
+            SocksError::GeneralFailure => write!(f, "General failure"),
+            SocksError::NotAllowed => write!(f, "Connection not allowed by ruleset"),
+            SocksError::NetworkUnreachable => write!(f, "Network unreachable"),
+            SocksError::HostUnreachable => write!(f, "Host unreachable"),
+            SocksError::ConnectionRefused => write!(f, "Connection refused"),
+            SocksError::TtlExpired => write!(f, "TTL expired"),
+            SocksError::CommandNotSupported => write!(f, "Command not supported"),
+            SocksError::AddressTypeNotSupported => write!(f, "Address type not supported"),
+            SocksError::OnionServiceNotFound => write!(f, "Onion service descriptor not found"),
+            SocksError::OnionServiceInvalidDescriptor => {
+                write!(f, "Onion service descriptor is invalid")
+            }
+            SocksError::OnionServiceIntroFailed => {
+                write!(f, "Onion service introduction failed")
+            }
+            SocksError::OnionServiceRendezvousFailed => {
+                write!(f, "Onion service rendezvous failed")
+            }
+            SocksError::OnionServiceMissingClientAuth => {
+                write!(f, "Onion service requires client authorization")
+            }
+            SocksError::OnionServiceWrongClientAuth => {
+                write!(f, "Onion service client authorization is wrong")
+            }
+            SocksError::OnionServiceInvalidAddress => write!(f, "Invalid onion address"),
+            SocksError::OnionServiceIntroTimedOut => {
+                write!(f, "Onion service introduction timed out")
+            }
+            SocksError::Unknown(code) => write!(f, "Unknown reply code {:#04x}", code),
+            SocksError::InvalidReply(message) => write!(f, "Invalid reply: {}", message),
+            SocksError::NoDnsRequest => {
+                write!(
+                    f,
+                    "DNS requests are disabled on the SOCKS port with NoDNSRequest"
+                )
+            }
+        }
+    }
+}
+
+/// Destination of a SOCKS connection
+///
+/// Hostnames are sent as is, so that they are resolved by Tor at the exit relay, which is
+/// also required to reach `.onion` addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Port of the destination
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    /// Encode the address in the `ATYP | ADDR | PORT` format of SOCKS5 requests
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ADDRESS_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ADDRESS_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Domain(domain, _) => {
+                if domain.is_empty() || domain.len() > 255 {
+                    return Err(Error::InvalidValue {
+                        kind: "TargetAddr".into(),
+                        value: domain.clone(),
+                    });
+                }
+                buf.push(ADDRESS_DOMAIN);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+
+        Ok(())
+    }
+
+    /// Length of an encoded address of type `address_type` without the type byte, given the
+    /// first byte of the address
+    fn encoded_len(address_type: u8, first: u8) -> Result<usize, SocksError> {
+        match address_type {
+            ADDRESS_IPV4 => Ok(4 + 2),
+            ADDRESS_IPV6 => Ok(16 + 2),
+            ADDRESS_DOMAIN => Ok(1 + first as usize + 2),
+            other => Err(SocksError::InvalidReply(format!(
+                "unknown address type {:#04x}",
+                other
+            ))),
+        }
+    }
+
+    /// Decode an address encoded without its type byte
+    fn decode(address_type: u8, bytes: &[u8]) -> Result<TargetAddr, SocksError> {
+        let (address, port) = bytes.split_at(bytes.len() - 2);
+        let port = u16::from_be_bytes([port[0], port[1]]);
+
+        match address_type {
+            ADDRESS_IPV4 => {
+                let octets: [u8; 4] = address.try_into().expect("length checked");
+                Ok(TargetAddr::Ip((Ipv4Addr::from(octets), port).into()))
+            }
+            ADDRESS_IPV6 => {
+                let octets: [u8; 16] = address.try_into().expect("length checked");
+                Ok(TargetAddr::Ip((Ipv6Addr::from(octets), port).into()))
+            }
+            _ => String::from_utf8(address[1..].to_vec())
+                .map(|domain| TargetAddr::Domain(domain, port))
+                .map_err(|_| SocksError::InvalidReply("domain is not valid UTF-8".into())),
+        }
+    }
+}
+
+impl std::fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+impl std::str::FromStr for TargetAddr {
+    type Err = Error;
+
+    /// Parse a `host:port` string, where the host can be an IP address or a hostname
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(TargetAddr::Ip(addr));
+        }
+
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !host.contains(':') => port
+                .parse()
+                .map(|port| TargetAddr::Domain(host.to_string(), port))
+                .map_err(|_| Error::InvalidValue {
+                    kind: "TargetAddr".into(),
+                    value: s.to_string(),
+                }),
+            _ => Err(Error::InvalidValue {
+                kind: "TargetAddr".into(),
+                value: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        TargetAddr::Ip(addr)
+    }
+}
+
+impl From<(IpAddr, u16)> for TargetAddr {
+    fn from(addr: (IpAddr, u16)) -> Self {
+        TargetAddr::Ip(addr.into())
+    }
+}
+
+impl From<(&str, u16)> for TargetAddr {
+    fn from((host, port): (&str, u16)) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => TargetAddr::Ip((ip, port).into()),
+            Err(_) => TargetAddr::Domain(host.to_string(), port),
+        }
+    }
+}
+
+impl From<(String, u16)> for TargetAddr {
+    fn from((host, port): (String, u16)) -> Self {
+        (host.as_str(), port).into()
+    }
+}
+
+/// Values that can be used as the destination of a SOCKS connection, like `"example.org:80"`
+/// or `("example.org", 80)`
+pub trait ToTargetAddr {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error>;
+}
+
+impl ToTargetAddr for TargetAddr {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        Ok(self.clone())
+    }
+}
+
+impl ToTargetAddr for SocketAddr {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        Ok(TargetAddr::Ip(*self))
+    }
+}
+
+impl ToTargetAddr for (IpAddr, u16) {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        Ok((*self).into())
+    }
+}
+
+impl ToTargetAddr for (&str, u16) {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        Ok((*self).into())
+    }
+}
+
+impl ToTargetAddr for (String, u16) {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        Ok((self.0.as_str(), self.1).into())
+    }
+}
+
+impl ToTargetAddr for str {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        self.parse()
+    }
+}
+
+impl ToTargetAddr for String {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        self.parse()
+    }
+}
+
+impl<T: ToTargetAddr + ?Sized> ToTargetAddr for &T {
+    fn to_target_addr(&self) -> Result<TargetAddr, Error> {
+        (**self).to_target_addr()
+    }
+}
+
+impl Tor {
+    /// Return the address of the first SOCKS port configured with `SocksPort` or
+    /// `SocksPortAddress`
+    ///
+    /// Like Tor, this defaults to `127.0.0.1:9050` when no SOCKS port is configured. Ports
+    /// configured with `SocksPortAuto` can't be known in advance, so they are ignored.
+    pub fn socks_addr(&self) -> Option<ListenerAddr> {
+        self.socks_port().map(|(addr, _)| addr)
+    }
+
+    /// Address of the SOCKS port returned by [`Tor::socks_addr`], and its options
+    pub(crate) fn socks_port(&self) -> Option<(ListenerAddr, PortOptions)> {
+        let mut configured = false;
+        for flag in &self.flags {
+            let addr = match flag {
+                TorFlag::SocksPort(port) => {
+                    ListenerAddr::from_tor_address(&TorAddress::Port(*port))
+                }
+                TorFlag::SocksPortAddress(address, _, _) => ListenerAddr::from_tor_address(address),
+                TorFlag::SocksPortAuto => None,
+                _ => continue,
+            };
+            configured = true;
+            if let Some(addr) = addr {
+                return Some((addr, PortOptions::from_flag(flag)));
+            }
+        }
+
+        match configured {
+            true => None,
+            false => Some((
+                ListenerAddr::Tcp(([127, 0, 0, 1], DEFAULT_SOCKS_PORT).into()),
+                PortOptions::default(),
+            )),
+        }
+    }
+}
+
+/// Options of a SOCKS port that change what the client can do with it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PortOptions {
+    /// `IsolateSOCKSAuth` is enabled by default, so only `NoIsolateSOCKSAuth` disables it
+    isolates_auth: bool,
+    /// Disabled by `NoDNSRequest`
+    dns_requests: bool,
+}
+
+impl Default for PortOptions {
+    fn default() -> Self {
+        PortOptions {
+            isolates_auth: true,
+            dns_requests: true,
+        }
+    }
+}
+
+impl PortOptions {
+    fn from_flag(flag: &TorFlag) -> PortOptions {
+        let expanded = flag.expand();
+        let words = expanded
+            .iter()
+            .skip(1)
+            .flat_map(|value| value.split_whitespace())
+            .collect::<Vec<_>>();
+        let has = |option: &dyn std::fmt::Display| words.contains(&option.to_string().as_str());
+
+        PortOptions {
+            isolates_auth: !has(&SocksPortIsolationFlag::NoIsolateSOCKSAuth),
+            dns_requests: !has(&SocksPortFlag::NoDNSRequest),
+        }
+    }
+}
+
+/// Token to isolate streams from each other
+///
+/// The token is sent as SOCKS credentials, so with `IsolateSOCKSAuth` (enabled by default on
+/// every SOCKS port) streams opened with different tokens never share a circuit, while
+/// streams opened with the same token can.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IsolationToken {
+    id: String,
+}
+
+impl IsolationToken {
+    /// Generate a new random token
+    pub fn new() -> IsolationToken {
+        let mut id = [0; 16];
+        rand::rngs::OsRng.fill_bytes(&mut id);
+
+        IsolationToken {
+            id: HEXLOWER.encode(&id),
+        }
+    }
+
+    /// Credentials sent to the SOCKS port for this token
+    pub fn auth(&self) -> SocksAuth {
+        SocksAuth::new(ISOLATION_USERNAME, self.id.as_str())
+    }
+}
+
+impl Default for IsolationToken {
+    fn default() -> Self {
+        IsolationToken::new()
+    }
+}
+
+/// Builder for connections through a SOCKS port, aware of how the port is configured
+///
+/// ```no_run
+/// # use libtor::socks::{IsolationToken, TorStreamBuilder};
+/// # use libtor::{Tor, TorFlag};
+/// let tor = Tor::new().flag(TorFlag::SocksPort(19050)).clone();
+/// let token = IsolationToken::new();
+/// let stream = TorStreamBuilder::from_config(&tor)
+///     .unwrap()
+///     .isolation(&token)
+///     .connect("example.org:80")
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct TorStreamBuilder {
+    proxy: ListenerAddr,
+    options: PortOptions,
+    auth: Option<SocksAuth>,
+}
+
+impl TorStreamBuilder {
+    /// Connect through the SOCKS port at `proxy`, assumed to use the default flags
+    pub fn new(proxy: ListenerAddr) -> TorStreamBuilder {
+        TorStreamBuilder {
+            proxy,
+            options: PortOptions::default(),
+            auth: None,
+        }
+    }
+
+    /// Connect through the SOCKS port configured in `tor`, see [`Tor::socks_addr`]
+    pub fn from_config(tor: &Tor) -> Result<TorStreamBuilder, Error> {
+        let (proxy, options) = tor.socks_port().ok_or(Error::NoSocksPort)?;
+
+        Ok(TorStreamBuilder {
+            proxy,
+            options,
+            auth: None,
+        })
+    }
+
+    /// Send raw credentials to the proxy
+    pub fn auth(&mut self, auth: SocksAuth) -> &mut TorStreamBuilder {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Isolate the connections from the ones opened with any other token
+    ///
+    /// A warning is logged if the SOCKS port is configured with `NoIsolateSOCKSAuth`, since
+    /// Tor would then ignore the token.
+    pub fn isolation(&mut self, token: &IsolationToken) -> &mut TorStreamBuilder {
+        if !self.options.isolates_auth {
+            warn!(
+                "Isolation requested on the SOCKS port {}, which is configured with {}",
+                self.proxy,
+                SocksPortIsolationFlag::NoIsolateSOCKSAuth
+            );
+        }
+
+        self.auth(token.auth())
+    }
+
+    /// Connect to `target`
+    pub fn connect<T: ToTargetAddr>(&self, target: T) -> Result<TorStream, Error> {
+        TorStream::connect_inner(&self.proxy, target.to_target_addr()?, self.auth.as_ref())
+    }
+
+    /// Connect to `target` with an async stream
+    #[cfg(feature = "tokio")]
+    pub async fn connect_async<T: ToTargetAddr>(&self, target: T) -> Result<AsyncTorStream, Error> {
+        AsyncTorStream::connect_proxy(&self.proxy, target, self.auth.as_ref()).await
+    }
+
+    fn check_dns_requests(&self) -> Result<(), SocksError> {
+        match self.options.dns_requests {
+            true => Ok(()),
+            false => Err(SocksError::NoDnsRequest),
+        }
+    }
+
+    /// Resolve `hostname` through Tor with the `RESOLVE` extension, without opening a stream
+    ///
+    /// Fails with [`SocksError::NoDnsRequest`] if the SOCKS port is configured with
+    /// `NoDNSRequest`.
+    pub fn resolve(&self, hostname: &str) -> Result<IpAddr, Error> {
+        self.check_dns_requests()?;
+        let reply = socks_command(
+            &self.proxy,
+            COMMAND_RESOLVE,
+            &resolve_target(hostname),
+            self.auth.as_ref(),
+        )?;
+        Ok(resolved_ip(reply)?)
+    }
+
+    /// Look up the hostname of `ip` through Tor with the `RESOLVE_PTR` extension
+    ///
+    /// Fails with [`SocksError::NoDnsRequest`] if the SOCKS port is configured with
+    /// `NoDNSRequest`.
+    pub fn resolve_ptr(&self, ip: IpAddr) -> Result<String, Error> {
+        self.check_dns_requests()?;
+        let reply = socks_command(
+            &self.proxy,
+            COMMAND_RESOLVE_PTR,
+            &TargetAddr::Ip((ip, 0).into()),
+            self.auth.as_ref(),
+        )?;
+        Ok(resolved_hostname(reply)?)
+    }
+
+    /// Resolve `hostname` with an async connection, see [`TorStreamBuilder::resolve`]
+    #[cfg(feature = "tokio")]
+    pub async fn resolve_async(&self, hostname: &str) -> Result<IpAddr, Error> {
+        self.check_dns_requests()?;
+        let reply = async_client::socks_command(
+            &self.proxy,
+            COMMAND_RESOLVE,
+            &resolve_target(hostname),
+            self.auth.as_ref(),
+        )
+        .await?;
+        Ok(resolved_ip(reply)?)
+    }
+
+    /// Look up the hostname of `ip` with an async connection, see
+    /// [`TorStreamBuilder::resolve_ptr`]
+    #[cfg(feature = "tokio")]
+    pub async fn resolve_ptr_async(&self, ip: IpAddr) -> Result<String, Error> {
+        self.check_dns_requests()?;
+        let reply = async_client::socks_command(
+            &self.proxy,
+            COMMAND_RESOLVE_PTR,
+            &TargetAddr::Ip((ip, 0).into()),
+            self.auth.as_ref(),
+        )
+        .await?;
+        Ok(resolved_hostname(reply)?)
+    }
+}
+
+/// Target of a `RESOLVE` request, for which Tor ignores the port
+fn resolve_target(hostname: &str) -> TargetAddr {
+    TargetAddr::Domain(hostname.to_string(), 0)
+}
+
+/// Address returned in the reply to a `RESOLVE` request
+fn resolved_ip(reply: TargetAddr) -> Result<IpAddr, SocksError> {
+    match reply {
+        TargetAddr::Ip(addr) => Ok(addr.ip()),
+        TargetAddr::Domain(domain, _) => Err(SocksError::InvalidReply(format!(
+            "expected an IP address, got {}",
+            domain
+        ))),
+    }
+}
+
+/// Hostname returned in the reply to a `RESOLVE_PTR` request
+fn resolved_hostname(reply: TargetAddr) -> Result<String, SocksError> {
+    match reply {
+        TargetAddr::Domain(domain, _) => Ok(domain),
+        TargetAddr::Ip(addr) => Err(SocksError::InvalidReply(format!(
+            "expected a hostname, got {}",
+            addr
+        ))),
+    }
+}
+
+/// Username and password sent to the proxy, as defined by RFC 1929
+///
+/// Tor doesn't check them, but with `IsolateSOCKSAuth` streams with different credentials
+/// never share a circuit.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SocksAuth {
+    username: String,
+    password: String,
+}
+
+impl SocksAuth {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> SocksAuth {
+        SocksAuth {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+/// Method selection message, offering only username/password when credentials are set
+fn greeting(auth: Option<&SocksAuth>) -> Vec<u8> {
+    match auth {
+        Some(_) => vec![SOCKS_VERSION, 1, METHOD_USERNAME_PASSWORD],
+        None => vec![SOCKS_VERSION, 1, METHOD_NO_AUTH],
+    }
+}
+
+/// Check the method selected by the proxy, returning whether credentials must be sent
+fn check_method(reply: [u8; 2], auth: Option<&SocksAuth>) -> Result<bool, SocksError> {
+    match reply {
+        [SOCKS_VERSION, METHOD_NO_AUTH] => Ok(false),
+        [SOCKS_VERSION, METHOD_USERNAME_PASSWORD] if auth.is_some() => Ok(true),
+        [SOCKS_VERSION, METHOD_NONE_ACCEPTABLE] => Err(SocksError::NoAcceptableMethod),
+        [SOCKS_VERSION, method] => Err(SocksError::InvalidReply(format!(
+            "method {:#04x} was not offered",
+            method
+        ))),
+        [version, _] => Err(SocksError::InvalidReply(format!(
+            "unsupported version {}",
+            version
+        ))),
+    }
+}
+
+fn auth_request(auth: &SocksAuth) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![AUTH_VERSION];
+    for field in [&auth.username, &auth.password] {
+        if field.is_empty() || field.len() > 255 {
+            return Err(Error::InvalidValue {
+                kind: "SocksAuth".into(),
+                value: field.clone(),
+            });
+        }
+        buf.push(field.len() as u8);
+        buf.extend_from_slice(field.as_bytes());
+    }
+
+    Ok(buf)
+}
+
+fn check_auth_reply(reply: [u8; 2]) -> Result<(), SocksError> {
+    match reply {
+        [AUTH_VERSION, 0x00] => Ok(()),
+        [AUTH_VERSION, _] => Err(SocksError::AuthenticationFailed),
+        [version, _] => Err(SocksError::InvalidReply(format!(
+            "unsupported authentication version {}",
+            version
+        ))),
+    }
+}
+
+fn request(command: u8, target: &TargetAddr) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![SOCKS_VERSION, command, 0x00];
+    target.encode(&mut buf)?;
+    Ok(buf)
+}
+
+/// Check the `VER | REP | RSV | ATYP` header of a reply and the first byte of its address,
+/// returning the number of bytes left to read
+fn check_reply_header(header: [u8; 5]) -> Result<usize, SocksError> {
+    if header[0] != SOCKS_VERSION {
+        return Err(SocksError::InvalidReply(format!(
+            "unsupported version {}",
+            header[0]
+        )));
+    }
+    if let Some(e) = SocksError::from_code(header[1]) {
+        return Err(e);
+    }
+
+    Ok(TargetAddr::encoded_len(header[3], header[4])? - 1)
+}
+
+/// Next step of a [`Handshake`]
+#[derive(Debug)]
+enum Step {
+    /// Write `send`, then read exactly `read` bytes and pass them to [`Handshake::receive`]
+    Exchange { send: Vec<u8>, read: usize },
+    /// The handshake succeeded, with the address in the reply of the proxy
+    Done(TargetAddr),
+}
+
+#[derive(Debug)]
+enum HandshakeState {
+    Method,
+    Auth,
+    ReplyHeader,
+    ReplyAddress([u8; 5]),
+}
+
+/// SOCKS5 handshake for a single command, as a sequence of [`Step`]s
+///
+/// It doesn't do any I/O itself, so that the blocking and `tokio` clients run the same sequence:
+/// see [`handshake`] and `async_client::handshake`.
+#[derive(Debug)]
+struct Handshake<'a> {
+    command: u8,
+    target: &'a TargetAddr,
+    auth: Option<&'a SocksAuth>,
+    state: HandshakeState,
+}
+
+impl<'a> Handshake<'a> {
+    /// Start the handshake, returning the first step
+    fn start(
+        command: u8,
+        target: &'a TargetAddr,
+        auth: Option<&'a SocksAuth>,
+    ) -> (Handshake<'a>, Step) {
+        let handshake = Handshake {
+            command,
+            target,
+            auth,
+            state: HandshakeState::Method,
+        };
+        let step = Step::Exchange {
+            send: greeting(auth),
+            read: 2,
+        };
+        (handshake, step)
+    }
+
+    /// Process the bytes read for the previous step, returning the next one
+    fn receive(&mut self, data: &[u8]) -> Result<Step, Error> {
+        match self.state {
+            HandshakeState::Method => {
+                if let (true, Some(auth)) =
+                    (check_method([data[0], data[1]], self.auth)?, self.auth)
+                {
+                    self.state = HandshakeState::Auth;
+                    return Ok(Step::Exchange {
+                        send: auth_request(auth)?,
+                        read: 2,
+                    });
+                }
+                self.request()
+            }
+            HandshakeState::Auth => {
+                check_auth_reply([data[0], data[1]])?;
+                self.request()
+            }
+            HandshakeState::ReplyHeader => {
+                let mut header = [0; 5];
+                header.copy_from_slice(data);
+                let read = check_reply_header(header)?;
+                self.state = HandshakeState::ReplyAddress(header);
+                Ok(Step::Exchange { send: vec![], read })
+            }
+            HandshakeState::ReplyAddress(header) => {
+                let mut address = vec![header[4]];
+                address.extend_from_slice(data);
+                Ok(Step::Done(TargetAddr::decode(header[3], &address)?))
+            }
+        }
+    }
+
+    fn request(&mut self) -> Result<Step, Error> {
+        self.state = HandshakeState::ReplyHeader;
+        Ok(Step::Exchange {
+            send: request(self.command, self.target)?,
+            read: 5,
+        })
+    }
+}
+
+/// Run the whole SOCKS5 handshake for `command`, returning the address in the reply
+fn handshake<S: Read + Write>(
+    stream: &mut S,
+    command: u8,
+    target: &TargetAddr,
+    auth: Option<&SocksAuth>,
+) -> Result<TargetAddr, Error> {
+    let (mut handshake, mut step) = Handshake::start(command, target, auth);
+    loop {
+        match step {
+            Step::Exchange { send, read } => {
+                stream.write_all(&send)?;
+                let mut data = vec![0; read];
+                stream.read_exact(&mut data)?;
+                step = handshake.receive(&data)?;
+            }
+            Step::Done(address) => return Ok(address),
+        }
+    }
+}
+
+/// Send a single request for `command`, which doesn't open a stream
+fn socks_command(
+    proxy: &ListenerAddr,
+    command: u8,
+    target: &TargetAddr,
+    auth: Option<&SocksAuth>,
+) -> Result<TargetAddr, Error> {
+    let mut stream = Stream::connect(proxy)?;
+    handshake(&mut stream, command, target, auth)
+}
+
+/// Connection to a remote host through Tor's SOCKS port
+///
+/// ```no_run
+/// # use std::io::Write;
+/// # use libtor::control::ListenerAddr;
+/// # use libtor::socks::TorStream;
+/// let proxy = ListenerAddr::Tcp("127.0.0.1:9050".parse().unwrap());
+/// let mut stream = TorStream::connect(&proxy, ("example.org", 80)).unwrap();
+/// stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TorStream {
+    stream: Stream,
+    target: TargetAddr,
+    bound_addr: TargetAddr,
+}
+
+impl TorStream {
+    /// Connect to `target` through the SOCKS proxy at `proxy`
+    pub fn connect<T: ToTargetAddr>(proxy: &ListenerAddr, target: T) -> Result<TorStream, Error> {
+        TorStream::connect_inner(proxy, target.to_target_addr()?, None)
+    }
+
+    /// Connect to `target` through the SOCKS proxy at `proxy`, authenticating with `auth`
+    pub fn connect_with_auth<T: ToTargetAddr>(
+        proxy: &ListenerAddr,
+        target: T,
+        auth: &SocksAuth,
+    ) -> Result<TorStream, Error> {
+        TorStream::connect_inner(proxy, target.to_target_addr()?, Some(auth))
+    }
+
+    fn connect_inner(
+        proxy: &ListenerAddr,
+        target: TargetAddr,
+        auth: Option<&SocksAuth>,
+    ) -> Result<TorStream, Error> {
+        debug!(
+            "Connecting to {} through the SOCKS proxy at {}",
+            target, proxy
+        );
+
+        let mut stream = Stream::connect(proxy)?;
+        let bound_addr = handshake(&mut stream, COMMAND_CONNECT, &target, auth)?;
+
+        Ok(TorStream {
+            stream,
+            target,
+            bound_addr,
+        })
+    }
+
+    /// Destination of the connection
+    pub fn target(&self) -> &TargetAddr {
+        &self.target
+    }
+
+    /// Address returned by the proxy in its reply, which Tor always sets to `0.0.0.0:0`
+    pub fn bound_addr(&self) -> &TargetAddr {
+        &self.bound_addr
+    }
+
+    /// Set the timeout of read operations, `None` to block indefinitely
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Set the timeout of write operations, `None` to block indefinitely
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+}
+
+impl Read for TorStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TorStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::FakeSocksServer;
+    use super::*;
+
+    const BOUND_IPV4: &[u8] = &[ADDRESS_IPV4, 0, 0, 0, 0, 0, 0];
+
+    fn proxy(server: &FakeSocksServer) -> ListenerAddr {
+        ListenerAddr::Tcp(server.addr())
+    }
+
+    #[test]
+    fn test_target_addr() {
+        assert_eq!(
+            "example.onion:80".parse::<TargetAddr>().unwrap(),
+            TargetAddr::Domain("example.onion".into(), 80)
+        );
+        assert_eq!(
+            "[::1]:443".parse::<TargetAddr>().unwrap(),
+            TargetAddr::Ip("[::1]:443".parse().unwrap())
+        );
+        assert!("example.org".parse::<TargetAddr>().is_err());
+        assert!("::1:80".parse::<TargetAddr>().is_err());
+        assert_eq!(
+            "xyz.onion:80".to_target_addr().unwrap(),
+            TargetAddr::Domain("xyz.onion".into(), 80)
+        );
+
+        assert_eq!(
+            TargetAddr::from(("127.0.0.1", 8080)),
+            TargetAddr::Ip("127.0.0.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            TargetAddr::from(("example.org".to_string(), 80)).to_string(),
+            "example.org:80"
+        );
+
+        let mut buf = vec![];
+        TargetAddr::Domain("a".repeat(256), 80)
+            .encode(&mut buf)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_socks_addr() {
+        assert_eq!(
+            Tor::new().socks_addr(),
+            Some(ListenerAddr::Tcp("127.0.0.1:9050".parse().unwrap()))
+        );
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::SocksPortAuto)
+                .flag(TorFlag::SocksPort(19050))
+                .socks_addr(),
+            Some(ListenerAddr::Tcp("127.0.0.1:19050".parse().unwrap()))
+        );
+        assert_eq!(Tor::new().flag(TorFlag::SocksPort(0)).socks_addr(), None);
+        assert_eq!(Tor::new().flag(TorFlag::SocksPortAuto).socks_addr(), None);
+        #[cfg(target_family = "unix")]
+        assert_eq!(
+            Tor::new()
+                .flag(TorFlag::SocksPortAddress(
+                    TorAddress::Unix("/tmp/socks".into()),
+                    None.into(),
+                    None.into()
+                ))
+                .socks_addr(),
+            Some(ListenerAddr::Unix("/tmp/socks".into()))
+        );
+    }
+
+    #[test]
+    fn test_isolation_token() {
+        let (a, b) = (IsolationToken::new(), IsolationToken::new());
+        assert_ne!(a, b);
+        assert_ne!(a.auth(), b.auth());
+        assert_eq!(a.auth(), a.clone().auth());
+        assert_eq!(a.auth().username(), ISOLATION_USERNAME);
+        assert_eq!(a.auth().password().len(), 32);
+    }
+
+    #[test]
+    fn test_builder_isolation() {
+        let builder =
+            TorStreamBuilder::from_config(Tor::new().flag(TorFlag::SocksPort(19050))).unwrap();
+        assert!(builder.options.isolates_auth);
+
+        let tor = Tor::new()
+            .flag(TorFlag::SocksPortAddress(
+                TorAddress::Port(19050),
+                None.into(),
+                Some(
+                    vec![
+                        SocksPortIsolationFlag::IsolateDestAddr,
+                        SocksPortIsolationFlag::NoIsolateSOCKSAuth,
+                    ]
+                    .into(),
+                )
+                .into(),
+            ))
+            .clone();
+        let builder = TorStreamBuilder::from_config(&tor).unwrap();
+        assert_eq!(
+            builder.proxy,
+            ListenerAddr::Tcp("127.0.0.1:19050".parse().unwrap())
+        );
+        assert!(!builder.options.isolates_auth);
+
+        assert!(matches!(
+            TorStreamBuilder::from_config(Tor::new().flag(TorFlag::SocksPortAuto)),
+            Err(Error::NoSocksPort)
+        ));
+    }
+
+    #[test]
+    fn test_connect_isolated() {
+        let token = IsolationToken::new();
+        let mut servers = vec![];
+        for _ in 0..2 {
+            let server = FakeSocksServer::new(0x00, BOUND_IPV4);
+            TorStreamBuilder::new(proxy(&server))
+                .isolation(&token)
+                .connect("example.org:80")
+                .unwrap();
+            servers.push(server);
+        }
+
+        let received = servers
+            .into_iter()
+            .map(|server| server.join().auth)
+            .collect::<Vec<_>>();
+        assert_eq!(received, vec![Some(token.auth()), Some(token.auth())]);
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = FakeSocksServer::new(0x00, BOUND_IPV4);
+        let mut stream = TorStream::connect(&proxy(&server), ("example.onion", 80)).unwrap();
+        assert_eq!(
+            stream.bound_addr(),
+            &TargetAddr::Ip("0.0.0.0:0".parse().unwrap())
+        );
+
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        drop(stream);
+
+        let received = server.join();
+        assert_eq!(received.methods, vec![METHOD_NO_AUTH]);
+        assert_eq!(received.auth, None);
+        assert_eq!(received.command, COMMAND_CONNECT);
+        assert_eq!(
+            received.target,
+            Some(TargetAddr::Domain("example.onion".into(), 80))
+        );
+    }
+
+    #[test]
+    fn test_connect_with_auth() {
+        let mut bound = vec![ADDRESS_IPV6];
+        bound.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bound.extend_from_slice(&9050u16.to_be_bytes());
+        let server = FakeSocksServer::new(0x00, &bound);
+
+        let auth = SocksAuth::new("user", "isolation");
+        let target: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let stream = TorStream::connect_with_auth(&proxy(&server), target, &auth).unwrap();
+        assert_eq!(stream.target(), &TargetAddr::Ip(target));
+        assert_eq!(
+            stream.bound_addr(),
+            &TargetAddr::Ip("[::1]:9050".parse().unwrap())
+        );
+        drop(stream);
+
+        let received = server.join();
+        assert_eq!(received.methods, vec![METHOD_USERNAME_PASSWORD]);
+        assert_eq!(received.auth, Some(auth));
+        assert_eq!(received.target, Some(TargetAddr::Ip(target)));
+    }
+
+    #[test]
+    fn test_authentication_failed() {
+        let server = FakeSocksServer::rejecting_auth();
+        let auth = SocksAuth::new("user", "wrong");
+        match TorStream::connect_with_auth(&proxy(&server), ("example.org", 80), &auth) {
+            Err(Error::Socks(SocksError::AuthenticationFailed)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        server.join();
+    }
+
+    #[test]
+    fn test_errors() {
+        for (code, expected) in [
+            (0x05, SocksError::ConnectionRefused),
+            (0xF0, SocksError::OnionServiceNotFound),
+            (0xF4, SocksError::OnionServiceMissingClientAuth),
+            (0x42, SocksError::Unknown(0x42)),
+        ] {
+            let server = FakeSocksServer::new(code, BOUND_IPV4);
+            match TorStream::connect(&proxy(&server), ("example.onion", 80)) {
+                Err(Error::Socks(e)) => assert_eq!(e, expected),
+                other => panic!("unexpected result: {:?}", other),
+            }
+            server.join();
+        }
+
+        assert!(SocksError::OnionServiceIntroTimedOut.is_onion_service_error());
+        assert!(!SocksError::HostUnreachable.is_onion_service_error());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let server = FakeSocksServer::new(0x00, &[ADDRESS_IPV4, 192, 0, 2, 1, 0, 0]);
+        let ip = TorStreamBuilder::new(proxy(&server))
+            .resolve("example.org")
+            .unwrap();
+        assert_eq!(ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+
+        let received = server.join();
+        assert_eq!(received.command, COMMAND_RESOLVE);
+        assert_eq!(
+            received.target,
+            Some(TargetAddr::Domain("example.org".into(), 0))
+        );
+
+        let mut bound = vec![ADDRESS_DOMAIN, 11];
+        bound.extend_from_slice(b"example.org");
+        bound.extend_from_slice(&[0, 0]);
+        let server = FakeSocksServer::new(0x00, &bound);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let hostname = TorStreamBuilder::new(proxy(&server))
+            .resolve_ptr(ip)
+            .unwrap();
+        assert_eq!(hostname, "example.org");
+
+        let received = server.join();
+        assert_eq!(received.command, COMMAND_RESOLVE_PTR);
+        assert_eq!(received.target, Some(TargetAddr::Ip((ip, 0).into())));
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let server = FakeSocksServer::new(0x04, BOUND_IPV4);
+        match TorStreamBuilder::new(proxy(&server)).resolve("nonexistent.example") {
+            Err(Error::Socks(SocksError::HostUnreachable)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        server.join();
+
+        // A hostname where an address was expected
+        let server = FakeSocksServer::new(0x00, &[ADDRESS_DOMAIN, 1, b'a', 0, 0]);
+        match TorStreamBuilder::new(proxy(&server)).resolve("example.org") {
+            Err(Error::Socks(SocksError::InvalidReply(_))) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        server.join();
+
+        let tor = Tor::new()
+            .flag(TorFlag::SocksPortAddress(
+                TorAddress::Port(19050),
+                Some(vec![SocksPortFlag::NoDNSRequest].into()).into(),
+                None.into(),
+            ))
+            .clone();
+        let builder = TorStreamBuilder::from_config(&tor).unwrap();
+        match builder.resolve("example.org") {
+            Err(Error::Socks(SocksError::NoDnsRequest)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(matches!(
+            builder.resolve_ptr([192, 0, 2, 1].into()),
+            Err(Error::Socks(SocksError::NoDnsRequest))
+        ));
+    }
+
+    #[test]
+    fn test_check_method() {
+        let auth = SocksAuth::new("user", "password");
+        assert_eq!(check_method([5, 0x00], Some(&auth)), Ok(false));
+        assert_eq!(check_method([5, 0x02], Some(&auth)), Ok(true));
+        assert!(matches!(
+            check_method([5, 0x02], None),
+            Err(SocksError::InvalidReply(_))
+        ));
+        assert_eq!(
+            check_method([5, 0xFF], None),
+            Err(SocksError::NoAcceptableMethod)
+        );
+        assert!(matches!(
+            check_method([4, 0x00], None),
+            Err(SocksError::InvalidReply(_))
+        ));
+    }
+}
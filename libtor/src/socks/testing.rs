@@ -0,0 +1,120 @@
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+use super::{SocksAuth, TargetAddr};
+
+/// What the fake server received from the client
+#[derive(Debug, Default)]
+pub(crate) struct Received {
+    pub methods: Vec<u8>,
+    pub auth: Option<SocksAuth>,
+    pub command: u8,
+    pub target: Option<TargetAddr>,
+}
+
+/// Fake SOCKS5 proxy that accepts a single connection
+///
+/// The request is answered with a fixed reply code and bound address, and on success the
+/// connection echoes everything back until the client closes it.
+pub(crate) struct FakeSocksServer {
+    addr: SocketAddr,
+    handle: JoinHandle<Received>,
+}
+
+impl FakeSocksServer {
+    /// `bound_addr` is sent as is after the reply code, starting with the address type
+    pub fn new(reply: u8, bound_addr: &[u8]) -> FakeSocksServer {
+        FakeSocksServer::start(reply, bound_addr.to_vec(), true)
+    }
+
+    /// Refuse any username and password
+    pub fn rejecting_auth() -> FakeSocksServer {
+        FakeSocksServer::start(0x00, vec![], false)
+    }
+
+    fn start(reply: u8, bound_addr: Vec<u8>, accept_auth: bool) -> FakeSocksServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Received::default();
+
+            let header = read_bytes(&mut stream, 2);
+            assert_eq!(header[0], 5);
+            received.methods = read_bytes(&mut stream, header[1] as usize);
+
+            if received.methods.contains(&0x02) {
+                stream.write_all(&[5, 0x02]).unwrap();
+                assert_eq!(read_bytes(&mut stream, 1), [1]);
+                let username = read_string(&mut stream);
+                let password = read_string(&mut stream);
+                received.auth = Some(SocksAuth::new(username, password));
+
+                if !accept_auth {
+                    stream.write_all(&[1, 0x01]).unwrap();
+                    return received;
+                }
+                stream.write_all(&[1, 0x00]).unwrap();
+            } else {
+                stream.write_all(&[5, 0x00]).unwrap();
+            }
+
+            let request = read_bytes(&mut stream, 4);
+            assert_eq!(request[0], 5);
+            received.command = request[1];
+            received.target = Some(read_target(&mut stream, request[3]));
+
+            let mut reply = vec![5, reply, 0];
+            reply.extend_from_slice(&bound_addr);
+            stream.write_all(&reply).unwrap();
+
+            if reply[1] == 0x00 {
+                io::copy(&mut stream.try_clone().unwrap(), &mut stream).unwrap();
+            }
+            received
+        });
+
+        FakeSocksServer { addr, handle }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the connection to be closed, returning what the client sent
+    pub fn join(self) -> Received {
+        self.handle.join().unwrap()
+    }
+}
+
+fn read_bytes(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+fn read_string(stream: &mut TcpStream) -> String {
+    let len = read_bytes(stream, 1)[0] as usize;
+    String::from_utf8(read_bytes(stream, len)).unwrap()
+}
+
+fn read_target(stream: &mut TcpStream, address_type: u8) -> TargetAddr {
+    let host = match address_type {
+        0x01 => {
+            let octets: [u8; 4] = read_bytes(stream, 4).try_into().unwrap();
+            std::net::Ipv4Addr::from(octets).to_string()
+        }
+        0x04 => {
+            let octets: [u8; 16] = read_bytes(stream, 16).try_into().unwrap();
+            std::net::Ipv6Addr::from(octets).to_string()
+        }
+        0x03 => read_string(stream),
+        other => panic!("unexpected address type {}", other),
+    };
+    let port = read_bytes(stream, 2);
+
+    TargetAddr::from((host, u16::from_be_bytes([port[0], port[1]])))
+}
@@ -0,0 +1,828 @@
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use log_crate::debug;
+
+use crate::control::reply::{quote, read_quoted};
+use crate::hs::write_private_file;
+use crate::utils::{keyword_starts_with, DisplayOption, DisplayVec, Joiner};
+use crate::*;
+
+/// Maximum nesting of `%include` directives, same as Tor
+const MAX_INCLUDE_DEPTH: usize = 31;
+
+/// Whether the flag can only be passed on the command line, like `-f` or `--quiet`
+fn is_command_line_only(args: &[String]) -> bool {
+    args.first().map(|k| k.starts_with('-')).unwrap_or(false)
+}
+
+/// Render a single `Keyword value` line, quoting the value if needed
+fn torrc_line(args: &[String]) -> String {
+    let keyword = args.first().map(String::as_str).unwrap_or_default();
+    let value = args.get(1).map(|v| v.trim()).unwrap_or_default();
+
+    let needs_quotes = value
+        .chars()
+        .any(|c| c.is_whitespace() || c.is_control() || c == '#' || c == '"' || c == '\\');
+    if value.is_empty() {
+        keyword.to_string()
+    } else if needs_quotes {
+        format!("{} {}", keyword, quote(value))
+    } else {
+        format!("{} {}", keyword, value)
+    }
+}
+
+impl Tor {
+    /// Start Tor with `-f` on a torrc generated at `path`, instead of passing the flags on the
+    /// command line
+    ///
+    /// The file is overwritten every time Tor is started. Flags that are only supported on the
+    /// command line, like [`TorFlag::Quiet`], are still passed as arguments.
+    pub fn torrc_file<P: Into<PathBuf>>(&mut self, path: P) -> &mut Tor {
+        self.torrc_file = Some(path.into());
+        self
+    }
+
+    /// Render the configuration as a torrc file
+    ///
+    /// Options of hidden services are grouped under their `HiddenServiceDir` at the end of the
+    /// file, and flags that are only supported on the command line are skipped.
+    pub fn to_torrc(&self) -> String {
+        let mut global = vec![];
+        let mut hidden_services = vec![];
+
+        for args in self.flags.iter().map(TorFlag::expand) {
+            match args.first().map(String::as_str) {
+                _ if is_command_line_only(&args) => {}
+                Some(keyword) if keyword.eq_ignore_ascii_case("HiddenServiceDir") => {
+                    hidden_services.push(String::new());
+                    hidden_services.push(torrc_line(&args));
+                }
+                Some(keyword) if keyword_starts_with(keyword, "HiddenService") => {
+                    hidden_services.push(torrc_line(&args))
+                }
+                _ => global.push(torrc_line(&args)),
+            }
+        }
+
+        let mut torrc = String::from("# Generated by libtor\n");
+        for line in global.into_iter().chain(hidden_services) {
+            torrc.push_str(&line);
+            torrc.push('\n');
+        }
+
+        torrc
+    }
+
+    /// Write the configuration to a torrc file
+    ///
+    /// The file may contain secrets like `HashedControlPassword`, so on Unix it's created
+    /// readable only by its owner.
+    pub fn write_torrc<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        write_private_file(path.as_ref(), self.to_torrc().as_bytes())
+    }
+
+    /// Write the torrc enabled with [`Tor::torrc_file`], if any
+    ///
+    /// Only called once the instance guard is held, so that a running daemon never sees its
+    /// torrc rewritten.
+    pub(crate) fn write_torrc_file(&self) -> Result<(), Error> {
+        if let Some(path) = &self.torrc_file {
+            debug!("Writing torrc to {}", path.display());
+            self.write_torrc(path)?;
+        }
+        Ok(())
+    }
+
+    /// Build the arguments to load the torrc at `path`
+    pub(crate) fn torrc_command_line(&self, path: &Path) -> Result<Vec<CString>, Error> {
+        let mut argv = vec![CString::new("tor").expect("No NUL bytes")];
+        push_arguments(&mut argv, &TorFlag::ConfigFile(path.display().to_string()))?;
+        for flag in self
+            .flags
+            .iter()
+            .filter(|f| is_command_line_only(&f.expand()))
+        {
+            push_arguments(&mut argv, flag)?;
+        }
+
+        Ok(argv)
+    }
+}
+
+/// Parse the content of a torrc file
+///
+/// Comments, line continuations and quoted values are handled like Tor does: a quoted value
+/// must end on the line where it starts, and supports the C escapes `\n`, `\r`, `\t`, `\"`,
+/// `\\`, `\NNN` in octal and `\xHH` in hexadecimal. Options that
+/// don't map to a typed [`TorFlag`], either because the keyword is unknown or because the
+/// value uses a syntax the enum can't represent, are returned as [`TorFlag::Custom`].
+///
+/// Relative `%include` paths are resolved from the current directory, like Tor does.
+pub fn parse(torrc: &str) -> Result<Vec<TorFlag>, Error> {
+    let mut flags = vec![];
+    parse_into(torrc, "torrc", 0, &mut flags)?;
+    Ok(flags)
+}
+
+/// Read and parse a torrc file, see [`parse`]
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<TorFlag>, Error> {
+    let mut flags = vec![];
+    read_into(path.as_ref(), 0, &mut flags)?;
+    Ok(flags)
+}
+
+fn read_into(path: &Path, depth: usize, flags: &mut Vec<TorFlag>) -> Result<(), Error> {
+    debug!("Reading torrc {}", path.display());
+    let torrc = std::fs::read_to_string(path)?;
+    parse_into(&torrc, &path.display().to_string(), depth, flags)
+}
+
+fn parse_into(
+    torrc: &str,
+    origin: &str,
+    depth: usize,
+    flags: &mut Vec<TorFlag>,
+) -> Result<(), Error> {
+    let lines = split_lines(torrc)
+        .map_err(|(number, e)| Error::InvalidTorrc(format!("{}:{}: {}", origin, number, e)))?;
+    for line in lines {
+        if line.keyword == "%include" {
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(Error::InvalidTorrc(format!(
+                    "{}:{}: too many nested %include",
+                    origin, line.number
+                )));
+            }
+            for path in expand_include(&line.value)? {
+                read_into(&path, depth + 1, flags)?;
+            }
+        } else {
+            let flag = parse_flag(&line.keyword, &line.value).unwrap_or_else(|| {
+                debug!("Parsing `{}` as a custom flag", line.keyword);
+                TorFlag::Custom(
+                    format!("{} {}", line.keyword, line.value)
+                        .trim_end()
+                        .to_string(),
+                )
+            });
+            flags.push(flag);
+        }
+    }
+
+    Ok(())
+}
+
+/// Option read from a torrc, before its value is parsed
+struct Line {
+    number: usize,
+    keyword: String,
+    value: String,
+}
+
+/// Split a torrc into options, following the format parsed by Tor in `confline.c`
+///
+/// Errors are returned with the number of the line that caused them.
+fn split_lines(torrc: &str) -> Result<Vec<Line>, (usize, &'static str)> {
+    let mut entries = vec![];
+    let mut lines = torrc.lines().enumerate().map(|(i, l)| (i + 1, l));
+
+    while let Some((number, line)) = lines.next() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let end = line
+            .find(|c: char| c.is_whitespace() || c == '#')
+            .unwrap_or(line.len());
+        let (keyword, mut rest) = line.split_at(end);
+        let mut keyword = keyword.to_string();
+        if let Some(stripped) = keyword.strip_suffix('\\') {
+            // The keyword itself is followed by a continuation
+            keyword = stripped.to_string();
+            rest = "\\";
+        }
+        let rest = rest.trim_start_matches([' ', '\t']);
+
+        let value = if rest.starts_with('"') {
+            let mut chars = rest.chars().peekable();
+            let value = read_quoted(&mut chars).map_err(|_| (number, "invalid quoted string"))?;
+            let excess = chars.collect::<String>();
+            let excess = excess.trim_start();
+            if !excess.is_empty() && !excess.starts_with('#') {
+                return Err((number, "excess data after quoted string"));
+            }
+            value
+        } else {
+            let mut value = String::new();
+            let mut part = rest;
+            let mut continuation = false;
+            loop {
+                match part.find('#') {
+                    // Before the first continuation a comment ends the value, after it a
+                    // comment only hides the rest of its line
+                    Some(i) if !continuation => {
+                        value.push_str(&part[..i]);
+                        break;
+                    }
+                    Some(i) => value.push_str(&part[..i]),
+                    None => match part.strip_suffix('\\') {
+                        Some(stripped) => {
+                            continuation = true;
+                            value.push_str(stripped);
+                        }
+                        None => {
+                            value.push_str(part);
+                            break;
+                        }
+                    },
+                }
+
+                match lines.next() {
+                    Some((_, next)) => part = next,
+                    None => break,
+                }
+            }
+            value.trim_end().to_string()
+        };
+
+        entries.push(Line {
+            number,
+            keyword,
+            value,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Whether `name` matches a pattern with `*` and `?` wildcards
+fn matches_pattern(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            matches_pattern(&pattern[1..], name)
+                || (!name.is_empty() && matches_pattern(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => matches_pattern(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches_pattern(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// List the files in `dir` in lexical order, skipping hidden files and subdirectories
+fn list_dir<F: Fn(&str) -> bool>(dir: &Path, filter: F) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if filter(&name) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Expand the value of an `%include` to the list of files to read
+///
+/// Wildcards are only supported in the last component of the path.
+fn expand_include(value: &str) -> Result<Vec<PathBuf>, Error> {
+    let path = Path::new(value);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let matches = if name.contains(['*', '?']) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let pattern = name.chars().collect::<Vec<_>>();
+        list_dir(dir, |n| {
+            (!n.starts_with('.') || name.starts_with('.'))
+                && matches_pattern(&pattern, &n.chars().collect::<Vec<_>>())
+        })?
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut files = vec![];
+    for path in matches {
+        if path.is_dir() {
+            let dir_files = list_dir(&path, |n| !n.starts_with('.'))?;
+            files.extend(dir_files.into_iter().filter(|p| p.is_file()));
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Split the first whitespace-separated word from the rest of `s`
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+fn parse_list<T, J>(s: &str) -> Option<DisplayVec<T, J>>
+where
+    T: std::str::FromStr + std::fmt::Debug + std::fmt::Display,
+    J: Joiner,
+{
+    s.split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<Vec<T>>>()
+        .map(DisplayVec::from)
+}
+
+fn non_empty<T, J>(vec: Vec<T>) -> DisplayOption<DisplayVec<T, J>>
+where
+    T: std::fmt::Debug + std::fmt::Display,
+    J: Joiner,
+{
+    if vec.is_empty() {
+        None.into()
+    } else {
+        Some(vec.into()).into()
+    }
+}
+
+fn parse_size(value: &str) -> Option<(usize, SizeUnit)> {
+    let (amount, unit) = split_word(value);
+    let unit = match unit {
+        "" => SizeUnit::Bytes,
+        unit => unit.parse().ok()?,
+    };
+
+    Some((amount.parse().ok()?, unit))
+}
+
+/// Parse a single level of a `Log` line, like `notice` or `[handshake,~net]debug`
+fn parse_log_level(s: &str) -> Option<(Vec<(bool, LogDomain)>, LogLevel)> {
+    let (domains, level) = match s.strip_prefix('[') {
+        Some(s) => s.split_once(']')?,
+        None => ("", s),
+    };
+    let domains = domains
+        .split(',')
+        .filter(|d| !d.is_empty())
+        .map(|d| match d.strip_prefix('~') {
+            Some(d) => d.parse().ok().map(|d| (false, d)),
+            None => d.parse().ok().map(|d| (true, d)),
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((domains, level.parse().ok()?))
+}
+
+fn parse_log(value: &str) -> Option<TorFlag> {
+    let mut levels = vec![];
+    let mut rest = value;
+    loop {
+        let (word, tail) = split_word(rest);
+        match parse_log_level(word) {
+            Some(level) => levels.push(level),
+            None => break,
+        }
+        rest = tail;
+    }
+
+    let destination = match rest {
+        "" => None,
+        rest => Some(rest.parse::<LogDestination>().ok()?),
+    };
+    Some(match (levels.as_slice(), destination) {
+        ([], _) => return None,
+        ([(domains, level)], None) if domains.is_empty() => TorFlag::Log(*level),
+        ([(domains, level)], Some(dest)) if domains.is_empty() => TorFlag::LogTo(*level, dest),
+        (_, Some(dest)) => TorFlag::LogExpanded(levels, dest),
+        (_, None) => return None,
+    })
+}
+
+fn parse_socks_port(value: &str) -> Option<TorFlag> {
+    if let Ok(port) = value.parse() {
+        return Some(TorFlag::SocksPort(port));
+    }
+    if value.eq_ignore_ascii_case("auto") {
+        return Some(TorFlag::SocksPortAuto);
+    }
+
+    let mut words = value.split_whitespace();
+    let address = words.next()?.parse().ok()?;
+    let (mut flags, mut isolation) = (vec![], vec![]);
+    for word in words {
+        match word.parse::<SocksPortFlag>() {
+            Ok(flag) => flags.push(flag),
+            Err(_) => isolation.push(word.parse::<SocksPortIsolationFlag>().ok()?),
+        }
+    }
+
+    Some(TorFlag::SocksPortAddress(
+        address,
+        non_empty(flags),
+        non_empty(isolation),
+    ))
+}
+
+fn parse_control_port(value: &str) -> Option<TorFlag> {
+    if let Ok(port) = value.parse() {
+        return Some(TorFlag::ControlPort(port));
+    }
+    if value.eq_ignore_ascii_case("auto") {
+        return Some(TorFlag::ControlPortAuto);
+    }
+
+    let mut words = value.split_whitespace();
+    let address = words.next()?.parse().ok()?;
+    let flags = words
+        .map(|w| w.parse::<ControlPortFlag>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(TorFlag::ControlPortAddress(address, non_empty(flags)))
+}
+
+/// Map a torrc option to the corresponding [`TorFlag`], if its value can be represented
+fn parse_flag(keyword: &str, value: &str) -> Option<TorFlag> {
+    let boolean = || value.parse::<TorBool>().ok();
+    let string = || Some(value.to_string());
+    let number = || value.parse::<usize>().ok();
+
+    Some(match keyword.to_ascii_lowercase().as_str() {
+        "bandwidthrate" => parse_size(value).map(|(n, u)| TorFlag::BandwidthRate(n, u))?,
+        "bandwidthburst" => parse_size(value).map(|(n, u)| TorFlag::BandwidthBurst(n, u))?,
+        "disablenetwork" => TorFlag::DisableNetwork(boolean()?),
+
+        "controlport" => parse_control_port(value)?,
+        #[cfg(target_family = "unix")]
+        "controlsocket" => TorFlag::ControlSocket(string()?),
+        #[cfg(target_family = "unix")]
+        "controlsocketsgroupwritable" => TorFlag::ControlSocketsGroupWritable(boolean()?),
+
+        "hashedcontrolpassword" => TorFlag::HashedControlPassword(string()?),
+        "cookieauthentication" => TorFlag::CookieAuthentication(boolean()?),
+        "cookieauthfile" => TorFlag::CookieAuthFile(string()?),
+        "cookieauthfilegroupreadable" => TorFlag::CookieAuthFileGroupReadable(boolean()?),
+        "controlportwritetofile" => TorFlag::ControlPortWriteToFile(string()?),
+        "controlportfilegroupreadable" => TorFlag::ControlPortFileGroupReadable(boolean()?),
+
+        "datadirectory" => TorFlag::DataDirectory(string()?),
+        "datadirectorygroupreadable" => TorFlag::DataDirectoryGroupReadable(boolean()?),
+        "cachedirectory" => TorFlag::CacheDirectory(string()?),
+        "cachedirectorygroupreadable" => TorFlag::CacheDirectoryGroupReadable(string()?),
+
+        "httpsproxy" => TorFlag::HTTPSProxy(string()?),
+        "httpsproxyauthenticator" => {
+            let (user, pass) = value.split_once(':')?;
+            TorFlag::HTTPSProxyAuthenticator(user.to_string(), pass.to_string())
+        }
+        "socks4proxy" => TorFlag::Socks4Proxy(string()?),
+        "socks5proxy" => TorFlag::Socks5Proxy(string()?),
+        "socks5proxyusername" => TorFlag::Socks5ProxyUsername(string()?),
+        "socks5proxypassword" => TorFlag::Socks5ProxyPassword(string()?),
+
+        "unixsocksgroupwritable" => TorFlag::UnixSocksGroupWritable(boolean()?),
+
+        "keepaliveperiod" => TorFlag::KeepalivePeriod(number()?),
+
+        "log" => parse_log(value)?,
+        "logmessagedomains" => TorFlag::LogMessageDomains(boolean()?),
+
+        "logtimegranularity" => TorFlag::LogTimeGranularity(number()?),
+        "truncatelogfile" => TorFlag::TruncateLogFile(boolean()?),
+        "syslogidentitytag" => TorFlag::SyslogIdentityTag(string()?),
+        "androididentitytag" => TorFlag::AndroidIdentityTag(string()?),
+        "safelogging" => TorFlag::SafeLogging(boolean()?),
+
+        "pidfile" => TorFlag::PidFile(string()?),
+        "protocolwarnings" => TorFlag::ProtocolWarnings(boolean()?),
+
+        "user" => TorFlag::User(string()?),
+        "noexec" => TorFlag::NoExec(boolean()?),
+
+        "bridge" => {
+            let (first, rest) = split_word(value);
+            let (second, third) = split_word(rest);
+            if third.is_empty() {
+                return None;
+            }
+            TorFlag::Bridge(first.to_string(), second.to_string(), third.to_string())
+        }
+
+        "connectionpadding" => TorFlag::ConnectionPadding(boolean()?),
+        "reducedconnectionpadding" => TorFlag::ReducedConnectionPadding(boolean()?),
+        "circuitpadding" => TorFlag::CircuitPadding(boolean()?),
+        "reducedcircuitpadding" => TorFlag::ReducedCircuitPadding(boolean()?),
+
+        "excludenodes" => TorFlag::ExcludeNodes(parse_list(value)?),
+        "excludeexitnodes" => TorFlag::ExcludeExitNodes(parse_list(value)?),
+        "exitnodes" => TorFlag::ExitNodes(parse_list(value)?),
+        "middlenodes" => TorFlag::MiddleNodes(parse_list(value)?),
+        "entrynodes" => TorFlag::EntryNodes(parse_list(value)?),
+        "strictnodes" => TorFlag::StrictNodes(boolean()?),
+
+        "fascistfirewall" => TorFlag::FascistFirewall(boolean()?),
+        "firewallports" => TorFlag::FirewallPorts(parse_list(value)?),
+
+        "mapaddress" => match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [from, to] => TorFlag::MapAddress(from.to_string(), to.to_string()),
+            _ => return None,
+        },
+        "newcircuitperiod" => TorFlag::NewCircuitPeriod(number()?),
+
+        "socksport" => parse_socks_port(value)?,
+        "sockstimeout" => TorFlag::SocksTimeout(number()?),
+        "safesocks" => TorFlag::SafeSocks(boolean()?),
+        "testsocks" => TorFlag::TestSocks(boolean()?),
+
+        "updatebridgesfromauthority" => TorFlag::UpdateBridgesFromAuthority(boolean()?),
+        "usebridges" => TorFlag::UseBridges(boolean()?),
+
+        "hiddenservicedir" => TorFlag::HiddenServiceDir(string()?),
+        "hiddenserviceport" => {
+            let (port, target) = split_word(value);
+            let target = match target {
+                "" => None,
+                target => Some(target.parse().ok()?),
+            };
+            TorFlag::HiddenServicePort(port.parse().ok()?, target.into())
+        }
+        "hiddenserviceversion" => TorFlag::HiddenServiceVersion(value.parse().ok()?),
+        #[cfg(feature = "legacy-v2")]
+        "hiddenserviceauthorizeclient" => {
+            let (auth_type, clients) = split_word(value);
+            TorFlag::HiddenServiceAuthorizeClient(auth_type.parse().ok()?, parse_list(clients)?)
+        }
+        "hiddenserviceallowunknownports" => TorFlag::HiddenServiceAllowUnknownPorts(boolean()?),
+        "hiddenservicemaxstreams" => TorFlag::HiddenServiceMaxStreams(number()?),
+        "hiddenservicemaxstreamsclosecircuit" => {
+            TorFlag::HiddenServiceMaxStreamsCloseCircuit(boolean()?)
+        }
+
+        "clientonionauthdir" => TorFlag::ClientOnionAuthDir(string()?),
+
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_torrc() {
+        let torrc = Tor::new()
+            .flag(TorFlag::DataDirectory("/tmp/tor rust".into()))
+            .flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs-1".into()))
+            .flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(80),
+                Some(TorAddress::AddressPort("127.0.0.1".into(), 8080)).into(),
+            ))
+            .flag(TorFlag::SocksPort(19050))
+            .flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(443),
+                None.into(),
+            ))
+            .flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs-2".into()))
+            .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
+            .flag(TorFlag::LogTo(LogLevel::Notice, LogDestination::Stdout))
+            .flag(TorFlag::Custom("Nickname libtor#1".into()))
+            .flag(TorFlag::Quiet())
+            .to_torrc();
+
+        assert_eq!(
+            torrc,
+            concat!(
+                "# Generated by libtor\n",
+                "DataDirectory \"/tmp/tor rust\"\n",
+                "SocksPort 19050\n",
+                "Log \"notice stdout\"\n",
+                "Nickname \"libtor#1\"\n",
+                "\n",
+                "HiddenServiceDir /tmp/tor-rust/hs-1\n",
+                "HiddenServicePort \"80 127.0.0.1:8080\"\n",
+                "HiddenServicePort 443\n",
+                "\n",
+                "HiddenServiceDir /tmp/tor-rust/hs-2\n",
+                "HiddenServiceVersion 3\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_to_torrc_case_insensitive() {
+        let torrc = Tor::new()
+            .flag(TorFlag::Custom("hiddenservicedir /tmp/tor-rust/hs".into()))
+            .flag(TorFlag::SocksPort(19050))
+            .flag(TorFlag::Custom("hiddenserviceport 80".into()))
+            .to_torrc();
+
+        assert_eq!(
+            torrc,
+            concat!(
+                "# Generated by libtor\n",
+                "SocksPort 19050\n",
+                "\n",
+                "hiddenservicedir /tmp/tor-rust/hs\n",
+                "hiddenserviceport 80\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_torrc_escape() {
+        assert_eq!(
+            torrc_line(&["Nickname".into(), "a\"b\\c\nd".into()]),
+            "Nickname \"a\\\"b\\\\c\\nd\""
+        );
+        assert_eq!(torrc_line(&["ClientOnly".into()]), "ClientOnly");
+    }
+
+    #[test]
+    fn test_torrc_command_line() {
+        let path = std::env::temp_dir().join(format!("libtor-test-{}.torrc", std::process::id()));
+        let tor = Tor::new()
+            .flag(TorFlag::SocksPort(19050))
+            .flag(TorFlag::Hush())
+            .torrc_file(&path)
+            .clone();
+        let argv = tor.command_line().unwrap();
+        assert!(!path.exists());
+
+        let expected = vec![
+            "tor".to_string(),
+            "-f".into(),
+            path.display().to_string(),
+            "--hush".into(),
+        ];
+        assert_eq!(
+            argv.into_iter()
+                .map(|s| s.into_string().unwrap())
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        tor.write_torrc_file().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# Generated by libtor\nSocksPort 19050\n"
+        );
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        let flags = parse(concat!(
+            "# A comment\n",
+            "  SocksPort 9050 # trailing comment\n",
+            "\n",
+            "datadirectory \"/tmp/tor rust\"\n",
+            "ExitNodes {de},\\\n",
+            "# hidden by the continuation\n",
+            "  {fr}\n",
+            "Nickname \"a\\\"b\\x21\"\n",
+            "UnknownOption  some value \r\n",
+            "HiddenServicePort 80 127.0.0.1:8080\n",
+            "Log [handshake]debug [~net]info notice file /tmp/tor.log\n",
+        ))
+        .unwrap();
+
+        assert_eq!(flags.len(), 7);
+        assert!(matches!(flags[0], TorFlag::SocksPort(9050)));
+        assert!(matches!(&flags[1], TorFlag::DataDirectory(dir) if dir == "/tmp/tor rust"));
+        assert!(matches!(&flags[2], TorFlag::ExitNodes(nodes) if nodes.to_string() == "{de},{fr}"));
+        assert!(matches!(&flags[3], TorFlag::Custom(s) if s == "Nickname a\"b!"));
+        assert!(matches!(&flags[4], TorFlag::Custom(s) if s == "UnknownOption some value"));
+        assert!(matches!(
+            &flags[5],
+            TorFlag::HiddenServicePort(TorAddress::Port(80), target)
+                if target.to_string() == "127.0.0.1:8080"
+        ));
+        assert!(
+            matches!(&flags[6], TorFlag::LogExpanded(levels, LogDestination::File(_)) if levels.len() == 3)
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        match parse("SocksPort 9050\nNickname \"unterminated\n") {
+            Err(Error::InvalidTorrc(message)) => assert!(message.starts_with("torrc:2:")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match parse("Nickname \"quoted\" excess\n") {
+            Err(Error::InvalidTorrc(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Quoted values can't continue on the next line
+        match parse("Nickname \"multi\\\nline\"\n") {
+            Err(Error::InvalidTorrc(message)) => assert!(message.starts_with("torrc:1:")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut tor = Tor::new();
+        tor.flag(TorFlag::BandwidthRate(256, SizeUnit::MBits))
+            .flag(TorFlag::DisableNetwork(TorBool::False))
+            .flag(TorFlag::ControlPortAuto)
+            .flag(TorFlag::ControlPortAddress(
+                TorAddress::AddressPort("127.0.0.1".into(), 9051),
+                None.into(),
+            ))
+            .flag(TorFlag::CookieAuthentication(true.into()))
+            .flag(TorFlag::HTTPSProxyAuthenticator(
+                "user".into(),
+                "pass".into(),
+            ))
+            .flag(TorFlag::Log(LogLevel::Notice))
+            .flag(TorFlag::LogTo(LogLevel::Warn, LogDestination::Stderr))
+            .flag(TorFlag::Bridge(
+                "obfs4".into(),
+                "1.2.3.4:443".into(),
+                "ABCDEF cert=xyz iat-mode=0".into(),
+            ))
+            .flag(TorFlag::FirewallPorts(vec![80, 443].into()))
+            .flag(TorFlag::MapAddress("a.com".into(), "b.com".into()))
+            .flag(TorFlag::SocksPortAddress(
+                TorAddress::Address("127.0.0.1".into()),
+                Some(vec![SocksPortFlag::NoDNSRequest].into()).into(),
+                Some(vec![SocksPortIsolationFlag::IsolateDestAddr].into()).into(),
+            ))
+            .flag(TorFlag::HiddenServiceDir("/tmp/tor-rust/hs".into()))
+            .flag(TorFlag::HiddenServiceVersion(HiddenServiceVersion::V3))
+            .flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(80),
+                None.into(),
+            ));
+        #[cfg(feature = "legacy-v2")]
+        tor.flag(TorFlag::HiddenServiceAuthorizeClient(
+            HiddenServiceAuthType::Basic,
+            vec!["alice".to_string(), "bob".to_string()].into(),
+        ));
+        tor.flag(TorFlag::HiddenServiceMaxStreams(10));
+        let torrc = tor.to_torrc();
+
+        let flags = parse(&torrc).unwrap();
+        assert!(
+            flags.iter().all(|f| !matches!(f, TorFlag::Custom(_))),
+            "{:?}",
+            flags
+        );
+        assert_eq!(Tor::new().flags(flags).to_torrc(), torrc);
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("libtor-test-torrc-{}", std::process::id()));
+        let conf_d = dir.join("torrc.d");
+        std::fs::create_dir_all(&conf_d).unwrap();
+        std::fs::write(conf_d.join("02-socks.conf"), "SocksPort 9050\n").unwrap();
+        std::fs::write(conf_d.join("01-data.conf"), "DataDirectory /tmp/tor\n").unwrap();
+        std::fs::write(conf_d.join(".hidden.conf"), "SocksPort 1\n").unwrap();
+        std::fs::write(conf_d.join("03-other.txt"), "SocksPort 2\n").unwrap();
+        std::fs::write(
+            dir.join("torrc"),
+            format!(
+                "%include {}\n%include {}/*.conf\nControlPort 9051\n",
+                conf_d.display(),
+                conf_d.display()
+            ),
+        )
+        .unwrap();
+
+        let rendered = read(dir.join("torrc"))
+            .unwrap()
+            .iter()
+            .map(|f| f.expand_cli())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rendered,
+            vec![
+                "DataDirectory \"/tmp/tor\"",
+                "SocksPort \"9050\"",
+                "SocksPort \"2\"",
+                "DataDirectory \"/tmp/tor\"",
+                "SocksPort \"9050\"",
+                "ControlPort \"9051\"",
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
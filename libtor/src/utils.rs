@@ -11,6 +11,45 @@ macro_rules! display_like_debug {
     };
 }
 
+macro_rules! from_str_like_debug {
+    ($type:ident, [$($(#[$attr:meta])* $variant:ident),* $(,)?]) => {
+        impl std::str::FromStr for $type {
+            type Err = crate::Error;
+
+            // `Self::Err` would be ambiguous for enums with an `Err` variant
+            fn from_str(s: &str) -> Result<Self, crate::Error> {
+                $(
+                    $(#[$attr])*
+                    if s.eq_ignore_ascii_case(stringify!($variant)) {
+                        return Ok($type::$variant);
+                    }
+                )*
+
+                Err(crate::Error::InvalidValue {
+                    kind: stringify!($type).into(),
+                    value: s.to_string(),
+                })
+            }
+        }
+    };
+}
+
+/// Enter a `tracing` span until the end of the current scope, with the `tracing` feature
+macro_rules! tracing_span {
+    ($level:ident, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::$level!($($args)*).entered();
+    };
+}
+
+/// Emit a `tracing` event, with the `tracing` feature
+macro_rules! tracing_event {
+    ($level:ident, $($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($args)*);
+    };
+}
+
 pub trait Joiner: std::fmt::Debug + std::clone::Clone {
     fn joiner(&self) -> String;
     fn new() -> Self;
@@ -90,3 +129,9 @@ impl<T: std::fmt::Debug + std::fmt::Display> From<Option<T>> for DisplayOption<T
         DisplayOption { option }
     }
 }
+
+/// Whether a torrc keyword starts with `prefix`, ignoring the case like Tor does
+pub(crate) fn keyword_starts_with(keyword: &str, prefix: &str) -> bool {
+    keyword.len() >= prefix.len()
+        && keyword.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+}
@@ -0,0 +1,450 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashSet;
+
+use log_crate::warn;
+
+use crate::utils::keyword_starts_with;
+use crate::{Error, Expand, Tor, TorBool, TorFlag};
+
+/// Severity of a [`Diagnostic`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Severity {
+    /// The option has no effect or is probably a mistake, but Tor will start
+    Warning,
+    /// Tor will refuse to start with this configuration
+    Error,
+}
+
+/// Problem found by [`Tor::validate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Problem {
+    /// A hidden service option appears before any `HiddenServiceDir`
+    HiddenServiceOptionWithoutDir,
+    /// The same `HiddenServiceDir` is configured twice
+    DuplicateHiddenServiceDir(String),
+    /// A `HiddenServiceDir` has no `HiddenServicePort`
+    HiddenServiceWithoutPort(String),
+    /// A port option is set both to `auto` and to an explicit port
+    AutoAndExplicitPort(String),
+    /// The same address is configured twice for a port option
+    DuplicatePort(String, String),
+    /// `UseBridges` is enabled but no `Bridge` is configured
+    UseBridgesWithoutBridge,
+    /// `StrictNodes` is enabled but there are no node restrictions to enforce
+    StrictNodesWithoutNodes,
+    /// An option that only applies to v2 hidden services, which Tor doesn't support anymore
+    LegacyV2Option(String),
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::HiddenServiceOptionWithoutDir => {
+                write!(f, "hidden service option before any HiddenServiceDir")
+            }
+            Problem::DuplicateHiddenServiceDir(dir) => {
+                write!(f, "HiddenServiceDir {} is configured twice", dir)
+            }
+            Problem::HiddenServiceWithoutPort(dir) => {
+                write!(f, "HiddenServiceDir {} has no HiddenServicePort", dir)
+            }
+            Problem::AutoAndExplicitPort(option) => {
+                write!(f, "{} is set both to auto and to an explicit port", option)
+            }
+            Problem::DuplicatePort(option, addr) => {
+                write!(f, "{} {} is configured twice", option, addr)
+            }
+            Problem::UseBridgesWithoutBridge => write!(f, "UseBridges is set without any Bridge"),
+            Problem::StrictNodesWithoutNodes => {
+                write!(f, "StrictNodes is set without any node restriction")
+            }
+            Problem::LegacyV2Option(option) => write!(
+                f,
+                "{} is only supported by v2 hidden services, which Tor doesn't support anymore \
+                 (see Tor::migrate_v2_hidden_services)",
+                option
+            ),
+        }
+    }
+}
+
+/// Result of the validation of a single flag
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Index of the offending flag, in the order they were added
+    pub index: usize,
+    pub problem: Problem,
+}
+
+impl Diagnostic {
+    fn error(index: usize, problem: Problem) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            index,
+            problem,
+        }
+    }
+
+    fn warning(index: usize, problem: Problem) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            index,
+            problem,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "flag #{}: {}", self.index, self.problem)
+    }
+}
+
+/// Whether the flag is `HiddenServiceVersion 2`, or an option only supported by v2 services
+///
+/// Without the `legacy-v2` feature these can still come from [`TorFlag::Custom`] or a torrc.
+pub(crate) fn is_legacy_v2_option(flag: &TorFlag) -> bool {
+    let expanded = flag.expand();
+    let keyword = expanded.first().map(String::as_str).unwrap_or_default();
+    let value = expanded.get(1).map(|v| v.trim()).unwrap_or_default();
+
+    (keyword.eq_ignore_ascii_case("HiddenServiceVersion") && value == "2")
+        || keyword.eq_ignore_ascii_case("HiddenServiceAuthorizeClient")
+}
+
+/// Address a port flag listens on, or `None` for `auto`
+fn port_flag(flag: &TorFlag) -> Option<(&'static str, Option<String>)> {
+    match flag {
+        TorFlag::SocksPort(port) => Some(("SocksPort", Some(port.to_string()))),
+        TorFlag::SocksPortAuto => Some(("SocksPort", None)),
+        TorFlag::SocksPortAddress(addr, _, _) => Some(("SocksPort", Some(addr.to_string()))),
+        TorFlag::ControlPort(port) => Some(("ControlPort", Some(port.to_string()))),
+        TorFlag::ControlPortAuto => Some(("ControlPort", None)),
+        TorFlag::ControlPortAddress(addr, _) => Some(("ControlPort", Some(addr.to_string()))),
+        _ => None,
+    }
+}
+
+impl Tor {
+    /// Check the configuration for options that Tor would reject, or that are likely mistakes
+    ///
+    /// This is run by default when Tor is started, see [`Tor::validate_on_start`].
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = vec![];
+
+        let mut hs_dirs = HashSet::new();
+        // Index and path of the current `HiddenServiceDir`, and whether it has a port
+        let mut current_hs: Option<(usize, String, bool)> = None;
+        let close_hs = |current: Option<(usize, String, bool)>, diagnostics: &mut Vec<_>| {
+            if let Some((index, dir, false)) = current {
+                diagnostics.push(Diagnostic::error(
+                    index,
+                    Problem::HiddenServiceWithoutPort(dir),
+                ));
+            }
+        };
+
+        let mut ports = HashSet::new();
+        let mut auto_ports = HashSet::new();
+        let mut strict_nodes = None;
+        let mut has_nodes = false;
+        let mut use_bridges = None;
+        let mut has_bridges = false;
+
+        for (index, flag) in self.flags.iter().enumerate() {
+            let keyword = flag.expand().into_iter().next().unwrap_or_default();
+
+            if is_legacy_v2_option(flag) {
+                let problem = Problem::LegacyV2Option(flag.expand().join(" "));
+                // With `legacy-v2` the v2 flags are explicitly opted into, only warn about them
+                #[cfg(not(feature = "legacy-v2"))]
+                diagnostics.push(Diagnostic::error(index, problem));
+                #[cfg(feature = "legacy-v2")]
+                diagnostics.push(Diagnostic::warning(index, problem));
+            }
+
+            if keyword.eq_ignore_ascii_case("HiddenServiceDir") {
+                let dir = flag.expand().get(1).cloned().unwrap_or_default();
+                close_hs(current_hs.take(), &mut diagnostics);
+                if !hs_dirs.insert(dir.clone()) {
+                    diagnostics.push(Diagnostic::error(
+                        index,
+                        Problem::DuplicateHiddenServiceDir(dir.clone()),
+                    ));
+                }
+                current_hs = Some((index, dir, false));
+            } else if keyword_starts_with(&keyword, "HiddenService") {
+                match &mut current_hs {
+                    Some((_, _, has_port)) => {
+                        *has_port |= keyword.eq_ignore_ascii_case("HiddenServicePort")
+                    }
+                    None => diagnostics.push(Diagnostic::error(
+                        index,
+                        Problem::HiddenServiceOptionWithoutDir,
+                    )),
+                }
+            }
+
+            match port_flag(flag) {
+                // Port 0 disables the listener
+                Some((_, Some(addr))) if addr == "0" => {}
+                Some((option, Some(addr))) => {
+                    if !ports.insert((option, addr.clone())) {
+                        diagnostics.push(Diagnostic::error(
+                            index,
+                            Problem::DuplicatePort(option.into(), addr),
+                        ));
+                    }
+                    if auto_ports.contains(option) {
+                        diagnostics.push(Diagnostic::error(
+                            index,
+                            Problem::AutoAndExplicitPort(option.into()),
+                        ));
+                    }
+                }
+                Some((option, None)) => {
+                    if ports.iter().any(|(o, _)| *o == option) {
+                        diagnostics.push(Diagnostic::error(
+                            index,
+                            Problem::AutoAndExplicitPort(option.into()),
+                        ));
+                    }
+                    auto_ports.insert(option);
+                }
+                None => {}
+            }
+
+            match flag {
+                TorFlag::StrictNodes(TorBool::True) | TorFlag::StrictNodes(TorBool::Enabled) => {
+                    strict_nodes = Some(index)
+                }
+                TorFlag::StrictNodes(_) => strict_nodes = None,
+                TorFlag::ExcludeNodes(nodes)
+                | TorFlag::ExcludeExitNodes(nodes)
+                | TorFlag::ExitNodes(nodes)
+                | TorFlag::MiddleNodes(nodes)
+                | TorFlag::EntryNodes(nodes) => has_nodes |= !nodes.to_string().is_empty(),
+                TorFlag::UseBridges(TorBool::True) | TorFlag::UseBridges(TorBool::Enabled) => {
+                    use_bridges = Some(index)
+                }
+                TorFlag::UseBridges(_) => use_bridges = None,
+                TorFlag::Bridge(..) => has_bridges = true,
+                _ => {}
+            }
+        }
+        close_hs(current_hs, &mut diagnostics);
+
+        match strict_nodes {
+            Some(index) if !has_nodes => {
+                diagnostics.push(Diagnostic::warning(index, Problem::StrictNodesWithoutNodes))
+            }
+            _ => {}
+        }
+        match use_bridges {
+            Some(index) if !has_bridges => {
+                diagnostics.push(Diagnostic::error(index, Problem::UseBridgesWithoutBridge))
+            }
+            _ => {}
+        }
+
+        diagnostics.sort_by_key(|d| d.index);
+        diagnostics
+    }
+
+    /// Whether to run [`Tor::validate`] before starting Tor, enabled by default
+    ///
+    /// When enabled, starting fails with [`Error::InvalidConfig`] if any error is found, and
+    /// warnings are logged.
+    pub fn validate_on_start(&mut self, enabled: bool) -> &mut Tor {
+        self.skip_validation = !enabled;
+        self
+    }
+
+    /// Validate the configuration, unless disabled
+    pub(crate) fn validate_for_start(&self) -> Result<(), Error> {
+        if self.skip_validation {
+            return Ok(());
+        }
+
+        let (errors, warnings): (Vec<_>, Vec<_>) = self
+            .validate()
+            .into_iter()
+            .partition(|d| d.severity == Severity::Error);
+        for warning in warnings {
+            warn!("Configuration warning: {}", warning);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(errors))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TorAddress;
+
+    fn problems(tor: &Tor) -> Vec<(usize, Problem)> {
+        tor.validate()
+            .into_iter()
+            .map(|d| (d.index, d.problem))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let tor = Tor::new()
+            .flag(TorFlag::SocksPort(9050))
+            .flag(TorFlag::ControlPortAuto)
+            .flag(TorFlag::HiddenServiceDir("/tmp/hs".into()))
+            .flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(80),
+                None.into(),
+            ))
+            .flag(TorFlag::StrictNodes(TorBool::True))
+            .flag(TorFlag::ExitNodes(vec!["{de}".to_string()].into()))
+            .clone();
+        assert_eq!(tor.validate(), vec![]);
+        assert!(tor.validate_for_start().is_ok());
+    }
+
+    #[test]
+    fn test_hidden_services() {
+        let tor = Tor::new()
+            .flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(80),
+                None.into(),
+            ))
+            .flag(TorFlag::HiddenServiceDir("/tmp/hs-1".into()))
+            .flag(TorFlag::HiddenServiceDir("/tmp/hs-2".into()))
+            .flag(TorFlag::Custom("HiddenServicePort 80".into()))
+            .flag(TorFlag::HiddenServiceDir("/tmp/hs-2".into()))
+            .clone();
+        assert_eq!(
+            problems(&tor),
+            vec![
+                (0, Problem::HiddenServiceOptionWithoutDir),
+                (1, Problem::HiddenServiceWithoutPort("/tmp/hs-1".into())),
+                (4, Problem::DuplicateHiddenServiceDir("/tmp/hs-2".into())),
+                (4, Problem::HiddenServiceWithoutPort("/tmp/hs-2".into())),
+            ]
+        );
+
+        // Keywords are case-insensitive, like in Tor
+        let tor = Tor::new()
+            .flag(TorFlag::Custom("hiddenservicedir /tmp/hs".into()))
+            .flag(TorFlag::Custom("HIDDENSERVICEPORT 80".into()))
+            .flag(TorFlag::Custom("hiddenserviceversion 2".into()))
+            .clone();
+        assert_eq!(
+            problems(&tor),
+            vec![(2, Problem::LegacyV2Option("hiddenserviceversion 2".into()))]
+        );
+    }
+
+    #[test]
+    fn test_ports() {
+        let tor = Tor::new()
+            .flag(TorFlag::SocksPort(9050))
+            .flag(TorFlag::SocksPortAuto)
+            .flag(TorFlag::SocksPortAddress(
+                TorAddress::Port(9050),
+                None.into(),
+                None.into(),
+            ))
+            .flag(TorFlag::ControlPort(0))
+            .flag(TorFlag::ControlPortAuto)
+            .clone();
+        assert_eq!(
+            problems(&tor),
+            vec![
+                (1, Problem::AutoAndExplicitPort("SocksPort".into())),
+                (2, Problem::DuplicatePort("SocksPort".into(), "9050".into())),
+                (2, Problem::AutoAndExplicitPort("SocksPort".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nodes_and_bridges() {
+        let mut tor = Tor::new();
+        tor.flag(TorFlag::StrictNodes(TorBool::True))
+            .flag(TorFlag::ExitNodes(Vec::<String>::new().into()))
+            .flag(TorFlag::UseBridges(TorBool::True));
+        assert_eq!(
+            tor.validate(),
+            vec![
+                Diagnostic::warning(0, Problem::StrictNodesWithoutNodes),
+                Diagnostic::error(2, Problem::UseBridgesWithoutBridge),
+            ]
+        );
+
+        match tor.validate_for_start() {
+            Err(Error::InvalidConfig(errors)) => {
+                assert_eq!(
+                    errors,
+                    vec![Diagnostic::error(2, Problem::UseBridgesWithoutBridge)]
+                )
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(tor.validate_on_start(false).validate_for_start().is_ok());
+    }
+
+    fn legacy_v2_flags() -> Tor {
+        Tor::new()
+            .flag(TorFlag::HiddenServiceDir("/tmp/hs".into()))
+            .flag(TorFlag::Custom("HiddenServiceVersion 2".into()))
+            .flag(TorFlag::HiddenServicePort(
+                TorAddress::Port(80),
+                None.into(),
+            ))
+            .flag(TorFlag::Custom(
+                "HiddenServiceAuthorizeClient stealth alice".into(),
+            ))
+            .clone()
+    }
+
+    #[test]
+    #[cfg(not(feature = "legacy-v2"))]
+    fn test_legacy_v2() {
+        let tor = legacy_v2_flags();
+        assert_eq!(
+            tor.validate(),
+            vec![
+                Diagnostic::error(1, Problem::LegacyV2Option("HiddenServiceVersion 2".into())),
+                Diagnostic::error(
+                    3,
+                    Problem::LegacyV2Option("HiddenServiceAuthorizeClient stealth alice".into())
+                ),
+            ]
+        );
+        assert!(tor.validate_for_start().is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "legacy-v2")]
+    fn test_legacy_v2_warning() {
+        let tor = legacy_v2_flags();
+        assert_eq!(
+            tor.validate(),
+            vec![
+                Diagnostic::warning(1, Problem::LegacyV2Option("HiddenServiceVersion 2".into())),
+                Diagnostic::warning(
+                    3,
+                    Problem::LegacyV2Option("HiddenServiceAuthorizeClient stealth alice".into())
+                ),
+            ]
+        );
+        assert!(tor.validate_for_start().is_ok());
+    }
+}